@@ -0,0 +1,91 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, ParameterizedBenchmark};
+use makv::{IndexMode, KvStore, MakvEngine, StoreOptions};
+use rand::prelude::*;
+use std::iter;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn open(path: &Path, index: IndexMode) -> KvStore {
+    let options = StoreOptions {
+        index,
+        ..StoreOptions::default()
+    };
+    KvStore::open_with(path, options).unwrap()
+}
+
+fn set_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "memory",
+        |b, _| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    (open(temp_dir.path(), IndexMode::Memory), temp_dir)
+                },
+                |(store, _temp_dir)| {
+                    for i in 1..(1 << 12) {
+                        store.set(format!("key{}", i), "value".to_string()).unwrap();
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        },
+        iter::once(()),
+    )
+    .with_function("disk", |b, _| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (open(temp_dir.path(), IndexMode::Disk), temp_dir)
+            },
+            |(store, _temp_dir)| {
+                for i in 1..(1 << 12) {
+                    store.set(format!("key{}", i), "value".to_string()).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    c.bench("index_set_bench", bench);
+}
+
+fn get_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "memory",
+        |b, i| {
+            let temp_dir = TempDir::new().unwrap();
+            let store = open(temp_dir.path(), IndexMode::Memory);
+            for key_i in 1..(1 << i) {
+                store
+                    .set(format!("key{}", key_i), "value".to_string())
+                    .unwrap();
+            }
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                store
+                    .get(format!("key{}", rng.gen_range(1, 1 << i)))
+                    .unwrap();
+            })
+        },
+        vec![4, 8, 12, 16],
+    )
+    .with_function("disk", |b, i| {
+        let temp_dir = TempDir::new().unwrap();
+        let store = open(temp_dir.path(), IndexMode::Disk);
+        for key_i in 1..(1 << i) {
+            store
+                .set(format!("key{}", key_i), "value".to_string())
+                .unwrap();
+        }
+        let mut rng = SmallRng::from_seed([0; 16]);
+        b.iter(|| {
+            store
+                .get(format!("key{}", rng.gen_range(1, 1 << i)))
+                .unwrap();
+        })
+    });
+    c.bench("index_get_bench", bench);
+}
+
+criterion_group!(benches, set_bench, get_bench);
+criterion_main!(benches);
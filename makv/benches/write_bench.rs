@@ -0,0 +1,50 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, ParameterizedBenchmark};
+use makv::{Durability, KvStore, MakvEngine, StoreOptions};
+use std::thread;
+use tempfile::TempDir;
+
+// Writes 1024 keys from a number of threads, fsyncing every write. Writes
+// queued by concurrent threads share an fsync.
+fn concurrent_set_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "sync",
+        |b, &threads| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    let options = StoreOptions {
+                        durability: Durability::Sync,
+                        ..StoreOptions::default()
+                    };
+                    (
+                        KvStore::open_with(temp_dir.path(), options).unwrap(),
+                        temp_dir,
+                    )
+                },
+                |(store, _temp_dir)| {
+                    let writers: Vec<_> = (0..threads)
+                        .map(|thread_id| {
+                            let store = store.clone();
+                            thread::spawn(move || {
+                                for i in 0..1024 / threads {
+                                    store
+                                        .set(format!("key{}-{}", thread_id, i), "value".to_string())
+                                        .unwrap();
+                                }
+                            })
+                        })
+                        .collect();
+                    for writer in writers {
+                        writer.join().unwrap();
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        },
+        vec![1, 4, 16],
+    );
+    c.bench("concurrent_set_bench", bench);
+}
+
+criterion_group!(benches, concurrent_set_bench);
+criterion_main!(benches);
@@ -0,0 +1,25 @@
+//! Feeds arbitrary bytes to `YakvMessage::new` as if they came from a peer.
+//!
+//! Run with `cargo fuzz run yakv_message` from the makv directory.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use makv::{PayloadType, YakvMessage};
+
+// Small enough that the fuzzer also hits the frame size check
+const MAX_FRAME_SIZE: u32 = 64 * 1024;
+
+fuzz_target!(|data: &[u8]| {
+    // the first byte picks the payload type, the rest is the stream
+    let Some((&ptype, mut stream)) = data.split_first() else {
+        return;
+    };
+    let ptype = if ptype % 2 == 0 {
+        PayloadType::Command
+    } else {
+        PayloadType::Response
+    };
+
+    // keep reading frames until the stream runs out, like the server does
+    while YakvMessage::with_max_frame_size(&mut stream, ptype, MAX_FRAME_SIZE).is_ok() {}
+});
@@ -0,0 +1,127 @@
+use crate::{Result, YakvError};
+use anyhow::anyhow;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Access control list for makv-server, loaded from a TOML file.
+///
+/// Each user has a token and lists of key prefixes they may read and write.
+/// An empty prefix matches every key.
+///
+/// ```toml
+/// [[users]]
+/// name = "alice"
+/// token = "secret"
+/// read = [""]
+/// write = ["alice/"]
+/// ```
+#[derive(Debug)]
+pub struct Acl {
+    users: HashMap<String, Arc<User>>,
+}
+
+/// A user from the ACL file, returned by `Acl::authenticate`.
+#[derive(Deserialize)]
+pub struct User {
+    name: String,
+    token: String,
+    #[serde(default)]
+    read: Vec<String>,
+    #[serde(default)]
+    write: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct AclFile {
+    #[serde(default)]
+    users: Vec<User>,
+}
+
+/// What a command does to a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(missing_docs)]
+pub enum Permission {
+    Read,
+    Write,
+}
+
+impl Acl {
+    /// Loads the ACL from a TOML file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let file: AclFile = toml::from_str(&content)
+            .map_err(|e| YakvError::Any(anyhow!("{}: {}", path.display(), e)))?;
+
+        let mut users = HashMap::new();
+        for user in file.users {
+            if users.contains_key(&user.name) {
+                return Err(YakvError::Any(anyhow!(
+                    "{}: duplicate user '{}'",
+                    path.display(),
+                    user.name
+                )));
+            }
+            users.insert(user.name.to_owned(), Arc::new(user));
+        }
+        Ok(Acl { users })
+    }
+
+    /// Returns the user if the token matches.
+    pub fn authenticate(&self, name: &str, token: &str) -> Option<Arc<User>> {
+        self.users
+            .get(name)
+            .filter(|user| constant_time_eq(user.token.as_bytes(), token.as_bytes()))
+            .cloned()
+    }
+}
+
+impl User {
+    /// Returns the user name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns an error if the user may not access `key` with `permission`.
+    pub fn check(&self, key: &str, permission: Permission) -> Result<()> {
+        let prefixes = match permission {
+            Permission::Read => &self.read,
+            Permission::Write => &self.write,
+        };
+        if prefixes
+            .iter()
+            .any(|prefix| key.starts_with(prefix.as_str()))
+        {
+            Ok(())
+        } else {
+            Err(YakvError::PermissionDenied(format!(
+                "user '{}' may not {} key '{}'",
+                self.name,
+                match permission {
+                    Permission::Read => "read",
+                    Permission::Write => "write",
+                },
+                key
+            )))
+        }
+    }
+}
+
+// Leaves out the token so that it does not end up in logs
+impl fmt::Debug for User {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("User")
+            .field("name", &self.name)
+            .field("read", &self.read)
+            .field("write", &self.write)
+            .finish()
+    }
+}
+
+// Compares tokens without leaking how many leading bytes matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,690 @@
+use anyhow::anyhow;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use makv::inspect;
+use makv::{
+    Command, EncryptionKey, Engine, KvStore, LsmStore, MakvEngine, Result, SledStore, StoreOptions,
+    YakvError,
+};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const ENGINES: &[&str] = &["yakv", "sled", "lsm"];
+
+// Keys read by every scan of an export or migration
+const SCAN_PAGE_SIZE: usize = 1000;
+
+// A key/value pair as a line of JSON
+#[derive(Serialize, Deserialize)]
+struct Pair {
+    key: String,
+    value: String,
+}
+
+// A log record printed by `inspect --dump`
+#[derive(Serialize)]
+struct DumpedRecord<'a> {
+    generation: u64,
+    pos: u64,
+    len: u64,
+    command: &'a Command,
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+    JsonLines,
+    Csv,
+}
+
+// Offline maintenance of a yakv data directory. The server must not be
+// running on the directory while a command changes it.
+fn main() -> Result<()> {
+    let matches = App::new(env!("CARGO_PKG_NAME"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(SubCommand::with_name("gen-key").about("Print a new random encryption key"))
+        .subcommand(
+            SubCommand::with_name("rotate-key")
+                .about("Rewrite all log files with records encrypted under a new key")
+                .arg(dir_arg())
+                .arg(key_file_arg())
+                .arg(
+                    Arg::with_name("new-key-file")
+                        .long("new-key-file")
+                        .value_name("FILE")
+                        .help("Key to encrypt the store with")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Write every key/value pair of a store as JSON lines or CSV")
+                .arg(dir_arg())
+                .arg(engine_arg())
+                .arg(key_file_arg())
+                .arg(format_arg())
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .value_name("FILE")
+                        .help("File to write the pairs to, defaults to stdout")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Set the key/value pairs of a JSON lines or CSV export in a store")
+                .arg(dir_arg())
+                .arg(engine_arg())
+                .arg(key_file_arg())
+                .arg(format_arg())
+                .arg(
+                    Arg::with_name("input")
+                        .long("input")
+                        .value_name("FILE")
+                        .help("File to read the pairs from, defaults to stdin")
+                        .takes_value(true),
+                )
+                .arg(batch_size_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about(
+                    "Copy every key/value pair to another engine, verify the copy and make \
+                     that engine the active one",
+                )
+                .arg(dir_arg())
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .value_name("ENGINE")
+                        .help("Engine to copy the pairs from")
+                        .takes_value(true)
+                        .possible_values(ENGINES)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .value_name("ENGINE")
+                        .help("Engine to copy the pairs to")
+                        .takes_value(true)
+                        .possible_values(ENGINES)
+                        .required(true),
+                )
+                .arg(key_file_arg())
+                .arg(batch_size_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("inspect")
+                .about(
+                    "List the log generations with their live and stale bytes, and check \
+                     every record can be read",
+                )
+                .arg(dir_arg())
+                .arg(key_file_arg())
+                .arg(
+                    Arg::with_name("dump")
+                        .long("dump")
+                        .help("Print every readable record as a line of JSON instead"),
+                )
+                .arg(
+                    Arg::with_name("generation")
+                        .long("generation")
+                        .value_name("ID")
+                        .help("Only dump the records of this generation")
+                        .takes_value(true)
+                        .requires("dump"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("repair")
+                .about(
+                    "Write the readable live records to a new generation, keeping damaged logs \
+                     as <id>.log.damaged",
+                )
+                .arg(dir_arg())
+                .arg(key_file_arg()),
+        )
+        .get_matches();
+
+    match matches.subcommand() {
+        ("gen-key", Some(_)) => println!("{}", EncryptionKey::generate().to_hex()),
+        ("rotate-key", Some(matches)) => rotate_key(matches)?,
+        ("export", Some(matches)) => export(matches)?,
+        ("import", Some(matches)) => import(matches)?,
+        ("migrate", Some(matches)) => migrate(matches)?,
+        ("inspect", Some(matches)) => inspect_logs(matches)?,
+        ("repair", Some(matches)) => repair(matches)?,
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+fn dir_arg() -> Arg<'static, 'static> {
+    Arg::with_name("dir")
+        .long("dir")
+        .value_name("DIR")
+        .help("Working directory of the server, defaults to the current directory")
+        .takes_value(true)
+}
+
+fn engine_arg() -> Arg<'static, 'static> {
+    Arg::with_name("engine")
+        .long("engine")
+        .value_name("ENGINE")
+        .help("Engine of the data directory")
+        .takes_value(true)
+        .possible_values(ENGINES)
+        .default_value("yakv")
+}
+
+fn batch_size_arg() -> Arg<'static, 'static> {
+    Arg::with_name("batch-size")
+        .long("batch-size")
+        .value_name("PAIRS")
+        .help("Pairs written together with one flush")
+        .takes_value(true)
+        .default_value("1000")
+}
+
+fn key_file_arg() -> Arg<'static, 'static> {
+    Arg::with_name("key-file")
+        .long("key-file")
+        .value_name("FILE")
+        .help(
+            "Key the store is encrypted with now, defaults to the hex key in \
+             MAKV_ENCRYPTION_KEY",
+        )
+        .takes_value(true)
+}
+
+fn format_arg() -> Arg<'static, 'static> {
+    Arg::with_name("format")
+        .long("format")
+        .value_name("jsonl|csv")
+        .help("JSON objects with a key and a value per line, or CSV with a key,value header")
+        .takes_value(true)
+        .possible_values(&["jsonl", "csv"])
+        .default_value("jsonl")
+}
+
+fn format(matches: &ArgMatches) -> Format {
+    match matches.value_of("format") {
+        Some("csv") => Format::Csv,
+        _ => Format::JsonLines,
+    }
+}
+
+fn encryption_key(matches: &ArgMatches) -> Result<Option<EncryptionKey>> {
+    match matches.value_of("key-file") {
+        Some(path) => Ok(Some(EncryptionKey::from_file(Path::new(path))?)),
+        None => EncryptionKey::from_env("MAKV_ENCRYPTION_KEY"),
+    }
+}
+
+// Options for reading a yakv store with the key given by the arguments
+fn store_options(matches: &ArgMatches) -> Result<StoreOptions> {
+    Ok(StoreOptions {
+        encryption_key: encryption_key(matches)?,
+        ..StoreOptions::default()
+    })
+}
+
+fn data_dir(matches: &ArgMatches) -> Result<PathBuf> {
+    match matches.value_of("dir") {
+        Some(dir) => Ok(PathBuf::from(dir)),
+        None => Ok(env::current_dir()?),
+    }
+}
+
+fn rotate_key(matches: &ArgMatches) -> Result<()> {
+    let key = encryption_key(matches)?;
+    let new_key = EncryptionKey::from_file(Path::new(
+        matches.value_of("new-key-file").expect("arg is required"),
+    ))?;
+
+    // the new key can also read records of an interrupted rotation
+    let options = StoreOptions {
+        encryption_key: key,
+        previous_keys: vec![new_key.clone()],
+        ..StoreOptions::default()
+    };
+    let store = KvStore::open_with(data_dir(matches)?, options)?;
+    store.rotate_key(new_key)
+}
+
+// Fails if a log is damaged, after printing every generation
+fn inspect_logs(matches: &ArgMatches) -> Result<()> {
+    let dir = data_dir(matches)?;
+    let options = store_options(matches)?;
+    if matches.is_present("dump") {
+        let ids = match matches.value_of("generation") {
+            Some(id) => vec![id.parse().map_err(|_| {
+                YakvError::Any(anyhow!("--generation must be a number, got '{}'", id))
+            })?],
+            None => inspect::generations(&dir)?,
+        };
+        let mut output = BufWriter::new(io::stdout());
+        for generation in ids {
+            let end = inspect::scan_log(&dir, generation, &options, |record| {
+                let record = DumpedRecord {
+                    generation,
+                    pos: record.pos,
+                    len: record.len,
+                    command: &record.command,
+                };
+                serde_json::to_writer(&mut output, &record)?;
+                writeln!(output)?;
+                Ok(())
+            })?;
+            if let Some(corruption) = end.corruption {
+                output.flush()?;
+                eprintln!(
+                    "{}.log is damaged at byte {}: {}",
+                    generation, corruption.pos, corruption.reason
+                );
+            }
+        }
+        output.flush()?;
+        return Ok(());
+    }
+
+    let generations = inspect::inspect(&dir, &options)?;
+    let mut damaged = 0;
+    for generation in &generations {
+        print!(
+            "{}.log: {} bytes, {} sets, {} removes, {} live bytes, {} stale bytes, hint {}",
+            generation.id,
+            generation.size,
+            generation.sets,
+            generation.removes,
+            generation.live_bytes,
+            generation.stale_bytes,
+            if generation.hint { "ok" } else { "missing" }
+        );
+        if generation.legacy {
+            print!(", legacy format");
+        }
+        if let Some(corruption) = &generation.corruption {
+            damaged += 1;
+            print!(
+                ", damaged at byte {}: {}",
+                corruption.pos, corruption.reason
+            );
+        }
+        println!();
+    }
+    println!(
+        "{} generations, {} live bytes, {} stale bytes",
+        generations.len(),
+        generations.iter().map(|g| g.live_bytes).sum::<u64>(),
+        generations.iter().map(|g| g.stale_bytes).sum::<u64>()
+    );
+    if damaged > 0 {
+        return Err(YakvError::Any(anyhow!(
+            "{} of {} logs are damaged, `makv-admin repair` salvages their readable records",
+            damaged,
+            generations.len()
+        )));
+    }
+    Ok(())
+}
+
+fn repair(matches: &ArgMatches) -> Result<()> {
+    let options = store_options(matches)?;
+    let report = inspect::repair(&data_dir(matches)?, &options)?;
+    for (id, corruption) in &report.damaged {
+        println!(
+            "{}.log is damaged at byte {}: {}, kept as {}.log.damaged",
+            id, corruption.pos, corruption.reason, id
+        );
+    }
+    println!("Wrote {} keys to {}.log", report.keys, report.generation);
+    Ok(())
+}
+
+fn export(matches: &ArgMatches) -> Result<()> {
+    let output: Box<dyn Write> = match matches.value_of("output") {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    };
+    let mut output = BufWriter::new(output);
+    let store = Store::open(&engine(matches, "engine"), matches)?;
+    export_pairs(&store, format(matches), &mut output)?;
+    output.flush()?;
+    Ok(())
+}
+
+fn import(matches: &ArgMatches) -> Result<()> {
+    let input: Box<dyn BufRead> = match matches.value_of("input") {
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+        None => Box::new(io::stdin().lock()),
+    };
+    let store = Store::open(&engine(matches, "engine"), matches)?;
+    import_pairs(&store, format(matches), input, batch_size(matches)?)
+}
+
+// Copies every pair to the other engine, reads them back, and only then
+// makes the other engine the active one
+fn migrate(matches: &ArgMatches) -> Result<()> {
+    let dir = data_dir(matches)?;
+    let from = engine(matches, "from");
+    let to = engine(matches, "to");
+    if from == to {
+        return Err(YakvError::Any(anyhow!(
+            "--from and --to are the same engine"
+        )));
+    }
+    if let Some(active) = Engine::active(&dir)? {
+        if active != from {
+            return Err(YakvError::Any(anyhow!(
+                "the active engine is {}, not {}",
+                active.name(),
+                from.name()
+            )));
+        }
+    }
+
+    let batch_size = batch_size(matches)?;
+    let source = Store::open(&from, matches)?;
+    let dest = Store::open(&to, matches)?;
+    if !dest.scan(String::new(), 1)?.is_empty() {
+        return Err(YakvError::Any(anyhow!(
+            "engine {} already holds keys",
+            to.name()
+        )));
+    }
+    let mut copied = Checksum::default();
+    let mut batch = Vec::with_capacity(batch_size);
+    for_each_pair(&source, |key, value| {
+        copied.add(&key, &value);
+        batch.push((key, value));
+        if batch.len() == batch_size {
+            dest.set_batch(mem::take(&mut batch))?;
+        }
+        Ok(())
+    })?;
+    if !batch.is_empty() {
+        dest.set_batch(batch)?;
+    }
+
+    // read every pair back before switching engines
+    let mut verified = Checksum::default();
+    for_each_pair(&dest, |key, value| {
+        verified.add(&key, &value);
+        Ok(())
+    })?;
+    if verified.finish() != copied.finish() {
+        return Err(YakvError::Any(anyhow!(
+            "engine {} holds {} keys with checksum {:08x} after copying {} keys with checksum \
+             {:08x}",
+            to.name(),
+            verified.keys,
+            verified.finish().1,
+            copied.keys,
+            copied.finish().1
+        )));
+    }
+    drop(dest);
+
+    to.set_active(&dir)?;
+    println!(
+        "Migrated {} keys from {} to {}",
+        copied.keys,
+        from.name(),
+        to.name()
+    );
+    Ok(())
+}
+
+fn engine(matches: &ArgMatches, name: &str) -> Engine {
+    Engine::from_str(matches.value_of(name).expect("arg has a value"))
+        .expect("arg has possible values")
+}
+
+fn batch_size(matches: &ArgMatches) -> Result<usize> {
+    matches
+        .value_of("batch-size")
+        .expect("arg has a default value")
+        .parse::<usize>()
+        .ok()
+        .filter(|&size| size > 0)
+        .ok_or_else(|| YakvError::Any(anyhow!("--batch-size must be a positive number")))
+}
+
+// A store of any engine
+#[derive(Clone)]
+enum Store {
+    Yakv(KvStore),
+    Sled(SledStore),
+    Lsm(LsmStore),
+}
+
+impl Store {
+    fn open(engine: &Engine, matches: &ArgMatches) -> Result<Store> {
+        let dir = data_dir(matches)?;
+        Ok(match engine {
+            Engine::Yakv => Store::Yakv(KvStore::open_with(dir, store_options(matches)?)?),
+            Engine::Sled => Store::Sled(SledStore::open(dir)?),
+            Engine::Lsm => Store::Lsm(LsmStore::open(dir)?),
+        })
+    }
+}
+
+impl MakvEngine for Store {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        match self {
+            Store::Yakv(store) => store.set(key, value),
+            Store::Sled(store) => store.set(key, value),
+            Store::Lsm(store) => store.set(key, value),
+        }
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self {
+            Store::Yakv(store) => store.get(key),
+            Store::Sled(store) => store.get(key),
+            Store::Lsm(store) => store.get(key),
+        }
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        match self {
+            Store::Yakv(store) => store.remove(key),
+            Store::Sled(store) => store.remove(key),
+            Store::Lsm(store) => store.remove(key),
+        }
+    }
+
+    fn scan(&self, start: String, limit: usize) -> Result<Vec<String>> {
+        match self {
+            Store::Yakv(store) => store.scan(start, limit),
+            Store::Sled(store) => store.scan(start, limit),
+            Store::Lsm(store) => store.scan(start, limit),
+        }
+    }
+
+    fn set_batch(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        match self {
+            Store::Yakv(store) => store.set_batch(pairs),
+            Store::Sled(store) => store.set_batch(pairs),
+            Store::Lsm(store) => store.set_batch(pairs),
+        }
+    }
+}
+
+// Number of pairs and a CRC-32 of their keys and values, in key order
+#[derive(Default)]
+struct Checksum {
+    keys: u64,
+    hasher: crc32fast::Hasher,
+}
+
+impl Checksum {
+    fn add(&mut self, key: &str, value: &str) {
+        self.keys += 1;
+        for field in &[key, value] {
+            self.hasher.update(&(field.len() as u64).to_be_bytes());
+            self.hasher.update(field.as_bytes());
+        }
+    }
+
+    fn finish(&self) -> (u64, u32) {
+        (self.keys, self.hasher.clone().finalize())
+    }
+}
+
+// Calls `f` with every pair in key order, scanning a page of keys at a time
+fn for_each_pair<E, F>(engine: &E, mut f: F) -> Result<()>
+where
+    E: MakvEngine,
+    F: FnMut(String, String) -> Result<()>,
+{
+    let mut start = String::new();
+    loop {
+        let keys = engine.scan(start, SCAN_PAGE_SIZE)?;
+        for key in &keys {
+            if let Some(value) = engine.get(key.to_owned())? {
+                f(key.to_owned(), value)?;
+            }
+        }
+        match keys.last() {
+            // the smallest key after the last one
+            Some(last) if keys.len() == SCAN_PAGE_SIZE => start = format!("{}\0", last),
+            _ => return Ok(()),
+        }
+    }
+}
+
+fn export_pairs<E: MakvEngine>(engine: &E, format: Format, output: &mut dyn Write) -> Result<()> {
+    if let Format::Csv = format {
+        writeln!(output, "key,value")?;
+    }
+    for_each_pair(engine, |key, value| {
+        match format {
+            Format::JsonLines => {
+                serde_json::to_writer(&mut *output, &Pair { key, value })?;
+                writeln!(output)?;
+            }
+            Format::Csv => writeln!(output, "{},{}", csv_field(&key), csv_field(&value))?,
+        }
+        Ok(())
+    })
+}
+
+// Sets the pairs `batch_size` at a time
+fn import_pairs<E: MakvEngine>(
+    engine: &E,
+    format: Format,
+    mut input: Box<dyn BufRead>,
+    batch_size: usize,
+) -> Result<()> {
+    if let Format::Csv = format {
+        match read_csv_record(&mut input)? {
+            Some(header) if header == ["key", "value"] => {}
+            _ => {
+                return Err(YakvError::Any(anyhow!(
+                    "CSV must start with a key,value header"
+                )))
+            }
+        }
+    }
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut line_number = 0;
+    loop {
+        line_number += 1;
+        let pair = match format {
+            Format::JsonLines => {
+                let mut line = String::new();
+                if input.read_line(&mut line)? == 0 {
+                    break;
+                }
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let pair: Pair = serde_json::from_str(&line).map_err(|e| {
+                    YakvError::Any(anyhow!(
+                        "line {} is not a key/value pair: {}",
+                        line_number,
+                        e
+                    ))
+                })?;
+                (pair.key, pair.value)
+            }
+            Format::Csv => match read_csv_record(&mut input)? {
+                None => break,
+                Some(record) => match <[String; 2]>::try_from(record) {
+                    Ok([key, value]) => (key, value),
+                    Err(record) => {
+                        return Err(YakvError::Any(anyhow!(
+                            "record {} has {} fields instead of 2",
+                            line_number,
+                            record.len()
+                        )))
+                    }
+                },
+            },
+        };
+        batch.push(pair);
+        if batch.len() == batch_size {
+            engine.set_batch(mem::take(&mut batch))?;
+        }
+    }
+    if !batch.is_empty() {
+        engine.set_batch(batch)?;
+    }
+    Ok(())
+}
+
+// Quotes a field if it holds a separator, quote or line break
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\r', '\n'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+// Reads the fields of the next CSV record, whose quoted fields may span
+// lines. Returns None at the end of the input.
+fn read_csv_record(input: &mut dyn BufRead) -> Result<Option<Vec<String>>> {
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    loop {
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (quoted, c) {
+                (true, '"') if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                (true, '"') => quoted = false,
+                (true, c) => field.push(c),
+                (false, '"') if field.is_empty() => quoted = true,
+                (false, ',') => fields.push(mem::take(&mut field)),
+                (false, '\r') | (false, '\n') => {}
+                (false, c) => field.push(c),
+            }
+        }
+        if !quoted {
+            break;
+        }
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            return Err(YakvError::Any(anyhow!("CSV ends inside a quoted field")));
+        }
+    }
+    fields.push(field);
+    Ok(Some(fields))
+}
@@ -1,10 +1,56 @@
+use anyhow::anyhow;
 use clap::{App, Arg, SubCommand};
-use makv::{Command, Payload, PayloadType, Result, YakvMessage};
+use makv::{
+    tls, Command, Handshake, Payload, PayloadType, Response, Result, StoreStats, YakvError,
+    YakvMessage,
+};
+use rustls::{ClientConnection, StreamOwned};
 use std::env;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::net::TcpStream;
+use std::path::Path;
 use std::process::exit;
 
+// Arguments shared by all subcommands for reaching the server
+fn connection_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("addr")
+            .long("addr")
+            .value_name("IP-PORT")
+            .takes_value(true)
+            .required(true),
+        Arg::with_name("ca")
+            .long("ca")
+            .value_name("PEM-FILE")
+            .help("Connect with TLS, trusting server certificates signed by this CA")
+            .takes_value(true),
+        Arg::with_name("cert")
+            .long("cert")
+            .value_name("PEM-FILE")
+            .help("Client certificate for mTLS")
+            .takes_value(true)
+            .requires_all(&["ca", "key"]),
+        Arg::with_name("key")
+            .long("key")
+            .value_name("PEM-FILE")
+            .help("Private key for --cert")
+            .takes_value(true)
+            .requires("cert"),
+        Arg::with_name("user")
+            .long("user")
+            .value_name("NAME")
+            .help("Authenticate as this user")
+            .takes_value(true)
+            .requires("token"),
+        Arg::with_name("token")
+            .long("token")
+            .value_name("TOKEN")
+            .env("MAKV_TOKEN")
+            .help("Token for --user")
+            .takes_value(true),
+    ]
+}
+
 fn main() -> Result<()> {
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
@@ -16,40 +62,48 @@ fn main() -> Result<()> {
                         .takes_value(true)
                         .number_of_values(2),
                 )
-                .arg(
-                    Arg::with_name("addr")
-                        .long("addr")
-                        .value_name("IP-PORT")
-                        .takes_value(true)
-                        .required(true),
-                ),
+                .args(&connection_args()),
         )
         .subcommand(
             SubCommand::with_name("get")
                 .arg(Arg::with_name("KEY").takes_value(true).required(true))
-                .arg(
-                    Arg::with_name("addr")
-                        .long("addr")
-                        .value_name("IP-PORT")
-                        .takes_value(true)
-                        .required(true),
-                ),
+                .args(&connection_args()),
         )
         .subcommand(
             SubCommand::with_name("rm")
                 .arg(Arg::with_name("KEY").takes_value(true).required(true))
-                .arg(
-                    Arg::with_name("addr")
-                        .long("addr")
-                        .value_name("IP-PORT")
-                        .takes_value(true)
-                        .required(true),
-                ),
+                .args(&connection_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("backup")
+                .about("Back up the store to a new directory in --backup-dir of the server")
+                .arg(Arg::with_name("DEST").takes_value(true).required(true))
+                .args(&connection_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Print the key count, sizes and counters of the store")
+                .args(&connection_args()),
         )
         .get_matches();
 
     let addr: &str;
     let cmd: Command;
+    let mut is_get = false;
+    let (_, sub_matches) = matches.subcommand();
+    let sub_matches = sub_matches.expect("Subcommand is required");
+    let ca = sub_matches.value_of("ca").map(Path::new);
+    let identity = sub_matches
+        .value_of("cert")
+        .map(Path::new)
+        .zip(sub_matches.value_of("key").map(Path::new));
+    let mut preamble = vec![Command::Handshake(Handshake::default())];
+    if let Some((user, token)) = sub_matches
+        .value_of("user")
+        .zip(sub_matches.value_of("token"))
+    {
+        preamble.push(Command::auth(user.to_owned(), token.to_owned()));
+    }
     match matches.subcommand() {
         ("set", Some(_matches)) => {
             let vals: Vec<_> = _matches
@@ -64,20 +118,36 @@ fn main() -> Result<()> {
             let key = _matches.value_of("KEY").map(ToOwned::to_owned).unwrap();
             addr = _matches.value_of("addr").expect("Address arg is required");
             cmd = Command::get(key);
+            is_get = true;
         }
         ("rm", Some(_matches)) => {
             let key = _matches.value_of("KEY").map(ToOwned::to_owned).unwrap();
             addr = _matches.value_of("addr").expect("Address arg is required");
             cmd = Command::remove(key);
         }
+        ("backup", Some(_matches)) => {
+            let dest = _matches.value_of("DEST").map(ToOwned::to_owned).unwrap();
+            addr = _matches.value_of("addr").expect("Address arg is required");
+            cmd = Command::backup(dest);
+        }
+        ("stats", Some(_matches)) => {
+            addr = _matches.value_of("addr").expect("Address arg is required");
+            cmd = Command::Stats;
+        }
         _ => unreachable!(),
     }
 
     // construct command and send it to server
     let mut stream = TcpStream::connect(addr)?;
-    stream.write_all(&YakvMessage::get_len_payload_bytes(Payload::Command(cmd))?.1)?;
-    stream.flush()?;
-    let response = YakvMessage::new(&mut stream, PayloadType::Response)?;
+    let response = match ca {
+        Some(ca) => {
+            let config = tls::client_config(ca, identity)?;
+            let conn = ClientConnection::new(config, tls::server_name(addr)?)
+                .map_err(|e| YakvError::Any(anyhow!(e)))?;
+            send_commands(&mut StreamOwned::new(conn, stream), preamble, cmd)?
+        }
+        None => send_commands(&mut stream, preamble, cmd)?,
+    };
 
     if let Payload::Response(res) = response.payload {
         let val = if res.is_error {
@@ -89,9 +159,59 @@ fn main() -> Result<()> {
         if res.is_error {
             eprintln!("{}", val.expect("No error message provided"));
             exit(1);
-        } else if val.is_some() {
-            println!("{}", val.expect("Value did not return"));
+        } else if let Some(stats) = res.stats {
+            print_stats(&stats);
+        } else if let Some(val) = val {
+            println!("{}", val);
+        } else if is_get {
+            println!("Key not found");
         }
     }
     Ok(())
 }
+
+// Prints one `name: value` line per statistic, for scripts to grep
+fn print_stats(stats: &StoreStats) {
+    println!("keys: {}", stats.keys);
+    println!("live_bytes: {}", stats.live_bytes);
+    println!("stale_bytes: {}", stats.stale_bytes);
+    println!("generations: {}", stats.generations);
+    match stats.last_compaction {
+        Some(secs) => println!("last_compaction: {}", secs),
+        None => println!("last_compaction: never"),
+    }
+    println!("compactions: {}", stats.compactions);
+    println!("compaction_seconds: {}", stats.compaction_durations.sum);
+    println!("reclaimed_bytes: {}", stats.reclaimed_bytes);
+    println!("syncs: {}", stats.syncs);
+    println!("cache_hits: {}", stats.cache.hits);
+    println!("cache_misses: {}", stats.cache.misses);
+    println!("cache_entries: {}", stats.cache.entries);
+    println!("cache_bytes: {}", stats.cache.size);
+    println!("gets: {}", stats.requests.gets);
+    println!("sets: {}", stats.requests.sets);
+    println!("removes: {}", stats.requests.removes);
+    println!("scans: {}", stats.requests.scans);
+}
+
+// Sends the handshake and authentication commands before `cmd`, stopping at
+// the first error
+fn send_commands<S: Read + Write>(
+    stream: &mut S,
+    preamble: Vec<Command>,
+    cmd: Command,
+) -> Result<YakvMessage> {
+    for first in preamble {
+        let response = send_command(&mut *stream, first)?;
+        if let Payload::Response(Response { is_error: true, .. }) = response.payload {
+            return Ok(response);
+        }
+    }
+    send_command(stream, cmd)
+}
+
+fn send_command<S: Read + Write>(stream: &mut S, cmd: Command) -> Result<YakvMessage> {
+    stream.write_all(&YakvMessage::get_len_payload_bytes(Payload::Command(cmd))?.1)?;
+    stream.flush()?;
+    YakvMessage::new(stream, PayloadType::Response)
+}
@@ -1,143 +1,1236 @@
 use anyhow::*;
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
+use makv::logging::RotatingFile;
+use makv::metrics::ServerMetrics;
+use makv::net::readable;
+use makv::trace::{self, SpanKind};
 use makv::{
-    Command, Engine, KvStore, MakvEngine, Payload, PayloadType, Response, Result,
-    SharedQueueThreadPool, ThreadPool, YakvError, YakvMessage,
+    tls, Acl, Command, Durability, EncryptionKey, Engine, GrpcServer, HttpGateway, KvStore,
+    LsmOptions, LsmStore, MakvEngine, NaiveThreadPool, Payload, PayloadType, Permission,
+    RayonThreadPool, RespServer, Response, Result, SharedQueueThreadPool, SledStore, StoreOptions,
+    ThreadPool, User, WatchedEngine, YakvError, YakvMessage, LEGACY_PROTOCOL_VERSION,
 };
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
 use slog::*;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::ffi::OsStr;
+use std::fmt::Display;
 use std::fs;
-use std::io::Write;
+use std::io::{self, Read, Write};
 use std::iter::Iterator;
 use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::runtime::Handle;
 
 // NOTE: look into structopt
 #[derive(Debug)]
 struct Config {
     addr: SocketAddr,
+    resp_addr: Option<SocketAddr>,
+    http_addr: Option<SocketAddr>,
+    grpc_addr: Option<SocketAddr>,
+    metrics_addr: Option<SocketAddr>,
+    tls: Option<Arc<ServerConfig>>,
+    acl: Option<Arc<Acl>>,
+    // backups go to subdirectories of it, none without it
+    backup_dir: Option<Arc<Path>>,
+    limits: Limits,
+    // also used as the threshold for compressed frames
+    store_options: StoreOptions,
     engine: Engine,
+    pool: Pool,
+    pool_size: u32,
 }
 
-struct YakvServer<E> {
+// Thread pools which can serve requests on --addr
+#[derive(Debug, Clone, Copy)]
+enum Pool {
+    SharedQueue,
+    Rayon,
+    Naive,
+}
+
+const ENGINES: &[&str] = &["yakv", "sled", "lsm"];
+const POOLS: &[&str] = &["shared-queue", "rayon", "naive"];
+const DURABILITIES: &[&str] = &["buffered", "sync", "group"];
+const LOG_LEVELS: &[&str] = &["critical", "error", "warning", "info", "debug", "trace"];
+const LOG_FORMATS: &[&str] = &["text", "json"];
+
+// Limits applied to every connection on --addr
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    max_frame_size: u32,
+    // how long a connection may be idle, so half-open sockets are closed
+    read_timeout: Option<Duration>,
+    // how long a client may take to read its response
+    write_timeout: Option<Duration>,
+}
+
+struct YakvServer<E: MakvEngine> {
     config: Config,
     log: slog::Logger,
-    store: E,
+    // shared by every front-end so that watchers see all changes
+    store: WatchedEngine<E>,
+    metrics: Arc<ServerMetrics>,
 }
 
-impl<E: MakvEngine> YakvServer<E> {
+impl<E: MakvEngine + Sync> YakvServer<E> {
     fn new(config: Config, log: slog::Logger, store: E) -> Self {
-        YakvServer { config, log, store }
+        YakvServer {
+            config,
+            log,
+            store: WatchedEngine::new(store),
+            metrics: Arc::new(ServerMetrics::default()),
+        }
     }
 
-    fn start(&self) -> Result<()> {
-        let listener = TcpListener::bind(&self.config.addr)?;
-        let pool = SharedQueueThreadPool::new(8)?;
-        for stream in listener.incoming() {
+    fn start<P: ThreadPool + Send + Sync + 'static>(&self) -> Result<()> {
+        if let Some(resp_addr) = self.config.resp_addr {
+            let resp = RespServer::new(
+                self.store.clone(),
+                self.log.new(o!("front_end" => "resp")),
+                self.metrics.clone(),
+            );
+            info!(self.log, "RESP listening on {}", resp_addr);
+            let log = self.log.clone();
+            let pool_size = self.config.pool_size;
+            let max_frame_size = self.config.limits.max_frame_size;
+            thread::Builder::new().spawn(move || {
+                if let Err(e) = resp.start(resp_addr, pool_size, max_frame_size) {
+                    error!(log, "RESP server failed: {}", e);
+                }
+            })?;
+        }
+
+        if let Some(http_addr) = self.config.http_addr {
+            let http = HttpGateway::new(self.store.clone(), self.metrics.clone());
+            info!(self.log, "HTTP listening on {}", http_addr);
+            let log = self.log.clone();
+            let pool_size = self.config.pool_size;
+            thread::Builder::new().spawn(move || {
+                if let Err(e) = http.start(http_addr, pool_size) {
+                    error!(log, "HTTP server failed: {}", e);
+                }
+            })?;
+        }
+
+        if let Some(grpc_addr) = self.config.grpc_addr {
+            let grpc = GrpcServer::new(self.store.clone(), self.metrics.clone());
+            info!(self.log, "gRPC listening on {}", grpc_addr);
+            let log = self.log.clone();
+            thread::Builder::new().spawn(move || {
+                if let Err(e) = grpc.start(grpc_addr) {
+                    error!(log, "gRPC server failed: {}", e);
+                }
+            })?;
+        }
+
+        if let Some(metrics_addr) = self.config.metrics_addr {
+            let metrics = self.metrics.clone();
             let store = self.store.clone();
-            pool.spawn(move || {
-                if let Ok(tcp_stream) = stream {
-                    match handle_request(&tcp_stream, store) {
-                        Ok(res) => {
-                            send_response(&tcp_stream, res).unwrap();
-                        }
-                        Err(e) => {
-                            let res = Response::new(true, Some(e.to_string()), None);
-                            send_response(&tcp_stream, res).unwrap();
-                        }
-                    }
+            info!(self.log, "metrics listening on {}", metrics_addr);
+            let log = self.log.clone();
+            thread::Builder::new().spawn(move || {
+                if let Err(e) = metrics.start(metrics_addr, store) {
+                    error!(log, "metrics server failed: {}", e);
                 }
-            });
+            })?;
+        }
+
+        let listener = TcpListener::bind(self.config.addr)?;
+        let idle = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("idle-connections")
+            .enable_all()
+            .build()?;
+        let connections = Arc::new(Connections {
+            store: self.store.clone(),
+            pool: P::new(self.config.pool_size)?,
+            idle: idle.handle().clone(),
+            tls: self.config.tls.clone(),
+            acl: self.config.acl.clone(),
+            backup_dir: self.config.backup_dir.clone(),
+            limits: self.config.limits,
+            compression_threshold: self.config.store_options.compression_threshold,
+            metrics: self.metrics.clone(),
+        });
+        for stream in listener.incoming().flatten() {
+            connections.accept(stream, &self.log);
         }
         Ok(())
     }
 }
 
-fn send_response(mut stream: &TcpStream, res: Response) -> Result<()> {
-    let (_, bytes) = YakvMessage::get_len_payload_bytes(Payload::Response(res))?;
+// The connections on --addr.
+//
+// A connection only holds a thread of the pool while one of its requests is
+// served. In between it waits on the `idle` runtime, so that idle clients
+// can't keep others from being served.
+struct Connections<E: MakvEngine, P> {
+    store: WatchedEngine<E>,
+    pool: P,
+    idle: Handle,
+    tls: Option<Arc<ServerConfig>>,
+    acl: Option<Arc<Acl>>,
+    backup_dir: Option<Arc<Path>>,
+    limits: Limits,
+    // also used as the threshold for compressed frames
+    compression_threshold: usize,
+    metrics: Arc<ServerMetrics>,
+}
+
+// A connection on --addr with what it keeps between two requests
+struct Connection {
+    stream: Stream,
+    session: Session,
+    // has the peer address of the connection
+    log: Logger,
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl Stream {
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Tcp(stream) => stream,
+            Stream::Tls(stream) => &stream.sock,
+        }
+    }
+
+    // Whether a request was already read from the socket, e.g. decrypted
+    // along with the previous one
+    fn has_buffered(&mut self) -> bool {
+        match self {
+            Stream::Tcp(_) => false,
+            // a broken session is left for the next read to report
+            Stream::Tls(stream) => stream
+                .conn
+                .process_new_packets()
+                .map_or(true, |state| state.plaintext_bytes_to_read() > 0),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl<E, P> Connections<E, P>
+where
+    E: MakvEngine + Sync,
+    P: ThreadPool + Send + Sync + 'static,
+{
+    fn accept(self: &Arc<Self>, stream: TcpStream, log: &Logger) {
+        let peer = match stream.peer_addr() {
+            Ok(peer) => peer.to_string(),
+            Err(_) => "unknown".to_owned(),
+        };
+        let log = log.new(o!("peer" => peer));
+        // the read timeout also covers clients which stop mid-frame
+        if let Err(e) = stream
+            .set_read_timeout(self.limits.read_timeout)
+            .and_then(|_| stream.set_write_timeout(self.limits.write_timeout))
+        {
+            error!(log, "Failed to set socket timeouts: {}", e);
+            return;
+        }
+        let stream = match &self.tls {
+            Some(tls) => match ServerConnection::new(tls.clone()) {
+                Ok(conn) => Stream::Tls(Box::new(StreamOwned::new(conn, stream))),
+                Err(e) => {
+                    error!(log, "Failed to start TLS session: {}", e);
+                    return;
+                }
+            },
+            None => Stream::Tcp(stream),
+        };
+        self.metrics.serve_connection(true);
+        self.wait(Connection {
+            stream,
+            session: Session::new(
+                self.acl.clone(),
+                self.backup_dir.clone(),
+                self.compression_threshold,
+            ),
+            log,
+        });
+    }
+
+    // Serves the connection once the client sends a request, or closes it
+    // once it's been idle for the read timeout
+    fn wait(self: &Arc<Self>, conn: Connection) {
+        let connections = self.clone();
+        self.idle.spawn(async move {
+            let readable = readable(conn.stream.tcp(), connections.limits.read_timeout).await;
+            match readable {
+                Ok(true) => connections.dispatch(conn),
+                Ok(false) => connections.close(conn),
+                Err(e) => {
+                    warn!(conn.log, "Failed to wait for a request: {}", e);
+                    connections.close(conn);
+                }
+            }
+        });
+    }
+
+    fn dispatch(self: &Arc<Self>, conn: Connection) {
+        self.metrics.queue_connection(true);
+        let queued_at = SystemTime::now();
+        let connections = self.clone();
+        self.pool.spawn(move || {
+            connections.metrics.queue_connection(false);
+            connections.serve(conn, (queued_at, SystemTime::now()));
+        });
+    }
+
+    // Serves the requests the client has sent, then waits for more
+    fn serve(self: &Arc<Self>, mut conn: Connection, queued: (SystemTime, SystemTime)) {
+        let mut queued = Some(queued);
+        loop {
+            if !self.serve_request(&mut conn, &mut queued) {
+                return self.close(conn);
+            }
+            if !conn.stream.has_buffered() {
+                return self.wait(conn);
+            }
+        }
+    }
+
+    // Reads and answers one request. Returns false once the connection is to
+    // be closed: the client closed it, stopped mid-frame or was sent a fatal
+    // error.
+    fn serve_request(
+        &self,
+        conn: &mut Connection,
+        queued: &mut Option<(SystemTime, SystemTime)>,
+    ) -> bool {
+        let Connection {
+            stream,
+            session,
+            log,
+        } = conn;
+        let message = YakvMessage::with_max_frame_size(
+            &mut *stream,
+            PayloadType::Command,
+            self.limits.max_frame_size,
+        );
+        let res = match message {
+            Ok(message) => {
+                let start = Instant::now();
+                let (command, key) = match &message.payload {
+                    Payload::Command(cmd) => (cmd.name(), cmd.key().map(str::to_owned)),
+                    Payload::Response(_) => ("response", None),
+                };
+                if message.trace.is_some() {
+                    // the wait joins the trace of the first traced request
+                    if let Some((queued_at, dequeued_at)) = queued.take() {
+                        trace::record_span("server.queue", message.trace, queued_at, dequeued_at);
+                    }
+                }
+                let mut span =
+                    trace::start_span("server.request", SpanKind::Server, message.trace).entered();
+                span.set_attribute("command", command);
+                let res = session.handle(message, self.store.clone());
+                if let Err(e) = &res {
+                    span.set_error(e);
+                }
+                drop(span);
+                let latency = start.elapsed();
+                self.metrics.observe_request(command, latency);
+                info!(log, "request";
+                    "command" => command,
+                    "key" => key,
+                    "latency_us" => latency.as_micros() as u64,
+                    "outcome" => res.as_ref().err().map_or("ok", YakvError::name));
+                res
+            }
+            Err(YakvError::Io(_)) => return false,
+            Err(e) => {
+                warn!(log, "bad frame: {}", e; "outcome" => e.name());
+                Err(e)
+            }
+        }
+        .unwrap_or_else(|e| {
+            self.metrics.count_error(&e);
+            Response::from_error(&e)
+        });
+        let closes = res.closes_connection();
+        send_response(&mut *stream, res, session.compression()).is_ok() && !closes
+    }
+
+    fn close(&self, conn: Connection) {
+        drop(conn);
+        self.metrics.serve_connection(false);
+    }
+}
+
+// State of a client connection.
+//
+// A connection may start with a handshake. With an ACL the next frame must be
+// an `Auth` command; otherwise the client gets an `Unauthenticated` error and
+// the connection is closed.
+struct Session {
+    acl: Option<Arc<Acl>>,
+    backup_dir: Option<Arc<Path>>,
+    // None until the first frame; LEGACY_PROTOCOL_VERSION without a handshake
+    version: Option<u32>,
+    user: Option<Arc<User>>,
+    compression_threshold: usize,
+    // set if the handshake agreed on compressed frames
+    compress: bool,
+}
+
+impl Session {
+    fn new(
+        acl: Option<Arc<Acl>>,
+        backup_dir: Option<Arc<Path>>,
+        compression_threshold: usize,
+    ) -> Self {
+        Session {
+            acl,
+            backup_dir,
+            version: None,
+            user: None,
+            compression_threshold,
+            compress: false,
+        }
+    }
+
+    // Returns the threshold for compressing responses, if the client wants them compressed
+    fn compression(&self) -> Option<usize> {
+        Some(self.compression_threshold).filter(|_| self.compress)
+    }
+
+    fn handle<E: MakvEngine>(&mut self, message: YakvMessage, store: E) -> Result<Response> {
+        match (&message.payload, self.version) {
+            (Payload::Command(Command::Handshake(handshake)), None) => {
+                let handshake = handshake.negotiate()?;
+                self.version = Some(handshake.version);
+                self.compress = handshake.has("lz4");
+                return Ok(Response::handshake(handshake));
+            }
+            (_, None) => self.version = Some(LEGACY_PROTOCOL_VERSION),
+            _ => {}
+        }
+
+        match &self.acl {
+            Some(acl) if self.user.is_none() => {
+                self.user = Some(authenticate(message, acl)?);
+                Ok(Response::default())
+            }
+            _ => handle_request(
+                message,
+                store,
+                self.user.as_deref(),
+                self.backup_dir.as_deref(),
+                self.version == Some(LEGACY_PROTOCOL_VERSION),
+            ),
+        }
+    }
+}
+
+// Checks the credentials of the first command after the handshake.
+fn authenticate(message: YakvMessage, acl: &Acl) -> Result<Arc<User>> {
+    match message.payload {
+        Payload::Command(Command::Auth { user, token }) => acl
+            .authenticate(&user, &token)
+            .ok_or_else(|| YakvError::Unauthenticated("invalid user or token".to_owned())),
+        _ => Err(YakvError::Unauthenticated(
+            "the first command must be auth".to_owned(),
+        )),
+    }
+}
+
+fn send_response<W: Write>(
+    mut stream: W,
+    res: Response,
+    compression_threshold: Option<usize>,
+) -> Result<()> {
+    let payload = Payload::Response(res);
+    let (_, bytes) = match compression_threshold {
+        Some(threshold) => YakvMessage::get_compressed_len_payload_bytes(payload, threshold)?,
+        None => YakvMessage::get_len_payload_bytes(payload)?,
+    };
     stream.write_all(&bytes)?;
     stream.flush()?;
     Ok(())
 }
 
-fn handle_request<E: MakvEngine>(mut stream: &TcpStream, store: E) -> Result<Response> {
-    let message = YakvMessage::new(&mut stream, PayloadType::Command)?;
+// Clients which skipped the handshake are `legacy` and get the replies of the
+// first protocol
+fn handle_request<E: MakvEngine>(
+    message: YakvMessage,
+    store: E,
+    user: Option<&User>,
+    backup_dir: Option<&Path>,
+    legacy: bool,
+) -> Result<Response> {
     let mut response: Response = Default::default();
+    let check = |key: &str, permission| user.map_or(Ok(()), |user| user.check(key, permission));
 
     if let Payload::Command(cmd) = message.payload {
         match cmd {
             Command::Set { key, value } => {
+                check(&key, Permission::Write)?;
                 store.set(key, value)?;
             }
             Command::Get { key } => {
-                response = Response::new(
-                    false,
-                    None,
-                    store.get(key)?.or(Some("Key not found".to_string())),
-                );
+                check(&key, Permission::Read)?;
+                let mut value = store.get(key)?;
+                if legacy && value.is_none() {
+                    value = Some("Key not found".to_owned());
+                }
+                response = Response::new(false, None, value);
             }
             Command::Remove { key } => {
+                check(&key, Permission::Write)?;
                 store.remove(key)?;
             }
+            // without an ACL credentials are accepted and ignored
+            Command::Auth { .. } if user.is_none() => {}
+            Command::Auth { .. } => {
+                return Err(YakvError::Any(anyhow!(
+                    "connection is already authenticated"
+                )));
+            }
+            Command::Handshake(_) => {
+                return Err(YakvError::Any(anyhow!(
+                    "handshake must be the first command"
+                )));
+            }
+            // a backup holds every key and writes files on the server
+            Command::Backup { dest } => {
+                check("", Permission::Read)?;
+                check("", Permission::Write)?;
+                store.backup(&backup_path(backup_dir, &dest)?)?;
+            }
+            // the key count and sizes cover every key
+            Command::Stats => {
+                check("", Permission::Read)?;
+                response = Response::stats(store.stats()?);
+            }
         }
     }
 
     Ok(response)
 }
 
-fn main() -> Result<()> {
-    let decorator = slog_term::PlainSyncDecorator::new(std::io::stderr());
-    let drain = slog_term::FullFormat::new(decorator).build().fuse();
-
-    let log = slog::Logger::root(drain, o!());
-    info!(log, "version: {}", env!("CARGO_PKG_VERSION"));
+// Resolves the destination of a backup in --backup-dir. Only relative paths
+// without `..` are accepted, so that clients can't write anywhere else.
+fn backup_path(backup_dir: Option<&Path>, dest: &str) -> Result<PathBuf> {
+    let backup_dir = backup_dir.ok_or_else(|| {
+        YakvError::Any(anyhow!(
+            "backups are disabled, start the server with --backup-dir"
+        ))
+    })?;
+    let dest = Path::new(dest);
+    let normal = dest
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if dest.as_os_str().is_empty() || !normal {
+        return Err(YakvError::Any(anyhow!(
+            "backup destination '{}' must be a relative path without '..'",
+            dest.display()
+        )));
+    }
+    Ok(backup_dir.join(dest))
+}
 
+fn main() -> Result<()> {
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
+        .after_help(
+            "Every option can also be set in a MAKV_<OPTION> environment variable, e.g. \
+             MAKV_POOL_SIZE, or in the TOML file of --config. Arguments override environment \
+             variables, which override the file.",
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("TOML-FILE")
+                .help("Read settings from this file")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("addr")
                 .long("addr")
                 .value_name("IP-PORT")
-                .takes_value(true)
-                .required(true),
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("data-dir")
+                .long("data-dir")
+                .value_name("DIR")
+                .help("Keep the data in this directory instead of the current one")
+                .takes_value(true),
         )
         .arg(
             Arg::with_name("engine")
                 .long("engine")
                 .value_name("ENGINE-NAME")
                 .takes_value(true)
+                .possible_values(ENGINES)
                 .default_value("yakv"),
         )
+        .arg(
+            Arg::with_name("pool")
+                .long("pool")
+                .value_name("shared-queue|rayon|naive")
+                .help("Thread pool which serves requests on --addr")
+                .takes_value(true)
+                .possible_values(POOLS)
+                .default_value("shared-queue"),
+        )
+        .arg(
+            Arg::with_name("pool-size")
+                .long("pool-size")
+                .value_name("THREADS")
+                .help("Number of threads of the pool of each front-end")
+                .takes_value(true)
+                .default_value("8"),
+        )
+        .arg(
+            Arg::with_name("resp-addr")
+                .long("resp-addr")
+                .value_name("IP-PORT")
+                .help("Also serve the Redis protocol (RESP2) on this address")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("http-addr")
+                .long("http-addr")
+                .value_name("IP-PORT")
+                .help("Also serve the HTTP/JSON gateway on this address")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("grpc-addr")
+                .long("grpc-addr")
+                .value_name("IP-PORT")
+                .help("Also serve the gRPC KeyValue service on this address")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metrics-addr")
+                .long("metrics-addr")
+                .value_name("IP-PORT")
+                .help("Serve Prometheus metrics at /metrics on this address")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls-cert")
+                .long("tls-cert")
+                .value_name("PEM-FILE")
+                .help("Serve TLS on --addr with this certificate chain")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls-key")
+                .long("tls-key")
+                .value_name("PEM-FILE")
+                .help("Private key for --tls-cert")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls-client-ca")
+                .long("tls-client-ca")
+                .value_name("PEM-FILE")
+                .help("Require client certificates signed by this CA (mTLS)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("acl")
+                .long("acl")
+                .value_name("TOML-FILE")
+                .help("Require clients on --addr to authenticate against this ACL")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("backup-dir")
+                .long("backup-dir")
+                .value_name("DIR")
+                .help("Let clients back up the store to subdirectories of this directory")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-frame-size")
+                .long("max-frame-size")
+                .value_name("BYTES")
+                .help("Close connections sending frames larger than this")
+                .takes_value(true)
+                .default_value("16777216"),
+        )
+        .arg(
+            Arg::with_name("read-timeout")
+                .long("read-timeout")
+                .value_name("SECONDS")
+                .help("Close connections idle for this long, 0 to never close them")
+                .takes_value(true)
+                .default_value("300"),
+        )
+        .arg(
+            Arg::with_name("write-timeout")
+                .long("write-timeout")
+                .value_name("SECONDS")
+                .help("Close connections not reading responses for this long, 0 to wait forever")
+                .takes_value(true)
+                .default_value("30"),
+        )
+        .arg(
+            Arg::with_name("compression")
+                .long("compression")
+                .value_name("none|lz4")
+                .help("Compression for new log records")
+                .takes_value(true)
+                .possible_values(&["none", "lz4"])
+                .default_value("none"),
+        )
+        .arg(
+            Arg::with_name("compression-threshold")
+                .long("compression-threshold")
+                .value_name("BYTES")
+                .help("Only compress log records and frames of at least this size")
+                .takes_value(true)
+                .default_value("1024"),
+        )
+        .arg(
+            Arg::with_name("compaction-threshold")
+                .long("compaction-threshold")
+                .value_name("BYTES")
+                .help("Compact the logs once this many bytes of them are stale")
+                .takes_value(true)
+                .default_value("1048576"),
+        )
+        .arg(
+            Arg::with_name("encryption-key-file")
+                .long("encryption-key-file")
+                .value_name("FILE")
+                .help(
+                    "Encrypt log records with the key in this file (32 bytes or 64 hex \
+                     characters), defaults to the hex key in MAKV_ENCRYPTION_KEY",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("index")
+                .long("index")
+                .value_name("memory|disk")
+                .help("Keep the index of keys in memory, or paged to disk for large stores")
+                .takes_value(true)
+                .possible_values(&["memory", "disk"])
+                .default_value("memory"),
+        )
+        .arg(
+            Arg::with_name("cache-size")
+                .long("cache-size")
+                .value_name("BYTES")
+                .help("Cache this many bytes of recently read values, 0 disables the cache")
+                .takes_value(true)
+                .default_value("0"),
+        )
+        .arg(
+            Arg::with_name("durability")
+                .long("durability")
+                .value_name("buffered|sync|group")
+                .help(
+                    "Leave writes in OS buffers, fsync every write, or fsync concurrent writes \
+                     together",
+                )
+                .takes_value(true)
+                .possible_values(DURABILITIES)
+                .default_value("buffered"),
+        )
+        .arg(
+            Arg::with_name("group-commit-window")
+                .long("group-commit-window")
+                .value_name("MILLISECONDS")
+                .help("Longest time a write waits for others to share its fsync")
+                .takes_value(true)
+                .default_value("2"),
+        )
+        .arg(
+            Arg::with_name("otlp-endpoint")
+                .long("otlp-endpoint")
+                .value_name("URL")
+                .help("Export spans as OTLP/HTTP JSON, e.g. to http://localhost:4318/v1/traces")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .value_name("LEVEL")
+                .help("Least severe level which is logged")
+                .takes_value(true)
+                .possible_values(LOG_LEVELS)
+                .default_value("info"),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .value_name("text|json")
+                .help("Log plain text lines or one JSON object per line")
+                .takes_value(true)
+                .possible_values(LOG_FORMATS)
+                .default_value("text"),
+        )
+        .arg(
+            Arg::with_name("log-file")
+                .long("log-file")
+                .value_name("PATH")
+                .help("Log to this file instead of stderr")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-max-size")
+                .long("log-max-size")
+                .value_name("BYTES")
+                .help("Rotate the log file once it reaches this size")
+                .takes_value(true)
+                .default_value("10485760"),
+        )
+        .arg(
+            Arg::with_name("log-keep")
+                .long("log-keep")
+                .value_name("COUNT")
+                .help("Number of rotated log files to keep")
+                .takes_value(true)
+                .default_value("5"),
+        )
         .get_matches();
 
-    let addr = matches.value_of("addr").expect("ADDR arg is required");
-    let engine_arg = matches.value_of("engine").expect("ENGINE arg is required");
-    let config = Config {
-        addr: SocketAddr::from_str(addr).expect("Address is not a valid IPV4 address."),
-        engine: Engine::from_str(engine_arg).unwrap_or(Engine::Yakv),
-    };
+    let settings = Settings::load(matches)?;
+    let log = logger(&settings)?;
+    info!(log, "version: {}", env!("CARGO_PKG_VERSION"));
+    if let Some(endpoint) = settings.value("otlp-endpoint") {
+        trace::install(
+            "makv-server",
+            &endpoint,
+            log.new(o!("component" => "trace")),
+        )?;
+        info!(log, "exporting spans to {}", endpoint);
+    }
 
-    let current_dir = env::current_dir()?;
-    let existing_engines = get_existing_engines(current_dir.clone())?;
-    if !existing_engines.is_empty() && !existing_engines.contains(&config.engine) {
-        return Err(YakvError::Any(anyhow!(
-            "Engine value is different from already used engines."
-        )));
+    let config = config(&settings)?;
+    let data_dir = match settings.get("data-dir") {
+        Some((dir, source)) => {
+            let dir = PathBuf::from(dir);
+            if !dir.is_dir() {
+                return Err(YakvError::Any(anyhow!(
+                    "data directory {} from {} does not exist",
+                    dir.display(),
+                    source
+                )));
+            }
+            dir
+        }
+        None => env::current_dir()?,
+    };
+    match Engine::active(&data_dir)? {
+        Some(active) if active != config.engine => {
+            return Err(YakvError::Any(anyhow!(
+                "The data of engine {} is active in this directory.",
+                active.name()
+            )));
+        }
+        Some(_) => {}
+        None => {
+            let existing_engines = get_existing_engines(data_dir.clone())?;
+            if !existing_engines.is_empty() && !existing_engines.contains(&config.engine) {
+                return Err(YakvError::Any(anyhow!(
+                    "Engine value is different from already used engines. Move the data to \
+                     another engine with `makv-admin migrate`."
+                )));
+            }
+        }
     }
 
     match config.engine {
         Engine::Yakv => {
-            let store = KvStore::open(current_dir)?;
-            let server = YakvServer::new(config, log, store);
-            server.start()?;
+            let options = StoreOptions {
+                log: log.new(o!("engine" => "yakv")),
+                ..config.store_options.clone()
+            };
+            let store = KvStore::open_with(data_dir, options)?;
+            serve(config, log, store)
+        }
+        Engine::Lsm => {
+            let options = LsmOptions {
+                durability: config.store_options.durability,
+                ..LsmOptions::default()
+            };
+            serve(config, log, LsmStore::open_with(data_dir, options)?)
+        }
+        Engine::Sled => serve(config, log, SledStore::open(data_dir)?),
+    }
+}
+
+// Runs the server on the thread pool of the config
+fn serve<E: MakvEngine + Sync>(config: Config, log: Logger, store: E) -> Result<()> {
+    let pool = config.pool;
+    let server = YakvServer::new(config, log, store);
+    match pool {
+        Pool::SharedQueue => server.start::<SharedQueueThreadPool>(),
+        Pool::Rayon => server.start::<RayonThreadPool>(),
+        Pool::Naive => server.start::<NaiveThreadPool>(),
+    }
+}
+
+// Validates the settings
+fn config(settings: &Settings) -> Result<Config> {
+    let tls = match (settings.value("tls-cert"), settings.value("tls-key")) {
+        (Some(cert), Some(key)) => Some(tls::server_config(
+            Path::new(&cert),
+            Path::new(&key),
+            settings.value("tls-client-ca").as_deref().map(Path::new),
+        )?),
+        (None, None) if settings.value("tls-client-ca").is_none() => None,
+        _ => {
+            return Err(YakvError::Any(anyhow!(
+                "TLS needs both --tls-cert and --tls-key, also with --tls-client-ca"
+            )))
         }
-        Engine::Sled => {}
     };
+    let max_frame_size = settings.at_least("max-frame-size", 1)?;
+    // the two highest bits of the length prefix are flags
+    if max_frame_size >= 1 << 30 {
+        return Err(YakvError::Any(anyhow!(
+            "--max-frame-size must be less than 1 GiB, got {}",
+            max_frame_size
+        )));
+    }
+    let acl = match settings.value("acl") {
+        Some(path) => Some(Arc::new(Acl::load(Path::new(&path))?)),
+        None => None,
+    };
+    // only --addr authenticates clients, the other front-ends would let
+    // anyone past the ACL
+    if acl.is_some() {
+        for name in &["resp-addr", "http-addr", "grpc-addr"] {
+            if let Some((_, source)) = settings.get(name) {
+                return Err(YakvError::Any(anyhow!(
+                    "--acl only applies to --addr and can't be used with {}",
+                    source
+                )));
+            }
+        }
+    }
+    let backup_dir = match settings.get("backup-dir") {
+        Some((dir, source)) => {
+            let dir = PathBuf::from(dir);
+            if !dir.is_dir() {
+                return Err(YakvError::Any(anyhow!(
+                    "backup directory {} from {} does not exist",
+                    dir.display(),
+                    source
+                )));
+            }
+            Some(Arc::from(dir))
+        }
+        None => None,
+    };
+    Ok(Config {
+        addr: settings.required("addr")?,
+        resp_addr: settings.parse("resp-addr")?,
+        http_addr: settings.parse("http-addr")?,
+        grpc_addr: settings.parse("grpc-addr")?,
+        metrics_addr: settings.parse("metrics-addr")?,
+        tls,
+        acl,
+        backup_dir,
+        limits: Limits {
+            max_frame_size: max_frame_size as u32,
+            read_timeout: settings.timeout("read-timeout")?,
+            write_timeout: settings.timeout("write-timeout")?,
+        },
+        store_options: StoreOptions {
+            compression: settings.required("compression")?,
+            compression_threshold: settings.required("compression-threshold")?,
+            encryption_key: match settings.value("encryption-key-file") {
+                Some(path) => Some(EncryptionKey::from_file(Path::new(&path))?),
+                None => EncryptionKey::from_env("MAKV_ENCRYPTION_KEY")?,
+            },
+            previous_keys: Vec::new(),
+            index: settings.required("index")?,
+            cache_size: settings.required("cache-size")?,
+            durability: match settings.choice("durability", DURABILITIES)? {
+                "sync" => Durability::Sync,
+                "group" => Durability::group(Duration::from_millis(
+                    settings.required("group-commit-window")?,
+                )),
+                _ => Durability::Buffered,
+            },
+            compaction_threshold: settings.at_least("compaction-threshold", 1)?,
+            // the store gets the server's logger once it is opened
+            ..StoreOptions::default()
+        },
+        engine: Engine::from_str(settings.choice("engine", ENGINES)?).expect("engine is valid"),
+        pool: match settings.choice("pool", POOLS)? {
+            "rayon" => Pool::Rayon,
+            "naive" => Pool::Naive,
+            _ => Pool::SharedQueue,
+        },
+        pool_size: settings.at_least("pool-size", 1)? as u32,
+    })
+}
 
-    Ok(())
+// Builds the logger described by the --log-* settings
+fn logger(settings: &Settings) -> Result<Logger> {
+    let level =
+        Level::from_str(settings.choice("log-level", LOG_LEVELS)?).expect("log level is valid");
+    let writer: Box<dyn Write + Send> = match settings.value("log-file") {
+        Some(path) => Box::new(RotatingFile::open(
+            path,
+            settings.at_least("log-max-size", 1)?,
+            settings.required("log-keep")?,
+        )?),
+        None => Box::new(std::io::stderr()),
+    };
+    let drain: Box<dyn Drain<Ok = (), Err = Never> + Send> =
+        match settings.choice("log-format", LOG_FORMATS)? {
+            "json" => Box::new(
+                slog_json::Json::new(writer)
+                    .add_default_keys()
+                    .build()
+                    .fuse(),
+            ),
+            _ => {
+                let decorator = slog_term::PlainSyncDecorator::new(writer);
+                Box::new(slog_term::FullFormat::new(decorator).build().fuse())
+            }
+        };
+    let drain = LevelFilter::new(drain, level).fuse();
+    Ok(Logger::root(Mutex::new(drain).fuse(), o!()))
+}
+
+// Settings which may be given in the config file, by section and key, with
+// the argument they stand for, e.g.
+//
+//     [server]
+//     addr = "127.0.0.1:4000"
+//     data-dir = "/var/lib/makv"
+//
+//     [pool]
+//     size = 16
+//
+//     [log]
+//     format = "json"
+const FILE_SETTINGS: &[(&str, &str, &str)] = &[
+    ("server", "addr", "addr"),
+    ("server", "data-dir", "data-dir"),
+    ("server", "engine", "engine"),
+    ("server", "resp-addr", "resp-addr"),
+    ("server", "http-addr", "http-addr"),
+    ("server", "grpc-addr", "grpc-addr"),
+    ("server", "metrics-addr", "metrics-addr"),
+    ("server", "acl", "acl"),
+    ("server", "backup-dir", "backup-dir"),
+    ("pool", "type", "pool"),
+    ("pool", "size", "pool-size"),
+    ("tls", "cert", "tls-cert"),
+    ("tls", "key", "tls-key"),
+    ("tls", "client-ca", "tls-client-ca"),
+    ("limits", "max-frame-size", "max-frame-size"),
+    ("limits", "read-timeout", "read-timeout"),
+    ("limits", "write-timeout", "write-timeout"),
+    ("store", "compression", "compression"),
+    ("store", "compression-threshold", "compression-threshold"),
+    ("store", "compaction-threshold", "compaction-threshold"),
+    ("store", "encryption-key-file", "encryption-key-file"),
+    ("store", "index", "index"),
+    ("store", "cache-size", "cache-size"),
+    ("store", "durability", "durability"),
+    ("store", "group-commit-window", "group-commit-window"),
+    ("log", "level", "log-level"),
+    ("log", "format", "log-format"),
+    ("log", "file", "log-file"),
+    ("log", "max-size", "log-max-size"),
+    ("log", "keep", "log-keep"),
+    ("tracing", "otlp-endpoint", "otlp-endpoint"),
+];
+
+// The value of every setting, from the first of its argument, its
+// MAKV_<ARGUMENT> environment variable, the config file and the argument's
+// default
+struct Settings<'a> {
+    matches: ArgMatches<'a>,
+    // values of the config file by argument, with where they were found
+    file: HashMap<&'static str, (String, String)>,
+}
+
+impl<'a> Settings<'a> {
+    fn load(matches: ArgMatches<'a>) -> Result<Self> {
+        let mut file = HashMap::new();
+        let path = match matches.value_of("config") {
+            Some(path) => Some(path.to_owned()),
+            None => env::var(env_var("config")).ok(),
+        };
+        if let Some(path) = &path {
+            let content = fs::read_to_string(path).map_err(|e| {
+                YakvError::Any(anyhow!("Failed to read config file {}: {}", path, e))
+            })?;
+            let table: toml::Table = toml::from_str(&content)
+                .map_err(|e| YakvError::Any(anyhow!("Invalid config file {}: {}", path, e)))?;
+            for (section, keys) in table {
+                let keys = match keys {
+                    toml::Value::Table(keys) => keys,
+                    _ => {
+                        return Err(YakvError::Any(anyhow!(
+                            "{}: `{}` must be a section like [{}]",
+                            path,
+                            section,
+                            section
+                        )))
+                    }
+                };
+                for (key, value) in keys {
+                    let name = format!("{}.{}", section, key);
+                    let arg = FILE_SETTINGS
+                        .iter()
+                        .find(|(s, k, _)| *s == section && *k == key)
+                        .map(|(_, _, arg)| *arg)
+                        .ok_or_else(|| {
+                            YakvError::Any(anyhow!("{}: unknown setting `{}`", path, name))
+                        })?;
+                    let value = match value {
+                        toml::Value::String(value) => value,
+                        toml::Value::Integer(value) => value.to_string(),
+                        toml::Value::Boolean(value) => value.to_string(),
+                        _ => {
+                            return Err(YakvError::Any(anyhow!(
+                                "{}: `{}` must be a string or an integer",
+                                path,
+                                name
+                            )))
+                        }
+                    };
+                    file.insert(arg, (value, format!("`{}` in {}", name, path)));
+                }
+            }
+        }
+        Ok(Settings { matches, file })
+    }
+
+    // Returns the value of a setting and where it came from
+    fn get(&self, name: &str) -> Option<(String, String)> {
+        if self.matches.occurrences_of(name) > 0 {
+            let value = self.matches.value_of(name).expect("arg takes a value");
+            return Some((value.to_owned(), format!("--{}", name)));
+        }
+        let var = env_var(name);
+        if let Ok(value) = env::var(&var) {
+            return Some((value, var));
+        }
+        if let Some(found) = self.file.get(name) {
+            return Some(found.clone());
+        }
+        let value = self.matches.value_of(name)?;
+        Some((value.to_owned(), format!("the default of --{}", name)))
+    }
+
+    fn value(&self, name: &str) -> Option<String> {
+        self.get(name).map(|(value, _)| value)
+    }
+
+    // Parses a setting, naming where an invalid value came from
+    fn parse<T>(&self, name: &str) -> Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.get(name)
+            .map(|(value, source)| {
+                value.parse().map_err(|e| {
+                    YakvError::Any(anyhow!("Invalid value '{}' from {}: {}", value, source, e))
+                })
+            })
+            .transpose()
+    }
+
+    // Like `parse`, for settings which have a default or must be given
+    fn required<T>(&self, name: &str) -> Result<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.parse(name)?.ok_or_else(|| {
+            YakvError::Any(anyhow!(
+                "--{} is required, also as {} or in the config file",
+                name,
+                env_var(name)
+            ))
+        })
+    }
+
+    // A number of at least `min`
+    fn at_least(&self, name: &str, min: u64) -> Result<u64> {
+        let value = self.required(name)?;
+        if value < min {
+            let (_, source) = self.get(name).expect("setting has a value");
+            return Err(YakvError::Any(anyhow!(
+                "{} from {} must be at least {}",
+                value,
+                source,
+                min
+            )));
+        }
+        Ok(value)
+    }
+
+    // A timeout in seconds, where 0 means no timeout
+    fn timeout(&self, name: &str) -> Result<Option<Duration>> {
+        let secs = self.required(name)?;
+        Ok(Some(Duration::from_secs(secs)).filter(|d| !d.is_zero()))
+    }
+
+    // One of `choices`
+    fn choice(&self, name: &str, choices: &[&'static str]) -> Result<&'static str> {
+        let (value, source) = self.get(name).expect("arg has a default value");
+        choices
+            .iter()
+            .find(|choice| **choice == value)
+            .copied()
+            .ok_or_else(|| {
+                YakvError::Any(anyhow!(
+                    "Invalid value '{}' from {}, expected one of {}",
+                    value,
+                    source,
+                    choices.join(", ")
+                ))
+            })
+    }
+}
+
+fn env_var(name: &str) -> String {
+    format!("MAKV_{}", name.to_uppercase().replace('-', "_"))
 }
 
 fn get_existing_engines(path: PathBuf) -> Result<HashSet<Engine>> {
@@ -146,8 +1239,8 @@ fn get_existing_engines(path: PathBuf) -> Result<HashSet<Engine>> {
         .filter_map(|e| {
             e.file_stem()
                 .and_then(OsStr::to_str)
-                .filter(|s| s.starts_with("engine"))
-                .map(|s| &s[7..11])
+                .and_then(|s| s.strip_prefix("engine_"))
+                .and_then(|s| s.strip_suffix("_data"))
                 .map(Engine::from_str)
         })
         .flatten()
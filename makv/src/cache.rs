@@ -0,0 +1,101 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// Hit and miss counters of a value cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheStats {
+    /// Reads answered from the cache
+    pub hits: u64,
+
+    /// Reads which went to the log files
+    pub misses: u64,
+
+    /// Number of cached values
+    pub entries: usize,
+
+    /// Bytes of keys and values in the cache
+    pub size: usize,
+}
+
+/// A least recently used cache of values, bounded by the bytes of its keys
+/// and values.
+pub(crate) struct ValueCache {
+    capacity: usize,
+    // value and last use of every key
+    entries: HashMap<String, (String, u64)>,
+    // keys by last use, oldest first
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+impl ValueCache {
+    /// Returns a cache of `capacity` bytes, where 0 disables caching.
+    pub fn new(capacity: usize) -> Self {
+        ValueCache {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<String> {
+        if self.capacity == 0 {
+            return None;
+        }
+        self.tick += 1;
+        match self.entries.get_mut(key) {
+            Some((value, last_use)) => {
+                let key = self.recency.remove(last_use).expect("key has a last use");
+                *last_use = self.tick;
+                self.recency.insert(self.tick, key);
+                self.stats.hits += 1;
+                Some(value.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Caches a value read from the logs, evicting the least recently used
+    /// values to make room.
+    pub fn insert(&mut self, key: String, value: String) {
+        let size = key.len() + value.len();
+        if size > self.capacity {
+            return;
+        }
+        self.remove(&key);
+        while self.stats.size + size > self.capacity {
+            let (_, oldest) = self.recency.pop_first().expect("cache is not empty");
+            self.remove(&oldest);
+        }
+        self.tick += 1;
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+        self.stats.size += size;
+        self.stats.entries += 1;
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        if let Some((value, last_use)) = self.entries.remove(key) {
+            self.recency.remove(&last_use);
+            self.stats.size -= key.len() + value.len();
+            self.stats.entries -= 1;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+        self.stats.size = 0;
+        self.stats.entries = 0;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+}
@@ -0,0 +1,291 @@
+use crate::trace::{self, SpanContext, SpanKind};
+use crate::{
+    tls, Command, Handshake, Payload, PayloadType, Response, Result, StoreStats, YakvError,
+    YakvMessage, DEFAULT_COMPRESSION_THRESHOLD,
+};
+use anyhow::anyhow;
+use rustls::ClientConfig;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time;
+use tokio_rustls::TlsConnector;
+
+/// Options used by `MakvClient` for timeouts and retries.
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// Timeout for connecting to the server.
+    pub connect_timeout: Duration,
+
+    /// Timeout for a single request/response round trip.
+    pub request_timeout: Duration,
+
+    /// How many times a request is retried after an I/O error or timeout,
+    /// see `MakvClient` for which requests are.
+    pub retries: u32,
+
+    /// Delay between two retries.
+    pub retry_backoff: Duration,
+
+    /// User name and token sent on every new connection, for servers with an ACL.
+    pub credentials: Option<(String, String)>,
+
+    /// Requests of at least this many bytes are LZ4 compressed if the server
+    /// supports it. `None` turns off compression in both directions.
+    pub compression_threshold: Option<usize>,
+
+    /// Connect with TLS, e.g. with a config from `tls::client_config`. The
+    /// server certificate is checked against the host of the address.
+    pub tls: Option<Arc<ClientConfig>>,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(5),
+            retries: 2,
+            retry_backoff: Duration::from_millis(100),
+            credentials: None,
+            compression_threshold: Some(DEFAULT_COMPRESSION_THRESHOLD),
+            tls: None,
+        }
+    }
+}
+
+/// Async client for makv-server.
+///
+/// The client keeps a single connection open and reuses it for every request.
+/// If the connection breaks or a request times out, the connection is dropped
+/// and the next request opens a fresh one.
+///
+/// Requests are retried on a fresh connection when the connection couldn't
+/// be opened, since nothing was sent yet. Once a request was sent, only `get`
+/// and `stats` are retried: a `set`, `remove` or `backup` may have been
+/// applied before the connection broke, so it fails instead and it's up to
+/// the caller whether repeating it is safe.
+///
+/// Every new connection starts with a handshake (see `Handshake`), so the
+/// client needs a server which speaks protocol version 1 or later.
+///
+/// Connections use TLS when `ClientOptions::tls` is set.
+///
+/// With tracing installed (see `crate::trace`), every request is a span
+/// whose context is sent to servers which support the `trace` feature.
+pub struct MakvClient {
+    addr: String,
+    options: ClientOptions,
+    conn: Option<Connection>,
+}
+
+// A TCP or TLS stream to the server
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + Sync> Stream for S {}
+
+// An open connection to the server
+struct Connection {
+    stream: Box<dyn Stream>,
+    // set if the server agreed on compressed frames
+    compression_threshold: Option<usize>,
+    // the server agreed on span contexts in frames
+    trace: bool,
+}
+
+impl MakvClient {
+    /// Connects to the server at `addr` with default options.
+    pub async fn connect<A: Into<String>>(addr: A) -> Result<Self> {
+        MakvClient::connect_with(addr, ClientOptions::default()).await
+    }
+
+    /// Connects to the server at `addr` with the given options.
+    pub async fn connect_with<A: Into<String>>(addr: A, options: ClientOptions) -> Result<Self> {
+        let mut client = MakvClient {
+            addr: addr.into(),
+            options,
+            conn: None,
+        };
+        client.conn = Some(client.open_connection().await?);
+        Ok(client)
+    }
+
+    /// Gets the string value for a given key.
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        let res = self.request(Command::get(key)).await?;
+        Ok(res.result)
+    }
+
+    /// Sets the value of a string key to a string.
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.request(Command::set(key, value)).await?;
+        Ok(())
+    }
+
+    /// Removes the given key.
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        self.request(Command::remove(key)).await?;
+        Ok(())
+    }
+
+    /// Backs up the store to `dest`, a new directory relative to the
+    /// `--backup-dir` of the server.
+    pub async fn backup(&mut self, dest: String) -> Result<()> {
+        self.request(Command::backup(dest)).await?;
+        Ok(())
+    }
+
+    /// Returns the sizes and counters of the server's store.
+    pub async fn stats(&mut self) -> Result<StoreStats> {
+        self.request(Command::Stats)
+            .await?
+            .stats
+            .map(|stats| *stats)
+            .ok_or_else(|| YakvError::Any(anyhow!("response has no statistics")))
+    }
+
+    async fn request(&mut self, cmd: Command) -> Result<Response> {
+        let mut span = trace::start_span("client.request", SpanKind::Client, None);
+        span.set_attribute("command", cmd.name());
+        let res = self.retry(&cmd, span.context()).await;
+        if let Err(e) = &res {
+            span.set_error(e);
+        }
+        res
+    }
+
+    async fn retry(&mut self, cmd: &Command, trace: Option<SpanContext>) -> Result<Response> {
+        let mut attempt = 0;
+        loop {
+            // whether the request may have reached the server
+            let (res, sent) = match self.conn {
+                Some(_) => (self.round_trip(cmd, trace).await, true),
+                None => match self.open_connection().await {
+                    Ok(conn) => {
+                        self.conn = Some(conn);
+                        continue;
+                    }
+                    Err(e) => (Err(e), false),
+                },
+            };
+            match res {
+                Ok(res) if res.is_error => {
+                    if res.closes_connection() {
+                        self.conn = None;
+                    }
+                    return Err(res.into_result().unwrap_err());
+                }
+                Ok(res) => return Ok(res),
+                Err(YakvError::Io(_))
+                    if attempt < self.options.retries && (!sent || idempotent(cmd)) =>
+                {
+                    // the connection is in an unknown state, start over
+                    self.conn = None;
+                    attempt += 1;
+                    time::sleep(self.options.retry_backoff).await;
+                }
+                Err(e) => {
+                    self.conn = None;
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    async fn round_trip(&mut self, cmd: &Command, trace: Option<SpanContext>) -> Result<Response> {
+        let conn = self.conn.as_mut().expect("client is connected");
+        let payload = Payload::Command(cmd.clone());
+        let (_, bytes) = match conn.compression_threshold {
+            Some(threshold) => YakvMessage::get_compressed_len_payload_bytes(payload, threshold)?,
+            None => YakvMessage::get_len_payload_bytes(payload)?,
+        };
+        let bytes = match trace.filter(|_| conn.trace) {
+            Some(context) => YakvMessage::traced(bytes, context),
+            None => bytes,
+        };
+        exchange(&mut conn.stream, &bytes, self.options.request_timeout).await
+    }
+
+    async fn open_connection(&self) -> Result<Connection> {
+        let mut stream = time::timeout(self.options.connect_timeout, self.open_stream())
+            .await
+            .map_err(|_| timed_out("connect timed out"))??;
+
+        let mut handshake = Handshake::default();
+        if self.options.compression_threshold.is_none() {
+            handshake.features.retain(|f| f != "lz4");
+        }
+        let res = self
+            .send_plain(&mut stream, Command::Handshake(handshake))
+            .await?;
+        let agreed = |feature| res.handshake.as_ref().is_some_and(|h| h.has(feature));
+        let compress = agreed("lz4");
+        if let Some((user, token)) = &self.options.credentials {
+            self.send_plain(
+                &mut stream,
+                Command::auth(user.to_owned(), token.to_owned()),
+            )
+            .await?;
+        }
+        Ok(Connection {
+            stream,
+            compression_threshold: self.options.compression_threshold.filter(|_| compress),
+            trace: agreed("trace"),
+        })
+    }
+
+    async fn open_stream(&self) -> Result<Box<dyn Stream>> {
+        let stream = TcpStream::connect(self.addr.as_str()).await?;
+        stream.set_nodelay(true)?;
+        match &self.options.tls {
+            Some(config) => {
+                let name = tls::server_name(&self.addr)?;
+                let stream = TlsConnector::from(config.clone())
+                    .connect(name, stream)
+                    .await?;
+                Ok(Box::new(stream))
+            }
+            None => Ok(Box::new(stream)),
+        }
+    }
+
+    // Sends an uncompressed command, turning an error response into an error
+    async fn send_plain(&self, stream: &mut Box<dyn Stream>, cmd: Command) -> Result<Response> {
+        let (_, bytes) = YakvMessage::get_len_payload_bytes(Payload::Command(cmd))?;
+        let res = exchange(stream, &bytes, self.options.request_timeout).await?;
+        if res.is_error {
+            return Err(res.into_result().unwrap_err());
+        }
+        Ok(res)
+    }
+}
+
+// Sends one encoded command and reads its response
+async fn exchange(
+    stream: &mut Box<dyn Stream>,
+    bytes: &[u8],
+    timeout: Duration,
+) -> Result<Response> {
+    let message = time::timeout(timeout, async {
+        stream.write_all(bytes).await?;
+        stream.flush().await?;
+        YakvMessage::new_async(stream, PayloadType::Response).await
+    })
+    .await
+    .map_err(|_| timed_out("request timed out"))??;
+
+    match message.payload {
+        Payload::Response(res) => Ok(res),
+        Payload::Command(_) => Err(YakvError::UnexpectedCommand),
+    }
+}
+
+// Whether repeating the command can't change the store or its answer
+fn idempotent(cmd: &Command) -> bool {
+    matches!(cmd, Command::Get { .. } | Command::Stats)
+}
+
+fn timed_out(msg: &str) -> YakvError {
+    YakvError::Io(io::Error::new(io::ErrorKind::TimedOut, msg))
+}
@@ -0,0 +1,66 @@
+use crate::{Result, YakvError};
+use anyhow::anyhow;
+use std::str::FromStr;
+
+/// Values smaller than this many bytes are not worth compressing.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Compression applied to log records and frames above a size threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Store and send bytes as they are
+    None,
+
+    /// LZ4 block format, prefixed with the uncompressed size
+    Lz4,
+}
+
+impl FromStr for Compression {
+    type Err = YakvError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            _ => Err(YakvError::Any(anyhow!("unknown compression '{}'", s))),
+        }
+    }
+}
+
+impl Compression {
+    /// Returns the compressed bytes, or None if they are not worth it.
+    ///
+    /// Inputs shorter than `threshold` and inputs which don't get smaller are
+    /// left uncompressed.
+    pub(crate) fn compress(self, bytes: &[u8], threshold: usize) -> Option<Vec<u8>> {
+        match self {
+            Compression::None => None,
+            Compression::Lz4 if bytes.len() < threshold => None,
+            Compression::Lz4 => {
+                Some(lz4_flex::compress_prepend_size(bytes)).filter(|c| c.len() < bytes.len())
+            }
+        }
+    }
+
+    /// Returns the decompressed bytes, refusing to produce more than `max_size`.
+    pub(crate) fn decompress(self, bytes: &[u8], max_size: usize) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(bytes.to_vec()),
+            Compression::Lz4 => {
+                // check the size prefix before allocating for it
+                let size = bytes
+                    .get(..4)
+                    .map(|prefix| u32::from_le_bytes(prefix.try_into().unwrap()) as usize)
+                    .ok_or_else(|| YakvError::Any(anyhow!("compressed data is truncated")))?;
+                if size > max_size {
+                    return Err(YakvError::Any(anyhow!(
+                        "compressed data expands to {} bytes, more than the maximum of {}",
+                        size,
+                        max_size
+                    )));
+                }
+                lz4_flex::decompress_size_prepended(bytes).map_err(|e| YakvError::Any(anyhow!(e)))
+            }
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use crate::{Result, YakvError};
+use anyhow::anyhow;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+
+// Length of the id stored in front of every encrypted record
+const KEY_ID_LEN: usize = 8;
+
+/// A 256-bit key for encrypting log records with XChaCha20-Poly1305.
+///
+/// Every encrypted record starts with the id of its key, so a store can hold
+/// records encrypted with different keys while a key is being rotated.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    bytes: [u8; KEY_LEN],
+    cipher: XChaCha20Poly1305,
+    id: [u8; KEY_ID_LEN],
+}
+
+impl EncryptionKey {
+    /// Returns a new random key.
+    pub fn generate() -> Self {
+        EncryptionKey::new(&XChaCha20Poly1305::generate_key(&mut OsRng))
+    }
+
+    /// Parses a key from 64 hex characters.
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let hex = hex.trim();
+        let bytes: Option<Vec<u8>> = (0..hex.len())
+            .step_by(2)
+            .map(|i| {
+                hex.get(i..i + 2)
+                    .and_then(|b| u8::from_str_radix(b, 16).ok())
+            })
+            .collect();
+        match bytes {
+            Some(bytes) if bytes.len() == KEY_LEN => Ok(EncryptionKey::new(&bytes)),
+            _ => Err(YakvError::Any(anyhow!(
+                "encryption key must be {} hex characters",
+                KEY_LEN * 2
+            ))),
+        }
+    }
+
+    /// Reads a key from a file holding either 32 raw bytes or 64 hex characters.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let bytes = fs::read(path)?;
+        if bytes.len() == KEY_LEN {
+            return Ok(EncryptionKey::new(&bytes));
+        }
+        let hex = String::from_utf8(bytes)
+            .map_err(|_| YakvError::Any(anyhow!("{}: invalid key file", path.display())))?;
+        EncryptionKey::from_hex(&hex)
+            .map_err(|e| YakvError::Any(anyhow!("{}: {}", path.display(), e)))
+    }
+
+    /// Reads a hex key from an environment variable, if it is set.
+    pub fn from_env(var: &str) -> Result<Option<Self>> {
+        match env::var(var) {
+            Ok(hex) => EncryptionKey::from_hex(&hex)
+                .map(Some)
+                .map_err(|e| YakvError::Any(anyhow!("{}: {}", var, e))),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Returns the key as 64 hex characters.
+    pub fn to_hex(&self) -> String {
+        to_hex(&self.bytes)
+    }
+
+    fn new(bytes: &[u8]) -> Self {
+        let cipher = XChaCha20Poly1305::new_from_slice(bytes).expect("key has the right length");
+        // the tag of an empty message identifies the key without revealing it
+        let tag = cipher
+            .encrypt(
+                &XNonce::default(),
+                Payload {
+                    msg: &[],
+                    aad: b"makv key id",
+                },
+            )
+            .expect("encryption can't fail");
+        let mut id = [0; KEY_ID_LEN];
+        id.copy_from_slice(&tag[..KEY_ID_LEN]);
+        let mut key = [0; KEY_LEN];
+        key.copy_from_slice(bytes);
+        EncryptionKey {
+            bytes: key,
+            cipher,
+            id,
+        }
+    }
+
+    /// Returns `key id | nonce | ciphertext`, authenticating `aad` too.
+    pub(crate) fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .expect("encryption can't fail");
+        let mut body = Vec::with_capacity(KEY_ID_LEN + NONCE_LEN + ciphertext.len());
+        body.extend_from_slice(&self.id);
+        body.extend_from_slice(&nonce);
+        body.extend_from_slice(&ciphertext);
+        body
+    }
+
+    /// Decrypts the output of `encrypt` with whichever of `keys` encrypted it.
+    pub(crate) fn decrypt<'a, I>(keys: I, body: &[u8], aad: &[u8]) -> Result<Vec<u8>>
+    where
+        I: IntoIterator<Item = &'a EncryptionKey>,
+    {
+        if body.len() < KEY_ID_LEN + NONCE_LEN {
+            return Err(YakvError::Any(anyhow!("encrypted record is truncated")));
+        }
+        let (id, rest) = body.split_at(KEY_ID_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let key = keys.into_iter().find(|key| key.id == id).ok_or_else(|| {
+            YakvError::Any(anyhow!(
+                "record is encrypted with an unknown key (id {})",
+                to_hex(id)
+            ))
+        })?;
+        key.cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| YakvError::Any(anyhow!("encrypted record is corrupted")))
+    }
+}
+
+// Never prints the key itself
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionKey")
+            .field("id", &to_hex(&self.id))
+            .finish()
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
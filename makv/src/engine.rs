@@ -1,11 +1,19 @@
-use crate::Result;
+use crate::{Result, StoreStats, YakvError};
+use anyhow::anyhow;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
 use std::str::FromStr;
 
+// File in the working directory naming the engine whose data is active
+const ENGINE_MARKER: &str = "ENGINE";
+
 #[derive(Debug, PartialEq, Eq, Hash)]
 #[allow(missing_docs)]
 pub enum Engine {
     Yakv,
     Sled,
+    Lsm,
 }
 
 // NOTE: look into arg_enum!() macro from clap as an alternative
@@ -16,11 +24,55 @@ impl FromStr for Engine {
         match s {
             "yakv" => Ok(Engine::Yakv),
             "sled" => Ok(Engine::Sled),
+            "lsm" => Ok(Engine::Lsm),
             _ => Err(()),
         }
     }
 }
 
+impl Engine {
+    /// Returns the name of the engine, as accepted by `--engine`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Engine::Yakv => "yakv",
+            Engine::Sled => "sled",
+            Engine::Lsm => "lsm",
+        }
+    }
+
+    /// Returns the engine named by the marker file in `dir`, if there is one.
+    ///
+    /// The marker is written by migrations, after which the data of other
+    /// engines in `dir` is no longer used.
+    pub fn active(dir: &Path) -> Result<Option<Engine>> {
+        let name = match fs::read_to_string(dir.join(ENGINE_MARKER)) {
+            Ok(name) => name,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Engine::from_str(name.trim()).map(Some).map_err(|_| {
+            YakvError::Any(anyhow!(
+                "{} names an unknown engine: {}",
+                ENGINE_MARKER,
+                name
+            ))
+        })
+    }
+
+    /// Makes this engine the active one of `dir`, replacing the marker file
+    /// atomically.
+    pub fn set_active(&self, dir: &Path) -> Result<()> {
+        // write to a temporary file first so a crash never loses the marker
+        let tmp_path = dir.join(format!("{}.tmp", ENGINE_MARKER));
+        let mut file = File::create(&tmp_path)?;
+        writeln!(file, "{}", self.name())?;
+        file.sync_all()?;
+        fs::rename(tmp_path, dir.join(ENGINE_MARKER))?;
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+}
+
 /// Define MakvEngine trait
 pub trait MakvEngine: Clone + Send + 'static {
     /// Sets the value of s string key to a string.
@@ -31,46 +83,31 @@ pub trait MakvEngine: Clone + Send + 'static {
 
     /// Removes the given key.
     fn remove(&self, key: String) -> Result<()>;
-}
 
-// /// YakvSledEngine implements YakvEngine trait
-// pub struct YakvSledEngine {
-//     db: Db,
-// }
-//
-// impl YakvSledEngine {
-//     /// Return Sled engine for the given path
-//     pub fn open(path: &Path) -> Result<Self> {
-//         let mut path = PathBuf::from(path);
-//         path.push("engine_sled_data");
-//         let db = sled::open(path)?;
-//         Ok(YakvSledEngine { db })
-//     }
-// }
-//
-// impl YakvEngine for YakvSledEngine {
-//     fn set(&mut self, key: String, value: String) -> Result<()> {
-//         self.db.insert(key.as_bytes(), value.as_bytes())?;
-//         self.db.flush()?;
-//         Ok(())
-//     }
-//
-//     fn get(&mut self, key: String) -> Result<Option<String>> {
-//         let val = self
-//             .db
-//             .get(key.as_bytes())?
-//             .map(|ivec| String::from_utf8(Vec::from(&*ivec)))
-//             .and_then(|v| v.ok());
-//         Ok(val)
-//     }
-//
-//     fn remove(&mut self, key: String) -> Result<()> {
-//         let result = self.db.remove(key.as_bytes())?;
-//         self.db.flush()?;
-//         if result.is_none() {
-//             Err(YakvError::NotFoundError(key))
-//         } else {
-//             Ok(())
-//         }
-//     }
-// }
+    /// Returns up to `limit` keys in ascending order, starting from `start` (inclusive).
+    fn scan(&self, start: String, limit: usize) -> Result<Vec<String>>;
+
+    /// Sets many keys at once. Engines which can write them together, e.g.
+    /// with one flush, override this; by default they are set one by one.
+    fn set_batch(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        pairs
+            .into_iter()
+            .try_for_each(|(key, value)| self.set(key, value))
+    }
+
+    /// Writes a consistent copy of the store to the directory `dest`, which
+    /// can then be opened as a store of its own.
+    fn backup(&self, dest: &Path) -> Result<()> {
+        Err(YakvError::Any(anyhow!(
+            "backups to {} are not supported by this engine",
+            dest.display()
+        )))
+    }
+
+    /// Returns the sizes and counters of the store.
+    fn stats(&self) -> Result<StoreStats> {
+        Err(YakvError::Any(anyhow!(
+            "statistics are not supported by this engine"
+        )))
+    }
+}
@@ -7,15 +7,19 @@ use thiserror::Error;
 #[error("...")]
 pub enum YakvError {
     /// Any Error
+    #[error("{0}")]
     Any(#[from] anyhow::Error),
 
     /// IO Error
+    #[error("{0}")]
     Io(#[from] io::Error),
 
     /// Serde Error
+    #[error("{0}")]
     Serde(#[from] serde_json::Error),
 
     /// Sled Error
+    #[error("{0}")]
     Sled(#[from] sled::Error),
 
     /// Unexpected Command Error
@@ -25,6 +29,50 @@ pub enum YakvError {
     /// Not found error
     #[error("Key not found: {0}")]
     NotFoundError(String),
+
+    /// The connection is not authenticated or the credentials are wrong
+    #[error("Authentication failed: {0}")]
+    Unauthenticated(String),
+
+    /// The authenticated user may not access the key
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+
+    /// The server does not speak the client's protocol version
+    #[error("Unsupported protocol version: {0}")]
+    UnsupportedVersion(String),
+
+    /// A frame is longer than the reader accepts
+    #[error("Frame of {length} bytes is larger than the maximum of {max} bytes")]
+    FrameTooLarge {
+        /// length prefix of the frame
+        length: u32,
+        /// maximum frame size
+        max: u32,
+    },
+
+    /// Error returned by the server in a `Response`
+    #[error("{0}")]
+    ServerError(String),
+}
+
+impl YakvError {
+    /// Returns the name of the kind of error, e.g. for metrics and logs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            YakvError::Any(_) => "other",
+            YakvError::Io(_) => "io",
+            YakvError::Serde(_) => "serde",
+            YakvError::Sled(_) => "sled",
+            YakvError::UnexpectedCommand => "unexpected_command",
+            YakvError::NotFoundError(_) => "not_found",
+            YakvError::Unauthenticated(_) => "unauthenticated",
+            YakvError::PermissionDenied(_) => "permission_denied",
+            YakvError::UnsupportedVersion(_) => "unsupported_version",
+            YakvError::FrameTooLarge { .. } => "frame_too_large",
+            YakvError::ServerError(_) => "server_error",
+        }
+    }
 }
 
 /// Result handles Result<T, YakvError>
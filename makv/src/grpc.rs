@@ -0,0 +1,169 @@
+use crate::metrics::ServerMetrics;
+use crate::{MakvEngine, Result, WatchEvent, WatchedEngine, YakvError};
+use anyhow::anyhow;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status};
+
+use proto::key_value_server::{KeyValue, KeyValueServer};
+use proto::{
+    watch_event, GetRequest, GetResponse, KeyValuePair, RemoveRequest, RemoveResponse, ScanRequest,
+    ScanResponse, SetRequest, SetResponse, WatchRequest,
+};
+
+const DEFAULT_SCAN_LIMIT: usize = 100;
+const MAX_SCAN_LIMIT: usize = 1000;
+
+/// Types and client/server stubs generated from `proto/makv.proto`.
+#[allow(missing_docs)]
+pub mod proto {
+    tonic::include_proto!("makv");
+}
+
+/// gRPC front-end implementing the `KeyValue` service from `proto/makv.proto`.
+#[derive(Clone)]
+pub struct GrpcServer<E: MakvEngine> {
+    store: WatchedEngine<E>,
+    metrics: Arc<ServerMetrics>,
+}
+
+impl<E: MakvEngine + Sync> GrpcServer<E> {
+    /// Returns a gRPC front-end backed by the given store, which records its
+    /// requests in `metrics`.
+    pub fn new(store: WatchedEngine<E>, metrics: Arc<ServerMetrics>) -> Self {
+        GrpcServer { store, metrics }
+    }
+
+    /// Listens on `addr` and serves gRPC requests on a new tokio runtime.
+    ///
+    /// Engine calls are blocking, so they run on the runtime's blocking threads.
+    pub fn start(self, addr: SocketAddr) -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime
+            .block_on(
+                tonic::transport::Server::builder()
+                    .add_service(KeyValueServer::new(self))
+                    .serve(addr),
+            )
+            .map_err(|e| YakvError::Any(anyhow!(e)))
+    }
+
+    // Runs the request named `operation` in the metrics
+    async fn blocking<T, F>(&self, operation: &'static str, f: F) -> std::result::Result<T, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(WatchedEngine<E>) -> Result<T> + Send + 'static,
+    {
+        let store = self.store.clone();
+        let start = Instant::now();
+        let res = tokio::task::spawn_blocking(move || f(store))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        self.metrics.observe_request(operation, start.elapsed());
+        res.map_err(|e| {
+            self.metrics.count_error(&e);
+            match e {
+                YakvError::NotFoundError(_) => Status::not_found(e.to_string()),
+                e => Status::internal(e.to_string()),
+            }
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl<E: MakvEngine + Sync> KeyValue for GrpcServer<E> {
+    async fn get(
+        &self,
+        request: Request<GetRequest>,
+    ) -> std::result::Result<Response<GetResponse>, Status> {
+        let key = request.into_inner().key;
+        let value = self.blocking("get", move |store| store.get(key)).await?;
+        Ok(Response::new(GetResponse { value }))
+    }
+
+    async fn set(
+        &self,
+        request: Request<SetRequest>,
+    ) -> std::result::Result<Response<SetResponse>, Status> {
+        let SetRequest { key, value } = request.into_inner();
+        self.blocking("set", move |store| store.set(key, value))
+            .await?;
+        Ok(Response::new(SetResponse {}))
+    }
+
+    async fn remove(
+        &self,
+        request: Request<RemoveRequest>,
+    ) -> std::result::Result<Response<RemoveResponse>, Status> {
+        let key = request.into_inner().key;
+        self.blocking("remove", move |store| store.remove(key))
+            .await?;
+        Ok(Response::new(RemoveResponse {}))
+    }
+
+    async fn scan(
+        &self,
+        request: Request<ScanRequest>,
+    ) -> std::result::Result<Response<ScanResponse>, Status> {
+        let ScanRequest { start, end, limit } = request.into_inner();
+        let limit = match limit as usize {
+            0 => DEFAULT_SCAN_LIMIT,
+            n => n.min(MAX_SCAN_LIMIT),
+        };
+        let pairs = self
+            .blocking("scan", move |store| {
+                let mut pairs = Vec::new();
+                for key in store.scan(start, limit)? {
+                    if !end.is_empty() && key >= end {
+                        break;
+                    }
+                    // the key may have been removed since it was listed
+                    if let Some(value) = store.get(key.to_owned())? {
+                        pairs.push(KeyValuePair { key, value });
+                    }
+                }
+                Ok(pairs)
+            })
+            .await?;
+        Ok(Response::new(ScanResponse { pairs }))
+    }
+
+    type WatchStream =
+        Pin<Box<dyn Stream<Item = std::result::Result<proto::WatchEvent, Status>> + Send>>;
+
+    // The events are awaited by the stream itself, so watches hold no thread
+    // and end as soon as their client goes away
+    async fn watch(
+        &self,
+        request: Request<WatchRequest>,
+    ) -> std::result::Result<Response<Self::WatchStream>, Status> {
+        let events = self.store.subscribe(request.into_inner().prefix);
+        // the engine only closes the subscription of watchers which fell
+        // behind
+        let stream = ReceiverStream::new(events)
+            .map(|event| Ok(to_proto(event)))
+            .chain(tokio_stream::once(Err(Status::resource_exhausted(
+                "watcher fell too far behind",
+            ))));
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+fn to_proto(event: WatchEvent) -> proto::WatchEvent {
+    match event {
+        WatchEvent::Set { key, value } => proto::WatchEvent {
+            kind: watch_event::Kind::Set as i32,
+            key,
+            value,
+        },
+        WatchEvent::Remove { key } => proto::WatchEvent {
+            kind: watch_event::Kind::Remove as i32,
+            key,
+            value: String::new(),
+        },
+    }
+}
@@ -0,0 +1,222 @@
+//! Hint files let `KvStore::open` build its index without reading values.
+//!
+//! Every sealed log file `<id>.log` gets a `<id>.hint` listing the position of
+//! each of its records:
+//!
+//! | magic: 4 bytes | flags: u8 | hints | log length: u64 BE | crc32: u32 BE |
+//!
+//! Each hint is framed as `| len: u32 BE | body |`, where the body is the JSON
+//! `Hint`, encrypted like log records if the store has a key. Hints are written
+//! and read one at a time, so a log with many keys never needs all of its
+//! hints in memory. The checksum covers everything after the magic. A hint
+//! file which is missing, fails its checksum or was written for a log of
+//! another length is ignored and the log is read in full instead.
+
+use crate::{EncryptionKey, Result, StoreOptions, YakvError};
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const HINT_MAGIC: &[u8; 4] = b"YKH\x02";
+// the magic and the flags
+const HINT_HEADER_LEN: u64 = 5;
+// the log length and the checksum
+const HINT_TRAILER_LEN: u64 = 12;
+const HINT_ENCRYPTED: u8 = 0x02;
+
+/// Position of one record of a log file.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Hint {
+    pub key: String,
+    pub pos: u64,
+    pub len: u64,
+    // the record removes the key
+    pub removed: bool,
+}
+
+pub(crate) fn hint_path(path: &Path, id: u64) -> PathBuf {
+    path.join(format!("{}.hint", id))
+}
+
+/// Returns the hints of log `id`, or None if they can't be trusted.
+///
+/// The checksum is verified before any hint is returned.
+pub(crate) fn read_hints<'a>(
+    path: &Path,
+    id: u64,
+    log_len: u64,
+    options: &'a StoreOptions,
+) -> Option<HintReader<'a>> {
+    let mut file = File::open(hint_path(path, id)).ok()?;
+    let len = file.metadata().ok()?.len();
+    if len < HINT_HEADER_LEN + HINT_TRAILER_LEN {
+        return None;
+    }
+    let mut header = [0; HINT_HEADER_LEN as usize];
+    file.read_exact(&mut header).ok()?;
+    if &header[..4] != HINT_MAGIC {
+        return None;
+    }
+    let end = len - HINT_TRAILER_LEN;
+    let mut trailer = [0; HINT_TRAILER_LEN as usize];
+    file.seek(SeekFrom::Start(end)).ok()?;
+    file.read_exact(&mut trailer).ok()?;
+    let hinted_len = u64::from_be_bytes(trailer[..8].try_into().unwrap());
+    let crc = u32::from_be_bytes(trailer[8..].try_into().unwrap());
+    if hinted_len != log_len {
+        return None;
+    }
+
+    file.seek(SeekFrom::Start(4)).ok()?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut reader = BufReader::new(file);
+    let mut checked = (&mut reader).take(len - 4 - 4);
+    let mut buf = [0; 8192];
+    loop {
+        match checked.read(&mut buf).ok()? {
+            0 => break,
+            n => hasher.update(&buf[..n]),
+        }
+    }
+    if hasher.finalize() != crc {
+        return None;
+    }
+
+    reader.seek(SeekFrom::Start(HINT_HEADER_LEN)).ok()?;
+    Some(HintReader {
+        reader,
+        id,
+        flags: header[4],
+        pos: HINT_HEADER_LEN,
+        end,
+        options,
+    })
+}
+
+/// Reads the hints of a hint file whose checksum was verified.
+pub(crate) struct HintReader<'a> {
+    reader: BufReader<File>,
+    id: u64,
+    flags: u8,
+    pos: u64,
+    end: u64,
+    options: &'a StoreOptions,
+}
+
+impl HintReader<'_> {
+    fn read_hint(&mut self) -> Result<Hint> {
+        let mut len = [0; 4];
+        self.reader.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as u64;
+        if len > self.end - self.pos - 4 {
+            return Err(YakvError::Any(anyhow!(
+                "hint runs past the end of its file"
+            )));
+        }
+        let mut body = vec![0; len as usize];
+        self.reader.read_exact(&mut body)?;
+        if self.flags & HINT_ENCRYPTED != 0 {
+            body = EncryptionKey::decrypt(
+                self.options.decryption_keys(),
+                &body,
+                &hint_aad(self.id, self.pos),
+            )?;
+        }
+        self.pos += 4 + len;
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+impl Iterator for HintReader<'_> {
+    type Item = Result<Hint>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.end {
+            return None;
+        }
+        let hint = self.read_hint();
+        if hint.is_err() {
+            self.pos = self.end;
+        }
+        Some(hint)
+    }
+}
+
+/// Writes the hints of log `id` to a temporary file, which replaces any
+/// existing hint file once finished.
+pub(crate) struct HintWriter {
+    writer: BufWriter<File>,
+    hasher: crc32fast::Hasher,
+    path: PathBuf,
+    id: u64,
+    pos: u64,
+    key: Option<EncryptionKey>,
+}
+
+impl HintWriter {
+    pub fn create(path: &Path, id: u64, options: &StoreOptions) -> Result<Self> {
+        let flags = if options.encryption_key.is_some() {
+            HINT_ENCRYPTED
+        } else {
+            0
+        };
+        // write to a temporary file first so a crash never leaves half a hint file
+        let mut writer = BufWriter::new(File::create(tmp_path(path, id))?);
+        writer.write_all(HINT_MAGIC)?;
+        writer.write_all(&[flags])?;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&[flags]);
+        Ok(HintWriter {
+            writer,
+            hasher,
+            path: path.to_owned(),
+            id,
+            pos: HINT_HEADER_LEN,
+            key: options.encryption_key.clone(),
+        })
+    }
+
+    pub fn write(&mut self, hint: &Hint) -> Result<()> {
+        let mut body = serde_json::to_vec(hint)?;
+        if let Some(key) = &self.key {
+            body = key.encrypt(&body, &hint_aad(self.id, self.pos));
+        }
+        let len = (body.len() as u32).to_be_bytes();
+        self.writer.write_all(&len)?;
+        self.writer.write_all(&body)?;
+        self.hasher.update(&len);
+        self.hasher.update(&body);
+        self.pos += 4 + body.len() as u64;
+        Ok(())
+    }
+
+    /// Ends the hints of a log of `log_len` bytes and moves them in place.
+    pub fn finish(mut self, log_len: u64) -> Result<()> {
+        let log_len = log_len.to_be_bytes();
+        self.hasher.update(&log_len);
+        self.writer.write_all(&log_len)?;
+        self.writer
+            .write_all(&self.hasher.finalize().to_be_bytes())?;
+        self.writer.flush()?;
+        drop(self.writer);
+        fs::rename(
+            tmp_path(&self.path, self.id),
+            hint_path(&self.path, self.id),
+        )?;
+        Ok(())
+    }
+}
+
+fn tmp_path(path: &Path, id: u64) -> PathBuf {
+    path.join(format!("{}.hint.tmp", id))
+}
+
+// Binds an encrypted hint to its hint file and position
+fn hint_aad(id: u64, pos: u64) -> Vec<u8> {
+    let mut aad = HINT_MAGIC.to_vec();
+    aad.extend_from_slice(&id.to_be_bytes());
+    aad.extend_from_slice(&pos.to_be_bytes());
+    aad
+}
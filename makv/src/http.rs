@@ -0,0 +1,200 @@
+use crate::metrics::ServerMetrics;
+use crate::{MakvEngine, Result, SharedQueueThreadPool, ThreadPool, YakvError};
+use anyhow::anyhow;
+use serde_json::json;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tiny_http::{Header, Method, Request, Server};
+
+const DEFAULT_LIST_LIMIT: usize = 100;
+const MAX_LIST_LIMIT: usize = 1000;
+
+type HttpResponse = tiny_http::Response<Cursor<Vec<u8>>>;
+
+/// HTTP/JSON gateway for a `MakvEngine`.
+///
+/// Endpoints:
+///
+/// - `GET /keys/{key}` returns the value as the response body, or 404.
+/// - `PUT /keys/{key}` sets the key to the request body.
+/// - `DELETE /keys/{key}` removes the key, or returns 404.
+/// - `GET /keys?start=a&end=b&limit=n` lists key/value pairs in key order as
+///   JSON, from `start` (inclusive) to `end` (exclusive).
+/// - `GET /health` returns 200 while the server is up.
+/// - `GET /metrics` returns the metrics of the server and the store in
+///   Prometheus text format, see `ServerMetrics`.
+///
+/// Keys in the path and query are percent-decoded.
+#[derive(Clone)]
+pub struct HttpGateway<E: MakvEngine> {
+    store: E,
+    metrics: Arc<ServerMetrics>,
+}
+
+impl<E: MakvEngine> HttpGateway<E> {
+    /// Returns an HTTP gateway backed by the given store, which records its
+    /// requests in `metrics`.
+    pub fn new(store: E, metrics: Arc<ServerMetrics>) -> Self {
+        HttpGateway { store, metrics }
+    }
+
+    /// Listens on `addr` and serves HTTP requests using a pool of `threads` threads.
+    pub fn start(self, addr: SocketAddr, threads: u32) -> Result<()> {
+        let server = Server::http(addr).map_err(|e| YakvError::Any(anyhow!(e)))?;
+        let pool = SharedQueueThreadPool::new(threads)?;
+        for request in server.incoming_requests() {
+            let gateway = self.clone();
+            pool.spawn(move || gateway.serve(request));
+        }
+        Ok(())
+    }
+
+    fn serve(&self, mut request: Request) {
+        let operation = operation(request.method(), request.url());
+        let start = Instant::now();
+        let response = self.handle(&mut request).unwrap_or_else(|e| {
+            self.metrics.count_error(&e);
+            text(500, e.to_string())
+        });
+        if let Some(operation) = operation {
+            self.metrics.observe_request(operation, start.elapsed());
+        }
+        // the client went away, nothing left to do
+        let _ = request.respond(response);
+    }
+
+    fn handle(&self, request: &mut Request) -> Result<HttpResponse> {
+        let url = request.url().to_owned();
+        let (path, query) = match url.find('?') {
+            Some(i) => (&url[..i], &url[i + 1..]),
+            None => (url.as_str(), ""),
+        };
+
+        let response = match (request.method(), path) {
+            (Method::Get, "/health") => text(200, "OK"),
+            (Method::Get, "/metrics") => {
+                let body = self.metrics.render(self.store.stats().ok().as_ref());
+                tiny_http::Response::from_string(body)
+                    .with_header(header("Content-Type", "text/plain; version=0.0.4"))
+            }
+            (Method::Get, "/keys") | (Method::Get, "/keys/") => self.list(query)?,
+            (method, path) if path.starts_with("/keys/") => {
+                let key = match percent_decode(&path["/keys/".len()..]) {
+                    Some(key) => key,
+                    None => return Ok(text(400, "Invalid key encoding")),
+                };
+                match method {
+                    Method::Get => match self.store.get(key)? {
+                        Some(value) => text(200, value),
+                        None => text(404, "Key not found"),
+                    },
+                    Method::Put => {
+                        let mut value = String::new();
+                        if request.as_reader().read_to_string(&mut value).is_err() {
+                            return Ok(text(400, "Value must be valid UTF-8"));
+                        }
+                        self.store.set(key, value)?;
+                        empty(204)
+                    }
+                    Method::Delete => match self.store.remove(key) {
+                        Ok(()) => empty(204),
+                        Err(YakvError::NotFoundError(_)) => text(404, "Key not found"),
+                        Err(e) => return Err(e),
+                    },
+                    _ => text(405, "Method not allowed"),
+                }
+            }
+            _ => text(404, "Not found"),
+        };
+        Ok(response)
+    }
+
+    fn list(&self, query: &str) -> Result<HttpResponse> {
+        let mut start = String::new();
+        let mut end = None;
+        let mut limit = DEFAULT_LIST_LIMIT;
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            let (name, value) = match pair.find('=') {
+                Some(i) => (&pair[..i], &pair[i + 1..]),
+                None => (pair, ""),
+            };
+            let value = match percent_decode(value) {
+                Some(value) => value,
+                None => return Ok(text(400, "Invalid query encoding")),
+            };
+            match name {
+                "start" => start = value,
+                "end" => end = Some(value),
+                "limit" => match value.parse::<usize>() {
+                    Ok(n) if n >= 1 => limit = n.min(MAX_LIST_LIMIT),
+                    _ => return Ok(text(400, "Invalid limit")),
+                },
+                _ => return Ok(text(400, format!("Unknown parameter: {}", name))),
+            }
+        }
+
+        let mut items = Vec::new();
+        for key in self.store.scan(start, limit)? {
+            if end.as_ref().is_some_and(|end| &key >= end) {
+                break;
+            }
+            // the key may have been removed since it was listed
+            if let Some(value) = self.store.get(key.to_owned())? {
+                items.push(json!({ "key": key, "value": value }));
+            }
+        }
+        Ok(json_response(200, &json!(items)))
+    }
+}
+
+// Names the requests which are counted in the metrics like the commands of
+// the native protocol
+fn operation(method: &Method, url: &str) -> Option<&'static str> {
+    let path = url.split('?').next().unwrap_or(url);
+    match (method, path) {
+        (Method::Get, "/keys") | (Method::Get, "/keys/") => Some("scan"),
+        (Method::Get, path) if path.starts_with("/keys/") => Some("get"),
+        (Method::Put, path) if path.starts_with("/keys/") => Some("set"),
+        (Method::Delete, path) if path.starts_with("/keys/") => Some("remove"),
+        _ => None,
+    }
+}
+
+fn text<S: Into<String>>(status: u16, body: S) -> HttpResponse {
+    tiny_http::Response::from_string(body)
+        .with_status_code(status)
+        .with_header(header("Content-Type", "text/plain; charset=utf-8"))
+}
+
+fn json_response(status: u16, body: &serde_json::Value) -> HttpResponse {
+    tiny_http::Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+}
+
+fn empty(status: u16) -> HttpResponse {
+    tiny_http::Response::from_data(Vec::new()).with_status_code(status)
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid header")
+}
+
+// Decodes `%XX` escapes, returns `None` for invalid input.
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        match b {
+            b'%' => {
+                let hi = (iter.next()? as char).to_digit(16)?;
+                let lo = (iter.next()? as char).to_digit(16)?;
+                bytes.push((hi * 16 + lo) as u8);
+            }
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
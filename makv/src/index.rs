@@ -0,0 +1,207 @@
+//! The index from keys to the position of their latest record.
+//!
+//! In memory mode the index is a `BTreeMap`, so every key and its position
+//! must fit in memory. In disk mode it is a sled tree under
+//! `engine_yakv_data/index`, which pages its nodes to disk and only keeps a
+//! bounded cache of them in memory. The disk index is rebuilt from the hint
+//! files every time the store is opened, so it is never a source of truth.
+//! Hints are read and written one at a time, so memory stays bounded while
+//! opening and compacting too, but opening takes time in proportion to the
+//! number of records in sealed logs.
+
+use crate::{Result, YakvError};
+use anyhow::anyhow;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use std::str::FromStr;
+
+// Memory sled may use for caching index pages
+const DISK_INDEX_CACHE_CAPACITY: u64 = 64 * 1024 * 1024;
+
+// Length of an encoded `CommandPos`: id, pos and len as u64 BE
+const COMMAND_POS_LEN: usize = 24;
+
+/// Where `KvStore` keeps the index of its keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexMode {
+    /// A `BTreeMap` holding every key in memory
+    Memory,
+
+    /// A B+tree paged to disk, for stores with more keys than fit in memory
+    Disk,
+}
+
+impl FromStr for IndexMode {
+    type Err = YakvError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "memory" => Ok(IndexMode::Memory),
+            "disk" => Ok(IndexMode::Disk),
+            _ => Err(YakvError::Any(anyhow!("unknown index mode '{}'", s))),
+        }
+    }
+}
+
+/// Position for Command in log file
+///
+/// Stores log file id, offset, and length
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CommandPos {
+    pub id: u64,
+    pub pos: u64,
+    pub len: u64,
+}
+
+impl From<(u64, Range<u64>)> for CommandPos {
+    fn from((id, range): (u64, Range<u64>)) -> Self {
+        CommandPos {
+            id,
+            pos: range.start,
+            len: range.end - range.start,
+        }
+    }
+}
+
+impl CommandPos {
+    fn to_bytes(self) -> [u8; COMMAND_POS_LEN] {
+        let mut bytes = [0; COMMAND_POS_LEN];
+        bytes[..8].copy_from_slice(&self.id.to_be_bytes());
+        bytes[8..16].copy_from_slice(&self.pos.to_be_bytes());
+        bytes[16..].copy_from_slice(&self.len.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != COMMAND_POS_LEN {
+            return Err(YakvError::Any(anyhow!("corrupted index entry")));
+        }
+        let u64_at = |i: usize| u64::from_be_bytes(bytes[i..i + 8].try_into().unwrap());
+        Ok(CommandPos {
+            id: u64_at(0),
+            pos: u64_at(8),
+            len: u64_at(16),
+        })
+    }
+}
+
+pub(crate) enum Index {
+    Memory(BTreeMap<String, CommandPos>),
+    Disk(sled::Db),
+}
+
+impl Index {
+    /// Opens an empty index for the store in `path`.
+    pub fn open(mode: IndexMode, path: &Path) -> Result<Self> {
+        match mode {
+            IndexMode::Memory => Ok(Index::Memory(BTreeMap::new())),
+            IndexMode::Disk => {
+                let path = path.join("index");
+                // left over if the last process didn't drop the index
+                match fs::remove_dir_all(&path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+                let db = sled::Config::new()
+                    .path(path)
+                    .temporary(true)
+                    .cache_capacity(DISK_INDEX_CACHE_CAPACITY)
+                    .open()?;
+                Ok(Index::Disk(db))
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Index::Memory(map) => map.len(),
+            Index::Disk(db) => db.len(),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<CommandPos>> {
+        match self {
+            Index::Memory(map) => Ok(map.get(key).copied()),
+            Index::Disk(db) => db
+                .get(key)?
+                .map(|bytes| CommandPos::from_bytes(&bytes))
+                .transpose(),
+        }
+    }
+
+    pub fn contains_key(&self, key: &str) -> Result<bool> {
+        match self {
+            Index::Memory(map) => Ok(map.contains_key(key)),
+            Index::Disk(db) => Ok(db.contains_key(key)?),
+        }
+    }
+
+    /// Returns the previous position of `key`.
+    pub fn insert(&mut self, key: String, cmd_pos: CommandPos) -> Result<Option<CommandPos>> {
+        match self {
+            Index::Memory(map) => Ok(map.insert(key, cmd_pos)),
+            Index::Disk(db) => db
+                .insert(key, &cmd_pos.to_bytes()[..])?
+                .map(|bytes| CommandPos::from_bytes(&bytes))
+                .transpose(),
+        }
+    }
+
+    /// Returns the removed position of `key`.
+    pub fn remove(&mut self, key: &str) -> Result<Option<CommandPos>> {
+        match self {
+            Index::Memory(map) => Ok(map.remove(key)),
+            Index::Disk(db) => db
+                .remove(key)?
+                .map(|bytes| CommandPos::from_bytes(&bytes))
+                .transpose(),
+        }
+    }
+
+    /// Lists up to `limit` keys in order, starting from `start`.
+    pub fn scan(&self, start: String, limit: usize) -> Result<Vec<String>> {
+        match self {
+            Index::Memory(map) => Ok(map
+                .range(start..)
+                .take(limit)
+                .map(|(key, _)| key.to_owned())
+                .collect()),
+            Index::Disk(db) => db
+                .range(start..)
+                .take(limit)
+                .map(|entry| Ok(key_from_bytes(&entry?.0)))
+                .collect(),
+        }
+    }
+
+    /// Calls `f` with every key in order, letting it move the key's record.
+    pub fn update_all<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&str, &mut CommandPos) -> Result<()>,
+    {
+        match self {
+            Index::Memory(map) => {
+                for (key, cmd_pos) in map.iter_mut() {
+                    f(key, cmd_pos)?;
+                }
+            }
+            Index::Disk(db) => {
+                for entry in db.iter() {
+                    let (key, bytes) = entry?;
+                    let mut cmd_pos = CommandPos::from_bytes(&bytes)?;
+                    f(&key_from_bytes(&key), &mut cmd_pos)?;
+                    db.insert(key, &cmd_pos.to_bytes()[..])?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// keys are only ever inserted from `String`s
+fn key_from_bytes(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
@@ -0,0 +1,382 @@
+//! Offline inspection and repair of the log files of a `KvStore`.
+//!
+//! These functions read `engine_yakv_data` directly instead of opening the
+//! store, so they work on logs which `KvStore::open` refuses. The store must
+//! not be open while they run.
+//!
+//! Records have no checksum of their own, so a log is damaged from the first
+//! record which can't be read: a truncated header or body, a body which is
+//! not a set or remove command, or an encrypted body which fails to decrypt.
+//! Nothing after that point can be trusted, since the length of the damaged
+//! record is unknown.
+
+use crate::hint::{self, Hint, HintWriter};
+use crate::yakv::{
+    log_path, read_record, sorted_ids, sync_dir, write_record, BufWriterWithPos, LOG_MAGIC,
+    RECORD_CHECKSUM, RECORD_CHECKSUM_LEN, RECORD_ENCRYPTED, RECORD_HEADER_LEN,
+};
+use crate::{Command, Result, StoreOptions, YakvError};
+use anyhow::anyhow;
+use serde_json::Deserializer;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// A set or remove record of a log file.
+#[derive(Debug)]
+pub struct LogRecord {
+    /// Offset of the record in the log
+    pub pos: u64,
+
+    /// Length of the record, header included
+    pub len: u64,
+
+    /// The set or remove the record holds
+    pub command: Command,
+}
+
+/// The point from which a log can't be read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Corruption {
+    /// Offset of the first unreadable byte
+    pub pos: u64,
+
+    /// Why the record at `pos` can't be read
+    pub reason: String,
+}
+
+/// How far a log was read by `scan_log`.
+#[derive(Debug)]
+pub struct LogEnd {
+    /// The log holds plain JSON commands instead of framed records
+    pub legacy: bool,
+
+    /// Length of the log file in bytes
+    pub size: u64,
+
+    /// Where the log is damaged, if it is
+    pub corruption: Option<Corruption>,
+}
+
+/// Summary of one generation, i.e. one `<id>.log` file.
+#[derive(Debug)]
+pub struct Generation {
+    /// Id of the log file
+    pub id: u64,
+
+    /// The log holds plain JSON commands instead of framed records
+    pub legacy: bool,
+
+    /// Length of the log file in bytes
+    pub size: u64,
+
+    /// Number of readable set records
+    pub sets: u64,
+
+    /// Number of readable remove records
+    pub removes: u64,
+
+    /// Bytes of records which hold the latest value of their key
+    pub live_bytes: u64,
+
+    /// Bytes of overwritten sets and of removes, which compaction drops
+    pub stale_bytes: u64,
+
+    /// The log has a hint file which matches it
+    pub hint: bool,
+
+    /// Where the log is damaged, if it is
+    pub corruption: Option<Corruption>,
+}
+
+/// What `repair` salvaged.
+#[derive(Debug)]
+pub struct RepairReport {
+    /// Id of the log holding the salvaged records
+    pub generation: u64,
+
+    /// Number of keys in the new log
+    pub keys: u64,
+
+    /// Damaged logs, which were renamed to `<id>.log.damaged`
+    pub damaged: Vec<(u64, Corruption)>,
+}
+
+/// Returns the ids of the log files of the store in `path`, oldest first.
+pub fn generations(path: &Path) -> Result<Vec<u64>> {
+    sorted_ids(&data_dir(path)?)
+}
+
+/// Calls `f` with every record of log `id` up to its end or the first record
+/// which can't be read.
+///
+/// Logs with encrypted records need the key in `options`.
+pub fn scan_log<F>(path: &Path, id: u64, options: &StoreOptions, mut f: F) -> Result<LogEnd>
+where
+    F: FnMut(LogRecord) -> Result<()>,
+{
+    let mut file = File::open(log_path(data_dir(path)?, id))?;
+    let size = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(0))?;
+    let mut reader = BufReader::new(file);
+    let mut magic = Vec::with_capacity(LOG_MAGIC.len());
+    (&mut reader)
+        .take(LOG_MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+    let legacy = magic != LOG_MAGIC;
+    let corruption = if legacy {
+        reader.seek(SeekFrom::Start(0))?;
+        scan_legacy_log(reader, &mut f)?
+    } else {
+        scan_framed_log(reader, id, size, options, &mut f)?
+    };
+    Ok(LogEnd {
+        legacy,
+        size,
+        corruption,
+    })
+}
+
+/// Summarises every generation of the store in `path`.
+///
+/// Live and stale bytes are counted like `KvStore` does for compaction: a
+/// set is stale once its key is set again or removed, and a remove is always
+/// stale.
+pub fn inspect(path: &Path, options: &StoreOptions) -> Result<Vec<Generation>> {
+    let dir = data_dir(path)?;
+    // latest set of every key, by id and length
+    let mut latest: HashMap<String, (u64, u64)> = HashMap::new();
+    let mut generations = Vec::new();
+    for id in sorted_ids(&dir)? {
+        let mut generation = Generation {
+            id,
+            legacy: false,
+            size: 0,
+            sets: 0,
+            removes: 0,
+            live_bytes: 0,
+            stale_bytes: 0,
+            hint: false,
+            corruption: None,
+        };
+        let end = scan_log(path, id, options, |record| {
+            // every record is stale until it's found to be the latest set
+            generation.stale_bytes += record.len;
+            match record.command {
+                Command::Set { key, .. } => {
+                    generation.sets += 1;
+                    latest.insert(key, (id, record.len));
+                }
+                Command::Remove { key } => {
+                    generation.removes += 1;
+                    latest.remove(&key);
+                }
+                _ => {}
+            }
+            Ok(())
+        })?;
+        generation.legacy = end.legacy;
+        generation.size = end.size;
+        generation.hint = hint::read_hints(&dir, id, end.size, options).is_some();
+        generation.corruption = end.corruption;
+        generations.push(generation);
+    }
+
+    for (id, len) in latest.into_values() {
+        let generation = generations
+            .iter_mut()
+            .find(|generation| generation.id == id)
+            .expect("generation was scanned");
+        generation.live_bytes += len;
+        generation.stale_bytes -= len;
+    }
+    Ok(generations)
+}
+
+/// Writes the latest value of every key which can still be read to a new
+/// log, and removes the old logs. Damaged logs are kept as
+/// `<id>.log.damaged`.
+///
+/// Records after the damaged point of a log are lost, so a key may get back
+/// an older value. Fails without changing anything if no record at all can
+/// be read, which usually means the encryption key is wrong.
+pub fn repair(path: &Path, options: &StoreOptions) -> Result<RepairReport> {
+    let dir = data_dir(path)?;
+    let ids = sorted_ids(&dir)?;
+
+    // position of the latest set of every key
+    let mut latest: HashMap<String, (u64, u64)> = HashMap::new();
+    let mut records = 0;
+    let mut damaged = Vec::new();
+    for &id in &ids {
+        let end = scan_log(path, id, options, |record| {
+            records += 1;
+            match record.command {
+                Command::Set { key, .. } => {
+                    latest.insert(key, (id, record.pos));
+                }
+                Command::Remove { key } => {
+                    latest.remove(&key);
+                }
+                _ => {}
+            }
+            Ok(())
+        })?;
+        if let Some(corruption) = end.corruption {
+            damaged.push((id, corruption));
+        }
+    }
+    if records == 0 && !damaged.is_empty() {
+        return Err(YakvError::Any(anyhow!(
+            "no record of any log can be read, check the encryption key"
+        )));
+    }
+
+    // copy the latest sets a log at a time, in the order they were written
+    let generation = ids.last().unwrap_or(&0) + 1;
+    let mut writer = BufWriterWithPos::new(File::create(log_path(&dir, generation))?)?;
+    writer.write_all(LOG_MAGIC)?;
+    let mut hints = HintWriter::create(&dir, generation, options)?;
+    for &id in &ids {
+        scan_log(path, id, options, |record| {
+            if let Command::Set { key, .. } = &record.command {
+                if latest.get(key) == Some(&(id, record.pos)) {
+                    let pos = writer.pos;
+                    write_record(&mut writer, generation, pos, &record.command, options)?;
+                    hints.write(&Hint {
+                        key: key.to_owned(),
+                        pos,
+                        len: writer.pos - pos,
+                        removed: false,
+                    })?;
+                }
+            }
+            Ok(())
+        })?;
+    }
+    writer.sync()?;
+    hints.finish(writer.pos)?;
+    sync_dir(&dir)?;
+
+    // the new log must be on disk before the logs it replaces are removed
+    for &id in &ids {
+        let log = log_path(&dir, id);
+        if damaged.iter().any(|(damaged_id, _)| *damaged_id == id) {
+            fs::rename(&log, log.with_extension("log.damaged"))?;
+        } else {
+            fs::remove_file(&log)?;
+        }
+        match fs::remove_file(hint::hint_path(&dir, id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    sync_dir(&dir)?;
+
+    Ok(RepairReport {
+        generation,
+        keys: latest.len() as u64,
+        damaged,
+    })
+}
+
+fn data_dir(path: &Path) -> Result<PathBuf> {
+    let dir = path.join("engine_yakv_data");
+    if !dir.is_dir() {
+        return Err(YakvError::Any(anyhow!(
+            "{} is not a yakv data directory",
+            dir.display()
+        )));
+    }
+    Ok(dir)
+}
+
+fn scan_framed_log<R, F>(
+    mut reader: R,
+    id: u64,
+    size: u64,
+    options: &StoreOptions,
+    f: &mut F,
+) -> Result<Option<Corruption>>
+where
+    R: Read,
+    F: FnMut(LogRecord) -> Result<()>,
+{
+    let mut pos = LOG_MAGIC.len() as u64;
+    while pos < size {
+        let damaged = |reason: String| Ok(Some(Corruption { pos, reason }));
+        if size - pos < RECORD_HEADER_LEN as u64 {
+            return damaged(format!("record header is cut after {} bytes", size - pos));
+        }
+        let mut header = vec![0; RECORD_HEADER_LEN];
+        reader.read_exact(&mut header)?;
+        let body_len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as u64;
+        let mut header_len = RECORD_HEADER_LEN;
+        if header[0] & RECORD_CHECKSUM != 0 {
+            header_len += RECORD_CHECKSUM_LEN;
+        }
+        let len = header_len as u64 + body_len;
+        if len > size - pos {
+            return damaged(format!(
+                "record of {} bytes runs past the end of the log",
+                len
+            ));
+        }
+        if header[0] & RECORD_ENCRYPTED != 0
+            && options.encryption_key.is_none()
+            && options.previous_keys.is_empty()
+        {
+            return Err(YakvError::Any(anyhow!(
+                "log is encrypted but no encryption key was given"
+            )));
+        }
+        header.resize(header_len, 0);
+        reader.read_exact(&mut header[RECORD_HEADER_LEN..])?;
+        let mut body = vec![0; body_len as usize];
+        reader.read_exact(&mut body)?;
+        let command = match read_record(&mut (&header[..]).chain(&body[..]), len, id, pos, options)
+        {
+            Ok((command @ (Command::Set { .. } | Command::Remove { .. }), _)) => command,
+            Ok((command, _)) => return damaged(format!("unexpected command {:?}", command)),
+            Err(e) => return damaged(e.to_string()),
+        };
+        f(LogRecord { pos, len, command })?;
+        pos += len;
+    }
+    Ok(None)
+}
+
+fn scan_legacy_log<R, F>(reader: R, f: &mut F) -> Result<Option<Corruption>>
+where
+    R: Read,
+    F: FnMut(LogRecord) -> Result<()>,
+{
+    let mut pos = 0;
+    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+    while let Some(command) = stream.next() {
+        let command = match command {
+            Ok(command @ (Command::Set { .. } | Command::Remove { .. })) => command,
+            Ok(command) => {
+                return Ok(Some(Corruption {
+                    pos,
+                    reason: format!("unexpected command {:?}", command),
+                }))
+            }
+            Err(e) => {
+                return Ok(Some(Corruption {
+                    pos,
+                    reason: e.to_string(),
+                }))
+            }
+        };
+        let new_pos = stream.byte_offset() as u64;
+        f(LogRecord {
+            pos,
+            len: new_pos - pos,
+            command,
+        })?;
+        pos = new_pos;
+    }
+    Ok(None)
+}
@@ -1,14 +1,50 @@
 #![deny(missing_docs)]
 //! Yet another Key/Value store
 
+pub use acl::{Acl, Permission, User};
+pub use cache::CacheStats;
+pub use client::{ClientOptions, MakvClient};
+pub use compression::{Compression, DEFAULT_COMPRESSION_THRESHOLD};
+pub use encryption::EncryptionKey;
 pub use engine::{Engine, MakvEngine};
 pub use error::{Result, YakvError};
-pub use protocol::{Payload, PayloadType, Response, YakvMessage};
+pub use grpc::{proto, GrpcServer};
+pub use http::HttpGateway;
+pub use index::IndexMode;
+pub use lsm::{LsmOptions, LsmStore};
+pub use protocol::{
+    ErrorKind, Handshake, Payload, PayloadType, Response, YakvMessage, DEFAULT_MAX_FRAME_SIZE,
+    FEATURES, LEGACY_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+pub use resp::RespServer;
+pub use sled_store::SledStore;
+pub use stats::{RequestStats, StoreStats};
 pub use thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
-pub use yakv::{Command, KvStore};
+pub use watch::{WatchEvent, WatchedEngine};
+pub use yakv::{Command, Durability, KvStore, StoreOptions};
 
+mod acl;
+mod cache;
+mod client;
+mod compression;
+mod encryption;
 mod engine;
 mod error;
+mod grpc;
+mod hint;
+mod http;
+mod index;
+pub mod inspect;
+pub mod logging;
+mod lsm;
+pub mod metrics;
+pub mod net;
 mod protocol;
+mod resp;
+mod sled_store;
+mod stats;
 mod thread_pool;
+pub mod tls;
+pub mod trace;
+mod watch;
 mod yakv;
@@ -0,0 +1,94 @@
+//! Log files of makv-server which rotate by size.
+//!
+//! Once the file holds `max_size` bytes, it's renamed to `<path>.1`, older
+//! files move up to `<path>.2` and so on, and a new file is started. Only
+//! `keep` old files are kept. Files only rotate between two lines, so a file
+//! may grow past `max_size` by the length of a line.
+
+use crate::Result;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// A log file which rotates once it reaches a size.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+    // the last byte written ends a line
+    line_start: bool,
+}
+
+impl RotatingFile {
+    /// Opens the log file at `path` for appending, keeping `keep` old files
+    /// of up to `max_size` bytes.
+    pub fn open(path: impl Into<PathBuf>, max_size: u64, keep: usize) -> Result<Self> {
+        let path = path.into();
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            max_size,
+            keep,
+            file,
+            size,
+            line_start: true,
+        })
+    }
+
+    /// Returns the path of the `n`th old file, 0 being the current one.
+    pub fn rotated_path(&self, n: usize) -> PathBuf {
+        rotated_path(&self.path, n)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                match fs::rename(self.rotated_path(n), self.rotated_path(n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.line_start && self.size >= self.max_size && !buf.is_empty() {
+            self.rotate()?;
+        }
+        let len = self.file.write(buf)?;
+        self.size += len as u64;
+        if len > 0 {
+            self.line_start = buf[len - 1] == b'\n';
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    if n == 0 {
+        return path.to_owned();
+    }
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
@@ -0,0 +1,75 @@
+use crate::{Result, YakvError};
+use anyhow::anyhow;
+
+// Upper bound on the number of probes, reached at 14 bits per key
+const MAX_HASHES: u32 = 10;
+
+/// A bloom filter over the keys of a table.
+///
+/// Probes are derived from the two halves of one 64-bit FNV-1a hash
+/// (Kirsch-Mitzenmacher double hashing).
+pub(crate) struct Bloom {
+    bits: Vec<u8>,
+    hashes: u32,
+}
+
+impl Bloom {
+    /// Builds a filter from the hashes of every key of a table.
+    pub fn build(key_hashes: &[u64], bits_per_key: usize) -> Self {
+        // ln(2) * bits per key probes give the lowest false positive rate
+        let hashes = ((bits_per_key as f64 * 0.69) as u32).clamp(1, MAX_HASHES);
+        let len = (key_hashes.len() * bits_per_key).max(64).div_ceil(8);
+        let mut bloom = Bloom {
+            bits: vec![0; len],
+            hashes,
+        };
+        for &hash in key_hashes {
+            for bit in bloom.probes(hash) {
+                bloom.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        bloom
+    }
+
+    /// Returns false if `key` is certainly not in the table.
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.probes(hash(key))
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// Returns `| hashes: u8 | bits |`.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(1 + self.bits.len());
+        bytes.push(self.hashes as u8);
+        bytes.extend_from_slice(&self.bits);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        match bytes.split_first() {
+            Some((&hashes, bits))
+                if !bits.is_empty() && (1..=MAX_HASHES).contains(&(hashes as u32)) =>
+            {
+                Ok(Bloom {
+                    bits: bits.to_vec(),
+                    hashes: hashes as u32,
+                })
+            }
+            _ => Err(YakvError::Any(anyhow!("corrupted bloom filter"))),
+        }
+    }
+
+    fn probes(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 8;
+        let h1 = hash & 0xffff_ffff;
+        let h2 = hash >> 32;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
+
+/// 64-bit FNV-1a hash of a key.
+pub(crate) fn hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
@@ -0,0 +1,569 @@
+//! A log-structured merge tree engine.
+//!
+//! Writes go to a write-ahead log and a sorted in-memory memtable. A full
+//! memtable is written to an immutable sorted table (`<id>.sst`) in level 0,
+//! and its log is removed. Tables of level 0 may overlap each other; deeper
+//! levels hold tables with disjoint key ranges, each level ten times larger
+//! than the one above it. A level over its size is merged into the next one
+//! a table at a time (leveled compaction).
+//!
+//! The tables of each level are listed in a `MANIFEST` file, which is
+//! replaced atomically after every flush and compaction. Tables not in the
+//! manifest are left over from an interrupted compaction and are removed on
+//! open. Tables and the manifest are fsynced before the log they replace is
+//! removed, whatever the durability of writes.
+
+mod bloom;
+mod sstable;
+mod wal;
+
+use self::sstable::{table_path, Table, TableWriter};
+use self::wal::{wal_path, Wal};
+use crate::metrics::{Histogram, COMPACTION_BUCKETS};
+use crate::stats::RequestCounters;
+use crate::yakv::sync_dir;
+use crate::{Durability, MakvEngine, Result, StoreStats, YakvError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// A key and its value, where None is a tombstone left by a removal
+type Entry = (String, Option<String>);
+
+const MAX_LEVELS: usize = 7;
+const LEVEL_SIZE_MULTIPLIER: u64 = 10;
+
+/// Options for `LsmStore::open_with`.
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    /// Size in bytes of the memtable before it is written to a table
+    pub memtable_size: usize,
+
+    /// Size in bytes of the data blocks of tables
+    pub block_size: usize,
+
+    /// Size in bytes of the tables written by compactions
+    pub table_size: u64,
+
+    /// Number of tables in level 0 which triggers a compaction into level 1
+    pub level0_tables: usize,
+
+    /// Size in bytes of level 1; every deeper level is ten times larger
+    pub level1_size: u64,
+
+    /// Bits of bloom filter per key, 10 gives about 1% false positives
+    pub bloom_bits_per_key: usize,
+
+    /// When writes to the log are fsynced. Writers hold the store lock, so
+    /// `Group` can't batch their fsyncs and behaves like `Sync`.
+    pub durability: Durability,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            memtable_size: 4 * 1024 * 1024,
+            block_size: 4 * 1024,
+            table_size: 2 * 1024 * 1024,
+            level0_tables: 4,
+            level1_size: 10 * 1024 * 1024,
+            bloom_bits_per_key: 10,
+            durability: Durability::Buffered,
+        }
+    }
+}
+
+/// The `LsmStore` stores string key/value pairs in a log-structured merge tree.
+///
+/// Unlike `KvStore` it doesn't keep every key in memory, and scans read keys
+/// in order from sorted tables.
+///
+/// ```rust
+/// # use makv::{LsmStore, MakvEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let store = LsmStore::open(current_dir()?)?;
+/// store.set("key".to_owned(), "value".to_owned())?;
+/// let val = store.get("key".to_owned())?;
+/// assert_eq!(val, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct LsmStore {
+    store: Arc<Mutex<SharedLsmStore>>,
+    requests: Arc<RequestCounters>,
+}
+
+impl LsmStore {
+    /// Opens a LsmStore with the given path.
+    pub fn open<T: Into<PathBuf>>(path: T) -> Result<Self> {
+        LsmStore::open_with(path, LsmOptions::default())
+    }
+
+    /// Opens a LsmStore with the given path and options.
+    pub fn open_with<T: Into<PathBuf>>(path: T, options: LsmOptions) -> Result<Self> {
+        Ok(LsmStore {
+            store: Arc::new(Mutex::new(SharedLsmStore::open(path, options)?)),
+            requests: Arc::new(RequestCounters::default()),
+        })
+    }
+}
+
+impl MakvEngine for LsmStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        RequestCounters::count(&self.requests.sets, 1);
+        let mut store = self.store.lock().unwrap();
+        store.write(key, Some(value))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        RequestCounters::count(&self.requests.gets, 1);
+        let store = self.store.lock().unwrap();
+        store.get(&key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        RequestCounters::count(&self.requests.removes, 1);
+        let mut store = self.store.lock().unwrap();
+        if store.get(&key)?.is_none() {
+            return Err(YakvError::NotFoundError(key));
+        }
+        store.write(key, None)
+    }
+
+    fn scan(&self, start: String, limit: usize) -> Result<Vec<String>> {
+        RequestCounters::count(&self.requests.scans, 1);
+        let store = self.store.lock().unwrap();
+        store.scan(&start, limit)
+    }
+
+    /// Returns the stats without reading any table, so the key count is an
+    /// estimate from the entry counts of the tables: a key is counted once
+    /// for every table holding it, and a removed key until compaction drops
+    /// its tombstone. Generations are tables, and live bytes their sizes.
+    fn stats(&self) -> Result<StoreStats> {
+        let store = self.store.lock().unwrap();
+        let tables = store.levels.iter().flatten();
+        let memtable_keys = store.memtable.values().filter(|v| v.is_some()).count() as u64;
+        let tombstones = store.memtable.len() as u64 - memtable_keys;
+        Ok(StoreStats {
+            keys: (tables.clone().map(|table| table.entries).sum::<u64>() + memtable_keys)
+                .saturating_sub(tombstones),
+            live_bytes: tables.clone().map(|table| table.size).sum(),
+            generations: tables.count() as u64,
+            last_compaction: store.last_compaction.map(|time| {
+                time.duration_since(UNIX_EPOCH)
+                    .map_or(0, |since_epoch| since_epoch.as_secs())
+            }),
+            compactions: store.compaction_durations.count,
+            compaction_durations: store.compaction_durations.clone(),
+            reclaimed_bytes: store.reclaimed_bytes,
+            requests: self.requests.stats(),
+            ..StoreStats::default()
+        })
+    }
+}
+
+// Tables of every level, by id
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    levels: Vec<Vec<u64>>,
+}
+
+struct SharedLsmStore {
+    path: PathBuf,
+    options: LsmOptions,
+    next_id: u64,
+    wal: Wal,
+    memtable: BTreeMap<String, Option<String>>,
+    memtable_size: usize,
+    // level 0 is ordered from oldest to newest, deeper levels by key range
+    levels: Vec<Vec<Table>>,
+    // largest key of the last table compacted out of every level, so
+    // compactions go round the key space
+    compaction_pointers: Vec<Option<String>>,
+    last_compaction: Option<SystemTime>,
+    compaction_durations: Histogram,
+    // bytes of compacted tables less the bytes of the tables replacing them
+    reclaimed_bytes: u64,
+}
+
+impl SharedLsmStore {
+    fn open<T: Into<PathBuf>>(path: T, options: LsmOptions) -> Result<Self> {
+        let mut path = path.into();
+        path.push("engine_lsm_data");
+        fs::create_dir_all(&path)?;
+
+        let manifest: Manifest = match fs::read(path.join("MANIFEST")) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Manifest::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut levels: Vec<Vec<Table>> = (0..MAX_LEVELS).map(|_| Vec::new()).collect();
+        for (level, ids) in manifest.levels.iter().enumerate().take(MAX_LEVELS) {
+            for &id in ids {
+                levels[level].push(Table::open(&path, id)?);
+            }
+        }
+
+        let (table_ids, wal_ids) = file_ids(&path)?;
+        for id in &table_ids {
+            if !manifest.levels.iter().flatten().any(|live| live == id) {
+                fs::remove_file(table_path(&path, *id))?;
+            }
+        }
+        let next_id = table_ids
+            .iter()
+            .chain(&wal_ids)
+            .max()
+            .map_or(1, |id| id + 1);
+
+        // the logs of memtables which weren't written to tables yet
+        let mut memtable = BTreeMap::new();
+        let mut memtable_size = 0;
+        for &id in &wal_ids {
+            for (key, value) in wal::replay(&path, id)? {
+                memtable_size += entry_size(&key, &value);
+                memtable.insert(key, value);
+            }
+        }
+        let mut wal = Wal::create(&path, next_id, options.durability)?;
+        for (key, value) in &memtable {
+            wal.append(key, value.as_deref())?;
+        }
+        // the new log must be on disk before the logs it replaces are removed
+        wal.sync()?;
+        sync_dir(&path)?;
+        for id in wal_ids {
+            fs::remove_file(wal_path(&path, id))?;
+        }
+
+        Ok(SharedLsmStore {
+            path,
+            options,
+            next_id: next_id + 1,
+            wal,
+            memtable,
+            memtable_size,
+            levels,
+            compaction_pointers: vec![None; MAX_LEVELS],
+            last_compaction: None,
+            compaction_durations: Histogram::new(COMPACTION_BUCKETS),
+            reclaimed_bytes: 0,
+        })
+    }
+
+    fn write(&mut self, key: String, value: Option<String>) -> Result<()> {
+        self.wal.append(&key, value.as_deref())?;
+        self.memtable_size += entry_size(&key, &value);
+        self.memtable.insert(key, value);
+        if self.memtable_size >= self.options.memtable_size {
+            self.flush()?;
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        if let Some(value) = self.memtable.get(key) {
+            return Ok(value.clone());
+        }
+        for table in self.levels[0].iter().rev() {
+            if let Some(value) = table.get(key)? {
+                return Ok(value);
+            }
+        }
+        for level in &self.levels[1..] {
+            let i = level.partition_point(|table| table.largest.as_str() < key);
+            if let Some(table) = level.get(i) {
+                if let Some(value) = table.get(key)? {
+                    return Ok(value);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn scan(&self, start: &str, limit: usize) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for entry in self.entries_from(start) {
+            if keys.len() >= limit {
+                break;
+            }
+            if let (key, Some(_)) = entry? {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+
+    // The latest entry of every key from `start` on, in order
+    fn entries_from<'a>(&'a self, start: &'a str) -> MergeIter<'a> {
+        let mut sources: Vec<Box<dyn Iterator<Item = Result<Entry>> + '_>> = Vec::new();
+        sources.push(Box::new(
+            self.memtable
+                .range(start.to_owned()..)
+                .map(|(key, value)| Ok((key.clone(), value.clone()))),
+        ));
+        for table in self.levels[0].iter().rev() {
+            sources.push(Box::new(table.iter_from(start)));
+        }
+        for level in &self.levels[1..] {
+            let first = level.partition_point(|table| table.largest.as_str() < start);
+            sources.push(Box::new(
+                level[first..]
+                    .iter()
+                    .flat_map(move |table| table.iter_from(start)),
+            ));
+        }
+        MergeIter::new(sources)
+    }
+
+    // Writes the memtable to a new table in level 0 and starts a new log
+    fn flush(&mut self) -> Result<()> {
+        if !self.memtable.is_empty() {
+            let id = self.take_id();
+            let mut writer = TableWriter::create(
+                &self.path,
+                id,
+                self.options.block_size,
+                self.options.bloom_bits_per_key,
+            )?;
+            for (key, value) in &self.memtable {
+                writer.add(key, value.as_deref())?;
+            }
+            self.levels[0].push(writer.finish()?);
+            self.write_manifest()?;
+        }
+
+        let old_wal = self.wal.id;
+        let id = self.take_id();
+        self.wal = Wal::create(&self.path, id, self.options.durability)?;
+        // the table, the manifest and the new log must be on disk before the
+        // old log is removed
+        sync_dir(&self.path)?;
+        fs::remove_file(wal_path(&self.path, old_wal))?;
+        self.memtable.clear();
+        self.memtable_size = 0;
+        Ok(())
+    }
+
+    // Compacts levels until none is over its size
+    fn compact(&mut self) -> Result<()> {
+        loop {
+            if self.levels[0].len() >= self.options.level0_tables {
+                self.compact_level(0)?;
+                continue;
+            }
+            let full = (1..MAX_LEVELS - 1).find(|&level| {
+                let size: u64 = self.levels[level].iter().map(|table| table.size).sum();
+                size > self.max_level_size(level)
+            });
+            match full {
+                Some(level) => self.compact_level(level)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    // Merges tables of `level` with the tables they overlap in the next level
+    fn compact_level(&mut self, level: usize) -> Result<()> {
+        let start = Instant::now();
+        let inputs: Vec<usize> = if level == 0 {
+            (0..self.levels[0].len()).collect()
+        } else {
+            // the first table after the one compacted last time
+            let tables = &self.levels[level];
+            let next = match &self.compaction_pointers[level] {
+                Some(pointer) => tables.partition_point(|table| &table.largest <= pointer),
+                None => 0,
+            };
+            vec![if next < tables.len() { next } else { 0 }]
+        };
+        let smallest = inputs
+            .iter()
+            .map(|&i| self.levels[level][i].smallest.clone())
+            .min()
+            .expect("level has tables");
+        let largest = inputs
+            .iter()
+            .map(|&i| self.levels[level][i].largest.clone())
+            .max()
+            .expect("level has tables");
+        let overlapping: Vec<usize> = (0..self.levels[level + 1].len())
+            .filter(|&i| self.levels[level + 1][i].overlaps(&smallest, &largest))
+            .collect();
+        // tombstones are only needed while an older value may be below them
+        let bottom = self.levels[level + 2..].iter().all(Vec::is_empty);
+
+        let mut outputs = Vec::new();
+        {
+            let mut sources: Vec<Box<dyn Iterator<Item = Result<Entry>> + '_>> = Vec::new();
+            // newer tables first, so their entries win
+            for &i in inputs.iter().rev() {
+                sources.push(Box::new(self.levels[level][i].iter_from("")));
+            }
+            sources.push(Box::new(
+                overlapping
+                    .iter()
+                    .flat_map(|&i| self.levels[level + 1][i].iter_from("")),
+            ));
+
+            let mut writer: Option<TableWriter> = None;
+            for entry in MergeIter::new(sources) {
+                let (key, value) = entry?;
+                if value.is_none() && bottom {
+                    continue;
+                }
+                let table = match writer.as_mut() {
+                    Some(table) => table,
+                    None => writer.insert(TableWriter::create(
+                        &self.path,
+                        self.next_id + outputs.len() as u64,
+                        self.options.block_size,
+                        self.options.bloom_bits_per_key,
+                    )?),
+                };
+                table.add(&key, value.as_deref())?;
+                if table.size() >= self.options.table_size {
+                    outputs.push(writer.take().expect("writer is open").finish()?);
+                }
+            }
+            if let Some(table) = writer {
+                if table.is_empty() {
+                    table.abandon()?;
+                } else {
+                    outputs.push(table.finish()?);
+                }
+            }
+        }
+        self.next_id += outputs.len() as u64 + 1;
+        let outputs_size: u64 = outputs.iter().map(|table| table.size).sum();
+
+        self.compaction_pointers[level] = Some(largest);
+        let removed: Vec<Table> = remove_indices(&mut self.levels[level], &inputs)
+            .into_iter()
+            .chain(remove_indices(&mut self.levels[level + 1], &overlapping))
+            .collect();
+        let next_level = &mut self.levels[level + 1];
+        next_level.extend(outputs);
+        next_level.sort_by(|a, b| a.smallest.cmp(&b.smallest));
+        self.write_manifest()?;
+
+        let compacted: u64 = removed.iter().map(|table| table.size).sum();
+        for table in removed {
+            fs::remove_file(table_path(&self.path, table.id))?;
+        }
+        self.reclaimed_bytes += compacted.saturating_sub(outputs_size);
+        self.last_compaction = Some(SystemTime::now());
+        self.compaction_durations.observe(start.elapsed());
+        Ok(())
+    }
+
+    fn max_level_size(&self, level: usize) -> u64 {
+        self.options.level1_size * LEVEL_SIZE_MULTIPLIER.pow(level as u32 - 1)
+    }
+
+    fn take_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    fn write_manifest(&self) -> Result<()> {
+        let manifest = Manifest {
+            levels: self
+                .levels
+                .iter()
+                .map(|level| level.iter().map(|table| table.id).collect())
+                .collect(),
+        };
+        // write to a temporary file first so a crash never loses the manifest
+        let tmp_path = self.path.join("MANIFEST.tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&serde_json::to_vec(&manifest)?)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, self.path.join("MANIFEST"))?;
+        sync_dir(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Merges sorted sources into one sorted iterator. When several sources hold
+/// the same key, the entry of the first source wins.
+struct MergeIter<'a> {
+    sources: Vec<Peekable<Box<dyn Iterator<Item = Result<Entry>> + 'a>>>,
+}
+
+impl<'a> MergeIter<'a> {
+    fn new(sources: Vec<Box<dyn Iterator<Item = Result<Entry>> + 'a>>) -> Self {
+        MergeIter {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+        }
+    }
+}
+
+impl Iterator for MergeIter<'_> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut min: Option<(usize, &str)> = None;
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            match source.peek() {
+                Some(Ok((key, _))) if min.is_none_or(|(_, min_key)| key.as_str() < min_key) => {
+                    min = Some((i, key));
+                }
+                Some(Ok(_)) => {}
+                Some(Err(_)) => return source.next(),
+                None => {}
+            }
+        }
+        let (winner, key) = min?;
+        let key = key.to_owned();
+        for source in &mut self.sources[winner + 1..] {
+            while source
+                .peek()
+                .is_some_and(|entry| matches!(entry, Ok((k, _)) if *k == key))
+            {
+                source.next();
+            }
+        }
+        self.sources[winner].next()
+    }
+}
+
+fn entry_size(key: &str, value: &Option<String>) -> usize {
+    key.len() + value.as_ref().map_or(0, String::len) + 8
+}
+
+// Removes the elements at the sorted `indices`
+fn remove_indices<T>(items: &mut Vec<T>, indices: &[usize]) -> Vec<T> {
+    indices.iter().rev().map(|&i| items.remove(i)).collect()
+}
+
+// ids of the table files and of the log files in a given path, sorted
+fn file_ids(path: &Path) -> Result<(Vec<u64>, Vec<u64>)> {
+    let mut tables = Vec::new();
+    let mut wals = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        let id = path
+            .file_stem()
+            .and_then(OsStr::to_str)
+            .and_then(|stem| stem.parse::<u64>().ok());
+        match (id, path.extension().and_then(OsStr::to_str)) {
+            (Some(id), Some("sst")) => tables.push(id),
+            (Some(id), Some("wal")) => wals.push(id),
+            _ => {}
+        }
+    }
+    tables.sort_unstable();
+    wals.sort_unstable();
+    Ok((tables, wals))
+}
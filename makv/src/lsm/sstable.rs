@@ -0,0 +1,374 @@
+//! Immutable sorted tables.
+//!
+//! A table is a run of data blocks, an index and a bloom filter:
+//!
+//! | data blocks | index | bloom filter | footer |
+//!
+//! Every entry of a data block is
+//! | key length: u32 BE | key | flags: u8 | value length: u32 BE | value |,
+//! where the `TOMBSTONE` flag marks a removed key. The index holds the
+//! smallest key of the table and, for every block, its last key, offset,
+//! length and crc32. The footer is
+//! | index offset: u64 BE | bloom offset: u64 BE | bloom length: u64 BE |
+//! | entry count: u64 BE | magic: 4 bytes |.
+//!
+//! Only the index and the bloom filter are kept in memory, so a lookup reads
+//! at most one block.
+
+use super::bloom::{self, Bloom};
+use super::Entry;
+use crate::{Result, YakvError};
+use anyhow::anyhow;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::vec;
+
+const TABLE_MAGIC: &[u8; 4] = b"YKS\x01";
+const FOOTER_LEN: u64 = 36;
+const TOMBSTONE: u8 = 0x01;
+
+pub(crate) fn table_path(path: &Path, id: u64) -> PathBuf {
+    path.join(format!("{}.sst", id))
+}
+
+// Location of a data block
+struct BlockHandle {
+    last_key: String,
+    offset: u64,
+    len: u32,
+    crc: u32,
+}
+
+/// Writes entries, which must be added in ascending key order, to a table.
+pub(crate) struct TableWriter {
+    path: PathBuf,
+    id: u64,
+    writer: BufWriter<File>,
+    block_size: usize,
+    bloom_bits_per_key: usize,
+    block: Vec<u8>,
+    last_key: String,
+    smallest: Option<String>,
+    offset: u64,
+    index: Vec<BlockHandle>,
+    key_hashes: Vec<u64>,
+}
+
+impl TableWriter {
+    pub fn create(
+        path: &Path,
+        id: u64,
+        block_size: usize,
+        bloom_bits_per_key: usize,
+    ) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(table_path(path, id))?;
+        Ok(TableWriter {
+            path: path.to_owned(),
+            id,
+            writer: BufWriter::new(file),
+            block_size,
+            bloom_bits_per_key,
+            block: Vec::new(),
+            last_key: String::new(),
+            smallest: None,
+            offset: 0,
+            index: Vec::new(),
+            key_hashes: Vec::new(),
+        })
+    }
+
+    pub fn add(&mut self, key: &str, value: Option<&str>) -> Result<()> {
+        if self.smallest.is_none() {
+            self.smallest = Some(key.to_owned());
+        }
+        let flags = if value.is_none() { TOMBSTONE } else { 0 };
+        let value = value.unwrap_or_default();
+        self.block
+            .extend_from_slice(&(key.len() as u32).to_be_bytes());
+        self.block.extend_from_slice(key.as_bytes());
+        self.block.push(flags);
+        self.block
+            .extend_from_slice(&(value.len() as u32).to_be_bytes());
+        self.block.extend_from_slice(value.as_bytes());
+        self.last_key.clear();
+        self.last_key.push_str(key);
+        self.key_hashes.push(bloom::hash(key));
+
+        if self.block.len() >= self.block_size {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of bytes written so far.
+    pub fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    /// Returns whether no entries were added.
+    pub fn is_empty(&self) -> bool {
+        self.key_hashes.is_empty()
+    }
+
+    /// Removes the table instead of finishing it.
+    pub fn abandon(self) -> Result<()> {
+        drop(self.writer);
+        fs::remove_file(table_path(&self.path, self.id))?;
+        Ok(())
+    }
+
+    /// Writes the index, bloom filter and footer, and opens the table.
+    pub fn finish(mut self) -> Result<Table> {
+        self.finish_block()?;
+
+        let mut index = Vec::new();
+        let smallest = self.smallest.take().unwrap_or_default();
+        put_bytes(&mut index, smallest.as_bytes());
+        for handle in &self.index {
+            put_bytes(&mut index, handle.last_key.as_bytes());
+            index.extend_from_slice(&handle.offset.to_be_bytes());
+            index.extend_from_slice(&handle.len.to_be_bytes());
+            index.extend_from_slice(&handle.crc.to_be_bytes());
+        }
+        let index_offset = self.offset;
+        self.writer.write_all(&index)?;
+
+        let bloom = Bloom::build(&self.key_hashes, self.bloom_bits_per_key).encode();
+        let bloom_offset = index_offset + index.len() as u64;
+        self.writer.write_all(&bloom)?;
+
+        let mut footer = Vec::with_capacity(FOOTER_LEN as usize);
+        footer.extend_from_slice(&index_offset.to_be_bytes());
+        footer.extend_from_slice(&bloom_offset.to_be_bytes());
+        footer.extend_from_slice(&(bloom.len() as u64).to_be_bytes());
+        footer.extend_from_slice(&(self.key_hashes.len() as u64).to_be_bytes());
+        footer.extend_from_slice(TABLE_MAGIC);
+        self.writer.write_all(&footer)?;
+        self.writer.flush()?;
+        // the table must be on disk before a manifest lists it
+        self.writer.get_ref().sync_all()?;
+
+        Table::open(&self.path, self.id)
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.writer.write_all(&self.block)?;
+        self.index.push(BlockHandle {
+            last_key: self.last_key.clone(),
+            offset: self.offset,
+            len: self.block.len() as u32,
+            crc: crc32fast::hash(&self.block),
+        });
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+}
+
+/// An open table, with its index and bloom filter in memory.
+pub(crate) struct Table {
+    pub id: u64,
+    pub smallest: String,
+    pub largest: String,
+    pub size: u64,
+    // number of entries, tombstones included
+    pub entries: u64,
+    file: File,
+    index: Vec<BlockHandle>,
+    bloom: Bloom,
+}
+
+impl Table {
+    pub fn open(path: &Path, id: u64) -> Result<Self> {
+        let mut file = File::open(table_path(path, id))?;
+        let size = file.metadata()?.len();
+        if size < FOOTER_LEN {
+            return Err(corrupted(id));
+        }
+        let mut footer = [0; FOOTER_LEN as usize];
+        file.seek(SeekFrom::Start(size - FOOTER_LEN))?;
+        file.read_exact(&mut footer)?;
+        if &footer[32..] != TABLE_MAGIC {
+            return Err(corrupted(id));
+        }
+        let index_offset = u64_at(&footer, 0);
+        let bloom_offset = u64_at(&footer, 8);
+        let bloom_len = u64_at(&footer, 16);
+        if index_offset > bloom_offset
+            || bloom_offset.checked_add(bloom_len) != Some(size - FOOTER_LEN)
+        {
+            return Err(corrupted(id));
+        }
+
+        let mut meta = vec![0; (size - FOOTER_LEN - index_offset) as usize];
+        file.seek(SeekFrom::Start(index_offset))?;
+        file.read_exact(&mut meta)?;
+        let (index_bytes, bloom_bytes) = meta.split_at((bloom_offset - index_offset) as usize);
+
+        let mut cursor = index_bytes;
+        let smallest = get_string(&mut cursor).ok_or_else(|| corrupted(id))?;
+        let mut index = Vec::new();
+        while !cursor.is_empty() {
+            let last_key = get_string(&mut cursor).ok_or_else(|| corrupted(id))?;
+            if cursor.len() < 16 {
+                return Err(corrupted(id));
+            }
+            let (handle, rest) = cursor.split_at(16);
+            cursor = rest;
+            index.push(BlockHandle {
+                last_key,
+                offset: u64_at(handle, 0),
+                len: u32::from_be_bytes(handle[8..12].try_into().unwrap()),
+                crc: u32::from_be_bytes(handle[12..16].try_into().unwrap()),
+            });
+        }
+
+        Ok(Table {
+            id,
+            largest: index
+                .last()
+                .map(|handle| handle.last_key.clone())
+                .unwrap_or_default(),
+            smallest,
+            size,
+            entries: u64_at(&footer, 24),
+            file,
+            index,
+            bloom: Bloom::decode(bloom_bytes)?,
+        })
+    }
+
+    /// Returns Some(None) if the table holds a tombstone for `key`, and None
+    /// if it holds nothing for it.
+    pub fn get(&self, key: &str) -> Result<Option<Option<String>>> {
+        if key < self.smallest.as_str()
+            || key > self.largest.as_str()
+            || !self.bloom.may_contain(key)
+        {
+            return Ok(None);
+        }
+        let block = self
+            .index
+            .partition_point(|handle| handle.last_key.as_str() < key);
+        if block == self.index.len() {
+            return Ok(None);
+        }
+        Ok(self
+            .read_block(block)?
+            .into_iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value))
+    }
+
+    /// Returns whether the keys of the table overlap with `smallest..=largest`.
+    pub fn overlaps(&self, smallest: &str, largest: &str) -> bool {
+        self.smallest.as_str() <= largest && self.largest.as_str() >= smallest
+    }
+
+    /// Iterates over the entries with keys of at least `start`.
+    pub fn iter_from(&self, start: &str) -> TableIter<'_> {
+        let block = self
+            .index
+            .partition_point(|handle| handle.last_key.as_str() < start);
+        TableIter {
+            table: self,
+            block,
+            start: start.to_owned(),
+            entries: Vec::new().into_iter(),
+        }
+    }
+
+    fn read_block(&self, block: usize) -> Result<Vec<Entry>> {
+        let handle = &self.index[block];
+        let mut bytes = vec![0; handle.len as usize];
+        // reads through a shared handle since every read seeks first
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(handle.offset))?;
+        file.read_exact(&mut bytes)?;
+        if crc32fast::hash(&bytes) != handle.crc {
+            return Err(corrupted(self.id));
+        }
+
+        let mut cursor = &bytes[..];
+        let mut entries = Vec::new();
+        while !cursor.is_empty() {
+            let key = get_string(&mut cursor).ok_or_else(|| corrupted(self.id))?;
+            let (&flags, rest) = cursor.split_first().ok_or_else(|| corrupted(self.id))?;
+            cursor = rest;
+            let value = get_string(&mut cursor).ok_or_else(|| corrupted(self.id))?;
+            entries.push((key, Some(value).filter(|_| flags & TOMBSTONE == 0)));
+        }
+        Ok(entries)
+    }
+}
+
+/// Iterator over the entries of a table, reading one block at a time.
+pub(crate) struct TableIter<'a> {
+    table: &'a Table,
+    block: usize,
+    start: String,
+    entries: vec::IntoIter<Entry>,
+}
+
+impl Iterator for TableIter<'_> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                if entry.0 >= self.start {
+                    return Some(Ok(entry));
+                }
+                continue;
+            }
+            if self.block >= self.table.index.len() {
+                return None;
+            }
+            match self.table.read_block(self.block) {
+                Ok(entries) => {
+                    self.entries = entries.into_iter();
+                    self.block += 1;
+                }
+                Err(e) => {
+                    self.block = self.table.index.len();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+// Takes a length-prefixed string from the front of `cursor`
+fn get_string(cursor: &mut &[u8]) -> Option<String> {
+    if cursor.len() < 4 {
+        return None;
+    }
+    let (len, rest) = cursor.split_at(4);
+    let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+    if rest.len() < len {
+        return None;
+    }
+    let (bytes, rest) = rest.split_at(len);
+    *cursor = rest;
+    String::from_utf8(bytes.to_vec()).ok()
+}
+
+fn u64_at(bytes: &[u8], i: usize) -> u64 {
+    u64::from_be_bytes(bytes[i..i + 8].try_into().unwrap())
+}
+
+fn corrupted(id: u64) -> YakvError {
+    YakvError::Any(anyhow!("table {}.sst is corrupted", id))
+}
@@ -0,0 +1,84 @@
+use crate::{Durability, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+// Every record is | length: u32 BE | crc32: u32 BE | body |, where the body
+// is the JSON (key, value) pair and a value of null removes the key
+const WAL_HEADER_LEN: usize = 8;
+
+pub(crate) fn wal_path(path: &Path, id: u64) -> PathBuf {
+    path.join(format!("{}.wal", id))
+}
+
+/// The write-ahead log of the memtable.
+pub(crate) struct Wal {
+    pub id: u64,
+    writer: BufWriter<File>,
+    durability: Durability,
+}
+
+impl Wal {
+    /// Creates a new empty log, whose appends are fsynced unless
+    /// `durability` is `Buffered`.
+    pub fn create(path: &Path, id: u64, durability: Durability) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(wal_path(path, id))?;
+        Ok(Wal {
+            id,
+            writer: BufWriter::new(file),
+            durability,
+        })
+    }
+
+    pub fn append(&mut self, key: &str, value: Option<&str>) -> Result<()> {
+        let body = serde_json::to_vec(&(key, value))?;
+        let mut header = [0; WAL_HEADER_LEN];
+        header[..4].copy_from_slice(&(body.len() as u32).to_be_bytes());
+        header[4..].copy_from_slice(&crc32fast::hash(&body).to_be_bytes());
+        self.writer.write_all(&header)?;
+        self.writer.write_all(&body)?;
+        self.writer.flush()?;
+        if self.durability != Durability::Buffered {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the log to disk.
+    pub fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+}
+
+/// Returns the entries of log `id` in the order they were written.
+///
+/// A torn or corrupted record ends the log, since it can only be the last
+/// write before a crash.
+pub(crate) fn replay(path: &Path, id: u64) -> Result<Vec<(String, Option<String>)>> {
+    let mut reader = BufReader::new(File::open(wal_path(path, id))?);
+    let mut entries = Vec::new();
+    loop {
+        let mut header = [0; WAL_HEADER_LEN];
+        match reader.read_exact(&mut header) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            result => result?,
+        }
+        let len = u32::from_be_bytes(header[..4].try_into().unwrap()) as u64;
+        let crc = u32::from_be_bytes(header[4..].try_into().unwrap());
+        let mut body = Vec::new();
+        (&mut reader).take(len).read_to_end(&mut body)?;
+        if body.len() as u64 != len || crc32fast::hash(&body) != crc {
+            break;
+        }
+        match serde_json::from_slice(&body) {
+            Ok(entry) => entries.push(entry),
+            Err(_) => break,
+        }
+    }
+    Ok(entries)
+}
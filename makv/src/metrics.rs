@@ -0,0 +1,199 @@
+//! Metrics of makv-server in the Prometheus text format.
+//!
+//! Every front-end of the server times the requests it dispatches and counts
+//! the errors it sends by kind, so requests of every protocol are counted
+//! together. Sizes and compactions of the store come from `MakvEngine::stats`,
+//! for engines which have them.
+
+use crate::{MakvEngine, Result, StoreStats, YakvError};
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tiny_http::{Header, Method, Server};
+
+/// Upper bounds in seconds of the buckets of request durations.
+pub const REQUEST_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+
+/// Upper bounds in seconds of the buckets of compaction durations.
+pub const COMPACTION_BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0];
+
+/// Durations counted in buckets.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Histogram {
+    /// Upper bounds of the buckets in seconds, in increasing order
+    pub bounds: Vec<f64>,
+
+    /// Observations of every bucket, the last one for those above every bound
+    pub counts: Vec<u64>,
+
+    /// Sum of the observations in seconds
+    pub sum: f64,
+
+    /// Number of observations
+    pub count: u64,
+}
+
+impl Histogram {
+    /// Returns an empty histogram with the given bucket bounds.
+    pub fn new(bounds: &[f64]) -> Self {
+        Histogram {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    /// Counts one duration.
+    pub fn observe(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let bucket = self.bounds.partition_point(|&bound| bound < secs);
+        self.counts[bucket] += 1;
+        self.sum += secs;
+        self.count += 1;
+    }
+
+    // Writes the cumulative buckets, sum and count of a histogram metric
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name, labels, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}le=\"+Inf\"}} {}",
+            name, labels, self.count
+        );
+        let labels = match labels.trim_end_matches(',') {
+            "" => String::new(),
+            labels => format!("{{{}}}", labels),
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.count);
+    }
+}
+
+/// Metrics of the requests and connections of makv-server.
+#[derive(Default)]
+pub struct ServerMetrics {
+    // durations by command
+    requests: Mutex<BTreeMap<&'static str, Histogram>>,
+    // errors by kind
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+    active_connections: AtomicI64,
+    // connections waiting for a thread of the pool
+    queued_connections: AtomicI64,
+}
+
+impl ServerMetrics {
+    /// Counts a request of the command named `command` which took
+    /// `duration`.
+    pub fn observe_request(&self, command: &'static str, duration: Duration) {
+        self.requests
+            .lock()
+            .unwrap()
+            .entry(command)
+            .or_insert_with(|| Histogram::new(REQUEST_BUCKETS))
+            .observe(duration);
+    }
+
+    /// Counts an error sent to a client.
+    pub fn count_error(&self, e: &YakvError) {
+        *self.errors.lock().unwrap().entry(e.name()).or_insert(0) += 1;
+    }
+
+    /// Counts a connection with a request waiting for a thread when `queued`,
+    /// or taken by one.
+    pub fn queue_connection(&self, queued: bool) {
+        let delta = if queued { 1 } else { -1 };
+        self.queued_connections.fetch_add(delta, Ordering::Relaxed);
+    }
+
+    /// Counts a connection being opened when `active`, or closed.
+    pub fn serve_connection(&self, active: bool) {
+        let delta = if active { 1 } else { -1 };
+        self.active_connections.fetch_add(delta, Ordering::Relaxed);
+    }
+
+    /// Returns the metrics, and those of the store if there are `stats`.
+    pub fn render(&self, stats: Option<&StoreStats>) -> String {
+        let mut out = String::new();
+        out.push_str("# TYPE makv_request_duration_seconds histogram\n");
+        for (command, histogram) in self.requests.lock().unwrap().iter() {
+            let labels = format!("command=\"{}\",", command);
+            histogram.render(&mut out, "makv_request_duration_seconds", &labels);
+        }
+        out.push_str("# TYPE makv_errors_total counter\n");
+        for (kind, count) in self.errors.lock().unwrap().iter() {
+            let _ = writeln!(out, "makv_errors_total{{kind=\"{}\"}} {}", kind, count);
+        }
+        for (name, gauge) in &[
+            ("makv_active_connections", &self.active_connections),
+            ("makv_queued_connections", &self.queued_connections),
+        ] {
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "{} {}", name, gauge.load(Ordering::Relaxed));
+        }
+
+        let stats = match stats {
+            Some(stats) => stats,
+            None => return out,
+        };
+        for (name, kind, value) in &[
+            ("makv_keys", "gauge", stats.keys),
+            ("makv_live_bytes", "gauge", stats.live_bytes),
+            ("makv_stale_bytes", "gauge", stats.stale_bytes),
+            ("makv_generations", "gauge", stats.generations),
+            ("makv_compactions_total", "counter", stats.compactions),
+            (
+                "makv_compaction_reclaimed_bytes_total",
+                "counter",
+                stats.reclaimed_bytes,
+            ),
+            ("makv_log_syncs_total", "counter", stats.syncs),
+            ("makv_cache_hits_total", "counter", stats.cache.hits),
+            ("makv_cache_misses_total", "counter", stats.cache.misses),
+        ] {
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        out.push_str("# TYPE makv_compaction_duration_seconds histogram\n");
+        stats
+            .compaction_durations
+            .render(&mut out, "makv_compaction_duration_seconds", "");
+        out
+    }
+
+    /// Listens on `addr` and answers `GET /metrics` with the metrics of the
+    /// server and `store`.
+    pub fn start<E: MakvEngine>(self: Arc<Self>, addr: SocketAddr, store: E) -> Result<()> {
+        let server = Server::http(addr).map_err(|e| YakvError::Any(anyhow!(e)))?;
+        for request in server.incoming_requests() {
+            let response = match (request.method(), request.url()) {
+                (Method::Get, "/metrics") => {
+                    let body = self.render(store.stats().ok().as_ref());
+                    let content_type =
+                        Header::from_bytes("Content-Type", "text/plain; version=0.0.4")
+                            .expect("header is valid");
+                    tiny_http::Response::from_string(body).with_header(content_type)
+                }
+                _ => tiny_http::Response::from_string("Not found").with_status_code(404),
+            };
+            // the client went away, nothing left to do
+            let _ = request.respond(response);
+        }
+        Ok(())
+    }
+}
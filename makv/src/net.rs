@@ -0,0 +1,28 @@
+//! Connections of makv-server which wait for requests without a thread.
+//!
+//! Front-ends serve a connection on a thread of their pool while it has a
+//! request, and hand it back to a tokio runtime with `readable` in between,
+//! so that idle clients can't keep others from being served.
+
+use std::io;
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// Waits until the client sends more. Returns false if it was idle for
+/// `timeout` instead.
+pub async fn readable(stream: &TcpStream, timeout: Option<Duration>) -> io::Result<bool> {
+    // the clone shares the socket, and whether it blocks, with the stream
+    let watched = stream.try_clone()?;
+    watched.set_nonblocking(true)?;
+    let watched = tokio::net::TcpStream::from_std(watched)?;
+    let readable = match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, watched.readable()).await {
+            Ok(res) => res.map(|_| true),
+            Err(_) => Ok(false),
+        },
+        None => watched.readable().await.map(|_| true),
+    };
+    drop(watched);
+    stream.set_nonblocking(false)?;
+    readable
+}
@@ -1,8 +1,90 @@
-use crate::{Command, Result, YakvError};
-use anyhow::*;
+use crate::trace::SpanContext;
+use crate::{Command, Compression, Result, StoreStats, YakvError};
+use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
-use std::io::Read;
-use std::net::TcpStream;
+use std::io::{self, Read};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Largest payload `YakvMessage::new` accepts, 16 MiB.
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 16 * 1024 * 1024;
+
+// High bit of the length prefix, set when the payload is LZ4 compressed.
+// Only sent to peers which agreed on the "lz4" feature.
+const COMPRESSED_FRAME: u32 = 1 << 31;
+
+// Second highest bit of the length prefix, set when the payload starts with
+// the span context of the sender. Only sent to peers which agreed on the
+// "trace" feature.
+const TRACED_FRAME: u32 = 1 << 30;
+
+/// Protocol version spoken by this version of makv.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version accepted in a handshake.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Version of clients which send commands without a handshake first.
+///
+/// These clients speak the original protocol, which is still served as is.
+pub const LEGACY_PROTOCOL_VERSION: u32 = 0;
+
+/// Optional features the server supports.
+///
+/// - `auth`: `Command::Auth`, needed when the server has an ACL
+/// - `lz4`: frames may be LZ4 compressed, see `YakvMessage`
+/// - `trace`: commands may carry the span context of the client, see
+///   `YakvMessage`
+pub const FEATURES: &[&str] = &["auth", "lz4", "trace"];
+
+/// First frame of a connection, announcing the protocol version and the
+/// features a client wants.
+///
+/// The server answers with the version it will speak and the features it
+/// supports out of the requested ones, or with an `UnsupportedVersion` error
+/// after which it closes the connection.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    /// Protocol version
+    pub version: u32,
+
+    /// Names of optional features
+    pub features: Vec<String>,
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Handshake {
+            version: PROTOCOL_VERSION,
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+}
+
+impl Handshake {
+    /// Returns the handshake the server answers with.
+    pub fn negotiate(&self) -> Result<Handshake> {
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&self.version) {
+            return Err(YakvError::UnsupportedVersion(format!(
+                "client speaks version {}, server supports {} to {}",
+                self.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            )));
+        }
+        Ok(Handshake {
+            version: self.version,
+            features: self
+                .features
+                .iter()
+                .filter(|f| FEATURES.contains(&f.as_str()))
+                .cloned()
+                .collect(),
+        })
+    }
+
+    /// Returns true if the feature was agreed on.
+    pub fn has(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
 
 /// Used when sending response to client
 #[derive(Default, Serialize, Deserialize, Debug)]
@@ -11,6 +93,34 @@ pub struct Response {
     pub is_error: bool,
     pub error_msg: Option<String>,
     pub result: Option<String>,
+    /// Set for errors clients are expected to handle differently
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_kind: Option<ErrorKind>,
+    /// Set in the answer to a `Handshake` command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub handshake: Option<Handshake>,
+    /// Set in the answer to a `Stats` command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<Box<StoreStats>>,
+}
+
+/// Kinds of errors a `Response` can carry.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The connection did not authenticate, or the credentials were wrong.
+    /// The server closes the connection after sending this.
+    Unauthenticated,
+
+    /// The authenticated user may not access the key.
+    PermissionDenied,
+
+    /// The server does not speak the protocol version of the handshake.
+    /// The server closes the connection after sending this.
+    UnsupportedVersion,
+
+    /// The length prefix of a frame is larger than the server accepts.
+    /// The server closes the connection after sending this.
+    FrameTooLarge,
 }
 
 impl Response {
@@ -20,11 +130,73 @@ impl Response {
             is_error,
             error_msg,
             result: value,
+            error_kind: None,
+            handshake: None,
+            stats: None,
+        }
+    }
+
+    /// Returns the answer to a handshake.
+    pub fn handshake(handshake: Handshake) -> Self {
+        Response {
+            handshake: Some(handshake),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the answer to a `Stats` command.
+    pub fn stats(stats: StoreStats) -> Self {
+        Response {
+            stats: Some(Box::new(stats)),
+            ..Default::default()
+        }
+    }
+
+    /// Returns an error response for the given error.
+    pub fn from_error(e: &YakvError) -> Self {
+        let error_kind = match e {
+            YakvError::Unauthenticated(_) => Some(ErrorKind::Unauthenticated),
+            YakvError::PermissionDenied(_) => Some(ErrorKind::PermissionDenied),
+            YakvError::UnsupportedVersion(_) => Some(ErrorKind::UnsupportedVersion),
+            YakvError::FrameTooLarge { .. } => Some(ErrorKind::FrameTooLarge),
+            _ => None,
+        };
+        Response {
+            error_kind,
+            ..Response::new(true, Some(e.to_string()), None)
+        }
+    }
+
+    /// Returns true if the server closes the connection after this response.
+    pub fn closes_connection(&self) -> bool {
+        matches!(
+            self.error_kind,
+            Some(ErrorKind::Unauthenticated)
+                | Some(ErrorKind::UnsupportedVersion)
+                | Some(ErrorKind::FrameTooLarge)
+        )
+    }
+
+    /// Turns an error response into the matching error.
+    pub fn into_result(self) -> Result<Option<String>> {
+        if !self.is_error {
+            return Ok(self.result);
         }
+        let msg = self
+            .error_msg
+            .unwrap_or_else(|| "No error message provided".to_owned());
+        Err(match self.error_kind {
+            Some(ErrorKind::Unauthenticated) => YakvError::Unauthenticated(msg),
+            Some(ErrorKind::PermissionDenied) => YakvError::PermissionDenied(msg),
+            Some(ErrorKind::UnsupportedVersion) => YakvError::UnsupportedVersion(msg),
+            Some(ErrorKind::FrameTooLarge) => YakvError::ServerError(msg),
+            None => YakvError::ServerError(msg),
+        })
     }
 }
 
 /// Represents different Payload types.
+#[derive(Debug, Clone, Copy)]
 pub enum PayloadType {
     /// Command variant
     Command,
@@ -45,11 +217,30 @@ pub enum Payload {
 /// Since TCP needs a way to distinguish how many bytes are actually needed
 /// to read and write, a custom protocol helps us solve this problem.
 ///
-/// Each read or write to TcpStream will utilize YakvMessage.
+/// Each read or write to TcpStream will utilize YakvMessage. The same framing
+/// is used by the blocking `makv-client` and the async `MakvClient`.
 /// This struct has the length of the actual payload we are sending over the
 /// network. This lets the protocol know how much bytes it needs for the buffer.
 ///
 /// We can find out the length of the payload from first 4 bytes i.e. [u8; 4]
+///
+/// The length comes from the peer, so frames longer than a maximum size are
+/// rejected before anything is read, and the buffer only grows as payload
+/// bytes actually arrive.
+///
+/// If both sides agreed on the `lz4` feature, the high bit of the length may
+/// be set to mark an LZ4 compressed payload. The maximum frame size applies
+/// both before and after decompression. Readers always accept compressed
+/// frames; writers only send them to peers which asked for them.
+///
+/// Likewise with the `trace` feature, the second highest bit marks a payload
+/// which starts with the `SpanContext` of the sender (see
+/// `crate::trace`), followed by the possibly compressed command. The length
+/// includes the context.
+///
+/// A connection may start with a `Command::Handshake` to agree on a protocol
+/// version and features. Connections without one are served the original
+/// protocol (`LEGACY_PROTOCOL_VERSION`).
 #[derive(Debug)]
 pub struct YakvMessage {
     /// length of payload
@@ -57,6 +248,9 @@ pub struct YakvMessage {
 
     /// payload for the message
     pub payload: Payload,
+
+    /// span context the sender put in the frame
+    pub trace: Option<SpanContext>,
 }
 
 impl YakvMessage {
@@ -80,26 +274,171 @@ impl YakvMessage {
         Ok((len, len_bytes))
     }
 
-    fn get_stream_payload_bytes(mut stream: &TcpStream) -> Result<(u32, Vec<u8>)> {
+    /// Same as `get_len_payload_bytes`, but compresses payloads of at least
+    /// `threshold` bytes.
+    ///
+    /// Only use this with peers which agreed on the `lz4` feature.
+    pub fn get_compressed_len_payload_bytes(
+        payload: Payload,
+        threshold: usize,
+    ) -> Result<(u32, Vec<u8>)> {
+        let (len, bytes) = YakvMessage::get_len_payload_bytes(payload)?;
+        match Compression::Lz4.compress(&bytes[4..], threshold) {
+            Some(mut compressed) => {
+                let len = compressed.len() as u32;
+                let mut len_bytes = (len | COMPRESSED_FRAME).to_be_bytes().to_vec();
+                len_bytes.append(&mut compressed);
+                Ok((len, len_bytes))
+            }
+            None => Ok((len, bytes)),
+        }
+    }
+
+    /// Puts the span context `context` in front of the payload of `frame`, a
+    /// frame returned by `get_len_payload_bytes` or
+    /// `get_compressed_len_payload_bytes`.
+    ///
+    /// Only use this with peers which agreed on the `trace` feature.
+    pub fn traced(frame: Vec<u8>, context: SpanContext) -> Vec<u8> {
+        let prefix = u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]);
+        let prefix = (prefix + SpanContext::ENCODED_LEN as u32) | TRACED_FRAME;
+        let mut bytes = Vec::with_capacity(frame.len() + SpanContext::ENCODED_LEN);
+        bytes.extend_from_slice(&prefix.to_be_bytes());
+        bytes.extend_from_slice(&context.to_bytes());
+        bytes.extend_from_slice(&frame[4..]);
+        bytes
+    }
+
+    fn get_stream_payload_bytes<R: Read>(
+        mut stream: R,
+        max_frame_size: u32,
+    ) -> Result<(u32, Vec<u8>, Option<SpanContext>)> {
+        let mut len_buf: [u8; 4] = [0; 4];
+        stream.read_exact(&mut len_buf)?;
+        let (length, flags) = YakvMessage::check_length(len_buf, max_frame_size)?;
+        let mut payload_buf = Vec::new();
+        stream
+            .take(u64::from(length))
+            .read_to_end(&mut payload_buf)?;
+        YakvMessage::check_complete(length, &payload_buf)?;
+        YakvMessage::decode(length, payload_buf, flags, max_frame_size)
+    }
+
+    async fn get_async_stream_payload_bytes<R: AsyncRead + Unpin>(
+        stream: &mut R,
+        max_frame_size: u32,
+    ) -> Result<(u32, Vec<u8>, Option<SpanContext>)> {
         let mut len_buf: [u8; 4] = [0; 4];
-        let mut handle = stream.take(4);
-        if handle.limit() != 4 {
-            return Err(YakvError::Any(anyhow!("Payload needs to be 4 bytes long.")));
+        stream.read_exact(&mut len_buf).await?;
+        let (length, flags) = YakvMessage::check_length(len_buf, max_frame_size)?;
+        let mut payload_buf = Vec::new();
+        stream
+            .take(u64::from(length))
+            .read_to_end(&mut payload_buf)
+            .await?;
+        YakvMessage::check_complete(length, &payload_buf)?;
+        YakvMessage::decode(length, payload_buf, flags, max_frame_size)
+    }
+
+    // Returns the payload length and the flag bits of the prefix
+    fn check_length(len_buf: [u8; 4], max_frame_size: u32) -> Result<(u32, u32)> {
+        let prefix = u32::from_be_bytes(len_buf);
+        let flags = prefix & (COMPRESSED_FRAME | TRACED_FRAME);
+        let length = prefix & !flags;
+        if length > max_frame_size {
+            return Err(YakvError::FrameTooLarge {
+                length,
+                max: max_frame_size,
+            });
+        }
+        Ok((length, flags))
+    }
+
+    // Splits off the span context and decompresses the rest
+    fn decode(
+        length: u32,
+        mut buf: Vec<u8>,
+        flags: u32,
+        max_frame_size: u32,
+    ) -> Result<(u32, Vec<u8>, Option<SpanContext>)> {
+        let trace = if flags & TRACED_FRAME != 0 {
+            if buf.len() < SpanContext::ENCODED_LEN {
+                return Err(YakvError::Any(anyhow!(
+                    "traced frame of {} bytes is too short for a span context",
+                    buf.len()
+                )));
+            }
+            let context = SpanContext::from_bytes(&buf[..SpanContext::ENCODED_LEN])?;
+            buf.drain(..SpanContext::ENCODED_LEN);
+            Some(context)
+        } else {
+            None
+        };
+        let buf = if flags & COMPRESSED_FRAME != 0 {
+            Compression::Lz4.decompress(&buf, max_frame_size as usize)?
+        } else {
+            buf
+        };
+        Ok((length, buf, trace))
+    }
+
+    // The peer hung up in the middle of a frame
+    fn check_complete(length: u32, buf: &[u8]) -> Result<()> {
+        if buf.len() < length as usize {
+            return Err(YakvError::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed in the middle of a frame",
+            )));
         }
-        handle.read_exact(&mut len_buf)?;
-        let length = u32::from_be_bytes(len_buf);
-        let mut payload_buf = vec![0; length as usize];
-        stream.read_exact(&mut payload_buf)?;
-        Ok((length, payload_buf))
+        Ok(())
     }
 
-    /// Returns payload from TcpStream and handle different payload types.
-    pub fn new(stream: &TcpStream, ptype: PayloadType) -> Result<Self> {
-        let (length, buf) = YakvMessage::get_stream_payload_bytes(stream)?;
+    // Both the blocking and async readers decode payload bytes here so that
+    // the two can't drift apart.
+    fn from_payload_bytes(
+        length: u32,
+        buf: &[u8],
+        trace: Option<SpanContext>,
+        ptype: PayloadType,
+    ) -> Result<Self> {
         let payload = match ptype {
-            PayloadType::Command => Payload::Command(serde_json::from_slice::<Command>(&buf)?),
-            PayloadType::Response => Payload::Response(serde_json::from_slice(&buf)?),
+            PayloadType::Command => Payload::Command(serde_json::from_slice::<Command>(buf)?),
+            PayloadType::Response => Payload::Response(serde_json::from_slice(buf)?),
         };
-        Ok(YakvMessage { length, payload })
+        Ok(YakvMessage {
+            length,
+            payload,
+            trace,
+        })
+    }
+
+    /// Returns payload from a stream and handle different payload types.
+    ///
+    /// Frames larger than `DEFAULT_MAX_FRAME_SIZE` are rejected.
+    pub fn new<R: Read>(stream: R, ptype: PayloadType) -> Result<Self> {
+        YakvMessage::with_max_frame_size(stream, ptype, DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    /// Same as `YakvMessage::new` but rejects frames larger than `max_frame_size`.
+    ///
+    /// After a `FrameTooLarge` error the stream is in the middle of a frame and
+    /// can't be read from any more.
+    pub fn with_max_frame_size<R: Read>(
+        stream: R,
+        ptype: PayloadType,
+        max_frame_size: u32,
+    ) -> Result<Self> {
+        let (length, buf, trace) = YakvMessage::get_stream_payload_bytes(stream, max_frame_size)?;
+        YakvMessage::from_payload_bytes(length, &buf, trace, ptype)
+    }
+
+    /// Same as `YakvMessage::new` but reads from an async stream.
+    pub async fn new_async<R: AsyncRead + Unpin>(
+        stream: &mut R,
+        ptype: PayloadType,
+    ) -> Result<Self> {
+        let (length, buf, trace) =
+            YakvMessage::get_async_stream_payload_bytes(stream, DEFAULT_MAX_FRAME_SIZE).await?;
+        YakvMessage::from_payload_bytes(length, &buf, trace, ptype)
     }
 }
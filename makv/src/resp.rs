@@ -0,0 +1,526 @@
+use crate::metrics::ServerMetrics;
+use crate::net::readable;
+use crate::{MakvEngine, Result, SharedQueueThreadPool, ThreadPool, YakvError};
+use slog::{error, Logger};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+
+// How often keys with an expired TTL are removed in the background
+const EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// RESP2 (Redis protocol) front-end for a `MakvEngine`.
+///
+/// Supports `GET`, `SET`, `DEL`, `EXISTS`, `KEYS`, `SCAN`, `EXPIRE` and `PING`
+/// so that redis-cli, redis-benchmark and Redis client libraries can talk to
+/// the store.
+///
+/// TTLs set with `EXPIRE` (or `SET ... EX`) are kept in memory and are lost
+/// when the server restarts. Expired keys are removed from the engine by a
+/// background thread, so they eventually disappear for native clients too.
+///
+/// A connection only holds a thread of the pool while it has a command to
+/// serve, so more clients than threads can stay connected.
+#[derive(Clone)]
+pub struct RespServer<E: MakvEngine> {
+    store: E,
+    log: Logger,
+    metrics: Arc<ServerMetrics>,
+    expirations: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl<E: MakvEngine + Sync> RespServer<E> {
+    /// Returns a RESP front-end backed by the given store, logging errors of
+    /// the background thread to `log` and recording commands in `metrics`.
+    pub fn new(store: E, log: Logger, metrics: Arc<ServerMetrics>) -> Self {
+        RespServer {
+            store,
+            log,
+            metrics,
+            expirations: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Listens on `addr` and serves RESP connections using a pool of `threads`
+    /// threads. Lines and bulk strings longer than `max_frame_size` bytes
+    /// close the connection.
+    pub fn start(self, addr: SocketAddr, threads: u32, max_frame_size: u32) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        let idle = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("resp-idle-connections")
+            .enable_all()
+            .build()?;
+
+        let reaper = self.clone();
+        thread::Builder::new().spawn(move || loop {
+            thread::sleep(EXPIRE_INTERVAL);
+            if let Err(e) = reaper.remove_expired() {
+                error!(reaper.log, "Failed to remove expired keys: {}", e);
+            }
+        })?;
+
+        let connections = Arc::new(Connections {
+            server: self,
+            pool: SharedQueueThreadPool::new(threads)?,
+            idle: idle.handle().clone(),
+            max_frame_size: max_frame_size as u64,
+        });
+        for stream in listener.incoming().flatten() {
+            connections.wait(BufReader::new(stream));
+        }
+        Ok(())
+    }
+
+    // Serves one command. Returns false once the client hung up or sent `QUIT`.
+    fn serve_command(&self, reader: &mut BufReader<TcpStream>, max_len: u64) -> Result<bool> {
+        let args = match read_command(reader, max_len)? {
+            Some(args) => args,
+            None => return Ok(false),
+        };
+        let mut writer = BufWriter::new(reader.get_ref());
+        if !args.is_empty() {
+            let quit = args[0].eq_ignore_ascii_case("QUIT");
+            let command = command_name(&args[0]);
+            let start = Instant::now();
+            let reply = if quit {
+                Reply::Simple("OK".to_owned())
+            } else {
+                self.handle_command(args).unwrap_or_else(|e| {
+                    self.metrics.count_error(&e);
+                    Reply::Error(format!("ERR {}", e))
+                })
+            };
+            self.metrics.observe_request(command, start.elapsed());
+            reply.write_to(&mut writer)?;
+            writer.flush()?;
+            if quit {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn handle_command(&self, mut args: Vec<String>) -> Result<Reply> {
+        let name = args.remove(0).to_ascii_uppercase();
+        let reply = match (name.as_str(), args.len()) {
+            ("PING", 0) => Reply::Simple("PONG".to_owned()),
+            ("PING", 1) => Reply::Bulk(args.pop()),
+            ("GET", 1) => {
+                let key = args.remove(0);
+                if self.expire_if_needed(&key)? {
+                    Reply::Bulk(None)
+                } else {
+                    Reply::Bulk(self.store.get(key)?)
+                }
+            }
+            ("SET", n) if n >= 2 => self.set(args)?,
+            ("DEL", n) if n >= 1 => {
+                let mut removed = 0;
+                for key in args {
+                    if self.expire_if_needed(&key)? {
+                        continue;
+                    }
+                    self.expirations.lock().unwrap().remove(&key);
+                    match self.store.remove(key) {
+                        Ok(()) => removed += 1,
+                        Err(YakvError::NotFoundError(_)) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                Reply::Integer(removed)
+            }
+            ("EXISTS", n) if n >= 1 => {
+                let mut found = 0;
+                for key in args {
+                    if !self.expire_if_needed(&key)? && self.store.get(key)?.is_some() {
+                        found += 1;
+                    }
+                }
+                Reply::Integer(found)
+            }
+            ("KEYS", 1) => {
+                let (keys, _) = self.keys(&args[0], usize::MAX)?;
+                Reply::Array(
+                    keys.into_iter()
+                        .map(|(_, k)| Reply::Bulk(Some(k)))
+                        .collect(),
+                )
+            }
+            ("SCAN", n) if n >= 1 => self.scan(args)?,
+            ("EXPIRE", 2) => {
+                let key = args.remove(0);
+                let seconds = parse_integer(&args[0])?;
+                if self.expire_if_needed(&key)? || self.store.get(key.to_owned())?.is_none() {
+                    Reply::Integer(0)
+                } else if seconds <= 0 {
+                    self.expirations.lock().unwrap().remove(&key);
+                    self.store.remove(key)?;
+                    Reply::Integer(1)
+                } else {
+                    let deadline = Instant::now() + Duration::from_secs(seconds as u64);
+                    self.expirations.lock().unwrap().insert(key, deadline);
+                    Reply::Integer(1)
+                }
+            }
+            // redis-cli asks for command docs on startup
+            ("COMMAND", _) => Reply::Array(vec![]),
+            ("PING", _)
+            | ("GET", _)
+            | ("SET", _)
+            | ("DEL", _)
+            | ("EXISTS", _)
+            | ("KEYS", _)
+            | ("SCAN", _)
+            | ("EXPIRE", _) => Reply::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name.to_lowercase()
+            )),
+            _ => Reply::Error(format!("ERR unknown command '{}'", name.to_lowercase())),
+        };
+        Ok(reply)
+    }
+
+    // SET key value [EX seconds | PX milliseconds]
+    fn set(&self, mut args: Vec<String>) -> Result<Reply> {
+        let key = args.remove(0);
+        let value = args.remove(0);
+        let ttl = match args.len() {
+            0 => None,
+            2 => {
+                let n = parse_integer(&args[1])?;
+                if n <= 0 {
+                    return Ok(Reply::Error(
+                        "ERR invalid expire time in 'set' command".to_owned(),
+                    ));
+                }
+                match args[0].to_ascii_uppercase().as_str() {
+                    "EX" => Some(Duration::from_secs(n as u64)),
+                    "PX" => Some(Duration::from_millis(n as u64)),
+                    _ => return Ok(Reply::Error("ERR syntax error".to_owned())),
+                }
+            }
+            _ => return Ok(Reply::Error("ERR syntax error".to_owned())),
+        };
+
+        self.store.set(key.to_owned(), value)?;
+        let mut expirations = self.expirations.lock().unwrap();
+        match ttl {
+            Some(ttl) => expirations.insert(key, Instant::now() + ttl),
+            None => expirations.remove(&key),
+        };
+        Ok(Reply::Simple("OK".to_owned()))
+    }
+
+    // SCAN cursor [MATCH pattern] [COUNT count]
+    //
+    // The cursor is the number of keys already visited in key order.
+    fn scan(&self, mut args: Vec<String>) -> Result<Reply> {
+        let cursor = parse_integer(&args.remove(0))?;
+        if cursor < 0 {
+            return Ok(Reply::Error("ERR invalid cursor".to_owned()));
+        }
+        let mut pattern = "*".to_owned();
+        let mut count = DEFAULT_SCAN_COUNT;
+        let mut options = args.into_iter();
+        while let Some(option) = options.next() {
+            match (option.to_ascii_uppercase().as_str(), options.next()) {
+                ("MATCH", Some(p)) => pattern = p,
+                ("COUNT", Some(c)) => match parse_integer(&c)? {
+                    c if c >= 1 => count = c as usize,
+                    _ => return Ok(Reply::Error("ERR syntax error".to_owned())),
+                },
+                _ => return Ok(Reply::Error("ERR syntax error".to_owned())),
+            }
+        }
+
+        let cursor = cursor as usize;
+        let end = cursor.saturating_add(count);
+        let (keys, more) = self.keys(&pattern, end)?;
+        let keys: Vec<_> = keys
+            .into_iter()
+            .filter(|(pos, _)| *pos >= cursor)
+            .map(|(_, key)| Reply::Bulk(Some(key)))
+            .collect();
+        let next = if more { end } else { 0 };
+        Ok(Reply::Array(vec![
+            Reply::Bulk(Some(next.to_string())),
+            Reply::Array(keys),
+        ]))
+    }
+
+    // Returns live keys matching `pattern` among the first `limit` keys, each
+    // with its position in key order, and whether there are keys left.
+    fn keys(&self, pattern: &str, limit: usize) -> Result<(KeyList, bool)> {
+        const PAGE: usize = 1024;
+        let mut keys = Vec::new();
+        let mut visited = 0;
+        let mut start = String::new();
+        loop {
+            let want = PAGE.min(limit - visited);
+            // fetch one extra key to know where the next page starts
+            let mut page = self.store.scan(start, want + 1)?;
+            let next = if page.len() > want { page.pop() } else { None };
+            for key in page {
+                if glob_match(pattern.as_bytes(), key.as_bytes()) && !self.expire_if_needed(&key)? {
+                    keys.push((visited, key));
+                }
+                visited += 1;
+            }
+            match next {
+                Some(next) if visited < limit => start = next,
+                next => return Ok((keys, next.is_some())),
+            }
+        }
+    }
+
+    // Removes the key if its TTL has passed, returns whether it did.
+    fn expire_if_needed(&self, key: &str) -> Result<bool> {
+        let mut expirations = self.expirations.lock().unwrap();
+        match expirations.get(key) {
+            Some(deadline) if *deadline <= Instant::now() => {
+                expirations.remove(key);
+                drop(expirations);
+                match self.store.remove(key.to_owned()) {
+                    Ok(()) | Err(YakvError::NotFoundError(_)) => Ok(true),
+                    Err(e) => Err(e),
+                }
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn remove_expired(&self) -> Result<()> {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .expirations
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(key, _)| key.to_owned())
+            .collect();
+        for key in expired {
+            self.expire_if_needed(&key)?;
+        }
+        Ok(())
+    }
+}
+
+type KeyList = Vec<(usize, String)>;
+
+// The connections of a RESP front-end, which wait on the `idle` runtime
+// between commands
+struct Connections<E: MakvEngine> {
+    server: RespServer<E>,
+    pool: SharedQueueThreadPool,
+    idle: Handle,
+    max_frame_size: u64,
+}
+
+impl<E: MakvEngine + Sync> Connections<E> {
+    // Serves the connection once the client sends a command
+    fn wait(self: &Arc<Self>, reader: BufReader<TcpStream>) {
+        let connections = self.clone();
+        self.idle.spawn(async move {
+            // errors here only mean the client went away
+            if let Ok(true) = readable(reader.get_ref(), None).await {
+                let served = connections.clone();
+                connections.pool.spawn(move || served.serve(reader));
+            }
+        });
+    }
+
+    // Serves the commands the client has sent, then waits for more
+    fn serve(self: &Arc<Self>, mut reader: BufReader<TcpStream>) {
+        loop {
+            match self.server.serve_command(&mut reader, self.max_frame_size) {
+                Ok(true) if reader.buffer().is_empty() => return self.wait(reader),
+                Ok(true) => {}
+                // errors here only mean the client went away or broke the protocol
+                Ok(false) | Err(_) => return,
+            }
+        }
+    }
+}
+
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Reply::Simple(s) => write!(writer, "+{}\r\n", s),
+            Reply::Error(e) => write!(writer, "-{}\r\n", e),
+            Reply::Integer(n) => write!(writer, ":{}\r\n", n),
+            Reply::Bulk(None) => write!(writer, "$-1\r\n"),
+            Reply::Bulk(Some(s)) => write!(writer, "${}\r\n{}\r\n", s.len(), s),
+            Reply::Array(items) => {
+                write!(writer, "*{}\r\n", items.len())?;
+                for item in items {
+                    item.write_to(writer)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// Reads one command, either as a RESP array of bulk strings or as an inline
+// command, whose lines and strings are at most `max_len` bytes. Returns `None`
+// when the client closed the connection.
+fn read_command<R: BufRead>(reader: &mut R, max_len: u64) -> Result<Option<Vec<String>>> {
+    let line = match read_line(reader, max_len)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if !line.starts_with('*') {
+        return Ok(Some(
+            line.split_whitespace().map(ToOwned::to_owned).collect(),
+        ));
+    }
+
+    let n = parse_integer(&line[1..])?;
+    let mut args = Vec::new();
+    for _ in 0..n {
+        let header = read_line(reader, max_len)?.ok_or_else(unexpected_eof)?;
+        if !header.starts_with('$') {
+            return Err(protocol_error(format!("expected '$', got '{}'", header)));
+        }
+        let len = parse_integer(&header[1..])?;
+        if len < 0 || len as u64 > max_len {
+            return Err(protocol_error("invalid bulk length".to_owned()));
+        }
+        // read the string and its trailing CRLF without trusting `len` for allocation
+        let mut buf = Vec::new();
+        reader.take(len as u64 + 2).read_to_end(&mut buf)?;
+        if buf.len() != len as usize + 2 {
+            return Err(unexpected_eof());
+        }
+        buf.truncate(len as usize);
+        args.push(String::from_utf8(buf).map_err(|e| protocol_error(e.to_string()))?);
+    }
+    Ok(Some(args))
+}
+
+fn read_line<R: BufRead>(reader: &mut R, max_len: u64) -> Result<Option<String>> {
+    let mut line = Vec::new();
+    // room for the CRLF, so that the longest line is read whole
+    if reader.take(max_len + 2).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") && line.len() as u64 > max_len {
+        return Err(protocol_error("line too long".to_owned()));
+    }
+    let line = String::from_utf8(line).map_err(|e| protocol_error(e.to_string()))?;
+    Ok(Some(line.trim_end_matches(&['\r', '\n'][..]).to_owned()))
+}
+
+// Names a command in the metrics, without letting clients add labels
+fn command_name(name: &str) -> &'static str {
+    const COMMANDS: &[&str] = &[
+        "get", "set", "del", "exists", "keys", "scan", "expire", "ping", "command", "quit",
+    ];
+    COMMANDS
+        .iter()
+        .find(|command| command.eq_ignore_ascii_case(name))
+        .copied()
+        .unwrap_or("unknown")
+}
+
+fn parse_integer(s: &str) -> Result<i64> {
+    s.parse()
+        .map_err(|_| protocol_error("value is not an integer or out of range".to_owned()))
+}
+
+fn protocol_error(msg: String) -> YakvError {
+    YakvError::Io(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+fn unexpected_eof() -> YakvError {
+    YakvError::Io(io::ErrorKind::UnexpectedEof.into())
+}
+
+// Redis style glob matching: `*`, `?`, `[abc]`, `[^a-z]` and `\` escapes.
+//
+// On a mismatch the last `*` takes one more byte instead of trying every
+// split, so matching takes at most `pattern.len() * s.len()` steps.
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // where matching resumes after the last `*`: in the pattern and in `s`
+    let mut star = None;
+    while i < s.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, i));
+            continue;
+        }
+        match match_byte(&pattern[p..], s[i]) {
+            Some(len) => {
+                p += len;
+                i += 1;
+            }
+            None => match star {
+                Some((star_p, star_i)) => {
+                    p = star_p;
+                    i = star_i + 1;
+                    star = Some((star_p, i));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+// Matches `c` against the first element of `pattern`, which isn't `*`.
+// Returns the length of the element if it matches.
+fn match_byte(pattern: &[u8], c: u8) -> Option<usize> {
+    match pattern {
+        [] => None,
+        [b'?', ..] => Some(1),
+        [b'[', rest @ ..] => {
+            let (negate, mut class) = match rest.split_first() {
+                Some((b'^', r)) => (true, r),
+                _ => (false, rest),
+            };
+            let mut matched = false;
+            loop {
+                match class {
+                    // unterminated class
+                    [] => return None,
+                    [b']', r @ ..] => {
+                        class = r;
+                        break;
+                    }
+                    [b'\\', x, r @ ..] => {
+                        matched |= *x == c;
+                        class = r;
+                    }
+                    [lo, b'-', hi, r @ ..] if *hi != b']' => {
+                        matched |= *lo <= c && c <= *hi;
+                        class = r;
+                    }
+                    [x, r @ ..] => {
+                        matched |= *x == c;
+                        class = r;
+                    }
+                }
+            }
+            (matched != negate).then_some(pattern.len() - class.len())
+        }
+        [b'\\', x, ..] => (*x == c).then_some(2),
+        [x, ..] => (*x == c).then_some(1),
+    }
+}
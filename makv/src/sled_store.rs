@@ -0,0 +1,95 @@
+use crate::stats::RequestCounters;
+use crate::{MakvEngine, Result, StoreStats, YakvError};
+use anyhow::anyhow;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// The `SledStore` stores string key/value pairs in a sled database under
+/// `engine_sled_data`. Every write is flushed before it returns.
+///
+/// ```rust
+/// # use makv::{MakvEngine, Result, SledStore};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let store = SledStore::open(current_dir()?)?;
+/// store.set("key".to_owned(), "value".to_owned())?;
+/// let val = store.get("key".to_owned())?;
+/// assert_eq!(val, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct SledStore {
+    db: sled::Db,
+    requests: Arc<RequestCounters>,
+}
+
+impl SledStore {
+    /// Opens a SledStore with the given path.
+    pub fn open<T: Into<PathBuf>>(path: T) -> Result<Self> {
+        let path = path.into().join("engine_sled_data");
+        Ok(SledStore {
+            db: sled::open(path)?,
+            requests: Arc::new(RequestCounters::default()),
+        })
+    }
+}
+
+impl MakvEngine for SledStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        RequestCounters::count(&self.requests.sets, 1);
+        self.db.insert(key, value.as_bytes())?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        RequestCounters::count(&self.requests.gets, 1);
+        self.db.get(key)?.map(|value| to_string(&value)).transpose()
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        RequestCounters::count(&self.requests.removes, 1);
+        if self.db.remove(&key)?.is_none() {
+            return Err(YakvError::NotFoundError(key));
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn scan(&self, start: String, limit: usize) -> Result<Vec<String>> {
+        RequestCounters::count(&self.requests.scans, 1);
+        self.db
+            .range(start..)
+            .keys()
+            .take(limit)
+            .map(|key| to_string(&key?))
+            .collect()
+    }
+
+    /// Applies the sets atomically with one flush.
+    fn set_batch(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        RequestCounters::count(&self.requests.sets, pairs.len() as u64);
+        let mut batch = sled::Batch::default();
+        for (key, value) in pairs {
+            batch.insert(key.as_bytes(), value.as_bytes());
+        }
+        self.db.apply_batch(batch)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Counts the keys and requests. Sizes and compactions are left at 0.
+    fn stats(&self) -> Result<StoreStats> {
+        Ok(StoreStats {
+            keys: self.db.len() as u64,
+            requests: self.requests.stats(),
+            ..StoreStats::default()
+        })
+    }
+}
+
+fn to_string(bytes: &[u8]) -> Result<String> {
+    String::from_utf8(bytes.to_vec())
+        .map_err(|e| YakvError::Any(anyhow!("sled holds a non UTF-8 string: {}", e)))
+}
@@ -0,0 +1,81 @@
+use crate::metrics::Histogram;
+use crate::CacheStats;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Sizes and counters of a store, as returned by `MakvEngine::stats`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StoreStats {
+    /// Number of keys with a value
+    pub keys: u64,
+
+    /// Bytes of records holding the latest value of their key
+    pub live_bytes: u64,
+
+    /// Bytes of overwritten and removed records, which compaction drops
+    pub stale_bytes: u64,
+
+    /// Number of log files
+    pub generations: u64,
+
+    /// Seconds since the Unix epoch of the last compaction since the store
+    /// was opened
+    pub last_compaction: Option<u64>,
+
+    /// Number of compactions since the store was opened
+    pub compactions: u64,
+
+    /// Durations of the compactions since the store was opened
+    pub compaction_durations: Histogram,
+
+    /// Bytes of logs removed by compactions, less the bytes of the logs
+    /// written in their place
+    pub reclaimed_bytes: u64,
+
+    /// Number of fsyncs of written records since the store was opened. With
+    /// group or sync durability concurrent writes share fsyncs.
+    pub syncs: u64,
+
+    /// Counters of the value cache
+    pub cache: CacheStats,
+
+    /// Requests served since the store was opened
+    pub requests: RequestStats,
+}
+
+/// Number of requests of every kind an engine served.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestStats {
+    #[allow(missing_docs)]
+    pub gets: u64,
+    #[allow(missing_docs)]
+    pub sets: u64,
+    #[allow(missing_docs)]
+    pub removes: u64,
+    #[allow(missing_docs)]
+    pub scans: u64,
+}
+
+/// Request counters shared by the clones of an engine.
+#[derive(Default)]
+pub(crate) struct RequestCounters {
+    pub gets: AtomicU64,
+    pub sets: AtomicU64,
+    pub removes: AtomicU64,
+    pub scans: AtomicU64,
+}
+
+impl RequestCounters {
+    pub fn count(counter: &AtomicU64, requests: u64) {
+        counter.fetch_add(requests, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> RequestStats {
+        RequestStats {
+            gets: self.gets.load(Ordering::Relaxed),
+            sets: self.sets.load(Ordering::Relaxed),
+            removes: self.removes.load(Ordering::Relaxed),
+            scans: self.scans.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,529 @@
+//! Distributed tracing of requests, exported to an OpenTelemetry collector.
+//!
+//! `MakvClient` starts a span for every request and sends its context in the
+//! frame of the command (see `YakvMessage`), so the server's spans join the
+//! client's trace. Inside the server, spans around the store lock, log writes,
+//! flushes and compactions are children of the span current on the thread.
+//!
+//! Spans are only recorded once `install` was called. They are exported in
+//! batches as OTLP/HTTP JSON, to an endpoint like
+//! `http://localhost:4318/v1/traces`. Spans wait for the exporter in a
+//! bounded queue, and are dropped when it's full, so that a slow or
+//! unreachable collector never holds up requests.
+
+use crate::{Result, YakvError};
+use anyhow::anyhow;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender};
+use serde::Serialize;
+use slog::{warn, Logger};
+use std::cell::Cell;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Spans are exported at least this often
+const EXPORT_INTERVAL: Duration = Duration::from_millis(500);
+
+// Spans are exported as soon as this many are waiting
+const EXPORT_BATCH_SIZE: usize = 512;
+
+// Spans waiting to be exported, beyond which new ones are dropped
+const SPAN_QUEUE_SIZE: usize = 8 * EXPORT_BATCH_SIZE;
+
+// How long connecting to the collector, sending a batch or waiting for its
+// answer may take
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+static TRACER: OnceLock<Tracer> = OnceLock::new();
+
+thread_local! {
+    // the span entered on this thread, parent of new spans
+    static CURRENT: Cell<Option<SpanContext>> = const { Cell::new(None) };
+}
+
+/// Identifies a span and its trace across processes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpanContext {
+    /// Id of the trace, shared by every span of a request
+    pub trace_id: u128,
+
+    /// Id of the span
+    pub span_id: u64,
+}
+
+impl SpanContext {
+    /// Length of an encoded context.
+    pub const ENCODED_LEN: usize = 24;
+
+    /// Returns the context as the trace id and span id, big endian.
+    pub fn to_bytes(&self) -> [u8; SpanContext::ENCODED_LEN] {
+        let mut bytes = [0; SpanContext::ENCODED_LEN];
+        bytes[..16].copy_from_slice(&self.trace_id.to_be_bytes());
+        bytes[16..].copy_from_slice(&self.span_id.to_be_bytes());
+        bytes
+    }
+
+    /// Reads a context written by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != SpanContext::ENCODED_LEN {
+            return Err(YakvError::Any(anyhow!(
+                "span context of {} bytes, expected {}",
+                bytes.len(),
+                SpanContext::ENCODED_LEN
+            )));
+        }
+        let mut trace_id = [0; 16];
+        trace_id.copy_from_slice(&bytes[..16]);
+        let mut span_id = [0; 8];
+        span_id.copy_from_slice(&bytes[16..]);
+        Ok(SpanContext {
+            trace_id: u128::from_be_bytes(trace_id),
+            span_id: u64::from_be_bytes(span_id),
+        })
+    }
+}
+
+/// Role of a span in a request, as OpenTelemetry defines it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanKind {
+    /// Work inside a process
+    Internal,
+
+    /// Handling of a request from a client
+    Server,
+
+    /// A request sent to a server
+    Client,
+}
+
+/// A timed operation, recorded when dropped.
+///
+/// Spans made while tracing is off record nothing and cost next to nothing.
+pub struct Span {
+    data: Option<SpanData>,
+    // the span which was current before this one was entered
+    entered: Option<Option<SpanContext>>,
+}
+
+// A span which is being recorded
+struct SpanData {
+    name: &'static str,
+    kind: SpanKind,
+    context: SpanContext,
+    parent: Option<SpanContext>,
+    start: SystemTime,
+    end: Option<SystemTime>,
+    attributes: Vec<(&'static str, String)>,
+    error: Option<String>,
+}
+
+impl Span {
+    /// Returns the context to send to other processes, if tracing is on.
+    pub fn context(&self) -> Option<SpanContext> {
+        self.data.as_ref().map(|data| data.context)
+    }
+
+    /// Makes this the parent of spans started by `span` on this thread until
+    /// it's dropped.
+    ///
+    /// Spans must be dropped in the reverse order they were entered, on the
+    /// thread which entered them.
+    pub fn entered(mut self) -> Self {
+        if let Some(context) = self.context() {
+            self.entered = Some(CURRENT.with(|current| current.replace(Some(context))));
+        }
+        self
+    }
+
+    /// Adds a key and value describing the operation.
+    pub fn set_attribute(&mut self, key: &'static str, value: impl ToString) {
+        if let Some(data) = &mut self.data {
+            data.attributes.push((key, value.to_string()));
+        }
+    }
+
+    /// Marks the operation as failed.
+    pub fn set_error(&mut self, e: &YakvError) {
+        if let Some(data) = &mut self.data {
+            data.error = Some(e.to_string());
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(previous) = self.entered {
+            CURRENT.with(|current| current.set(previous));
+        }
+        if let (Some(mut data), Some(tracer)) = (self.data.take(), TRACER.get()) {
+            data.end.get_or_insert_with(SystemTime::now);
+            // never wait for the exporter, a full queue drops the span
+            if tracer.spans.try_send(Message::Span(data)).is_err() {
+                tracer.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Starts a span which is a child of `parent`, or of a new trace without one.
+///
+/// The span isn't entered, see `Span::entered`.
+pub fn start_span(name: &'static str, kind: SpanKind, parent: Option<SpanContext>) -> Span {
+    if TRACER.get().is_none() {
+        return Span {
+            data: None,
+            entered: None,
+        };
+    }
+    let context = SpanContext {
+        trace_id: parent.map_or_else(new_trace_id, |parent| parent.trace_id),
+        span_id: new_span_id(),
+    };
+    Span {
+        data: Some(SpanData {
+            name,
+            kind,
+            context,
+            parent,
+            start: SystemTime::now(),
+            end: None,
+            attributes: Vec::new(),
+            error: None,
+        }),
+        entered: None,
+    }
+}
+
+/// Starts and enters a span which is a child of the current span. Nothing is
+/// recorded if there is no current span.
+pub fn span(name: &'static str) -> Span {
+    match current() {
+        Some(parent) => start_span(name, SpanKind::Internal, Some(parent)).entered(),
+        None => Span {
+            data: None,
+            entered: None,
+        },
+    }
+}
+
+/// Records a span which already ended, e.g. time spent in a queue.
+pub fn record_span(
+    name: &'static str,
+    parent: Option<SpanContext>,
+    start: SystemTime,
+    end: SystemTime,
+) {
+    let mut span = start_span(name, SpanKind::Internal, parent);
+    if let Some(data) = &mut span.data {
+        data.start = start;
+        data.end = Some(end);
+    }
+}
+
+/// Returns the context of the span entered on this thread.
+pub fn current() -> Option<SpanContext> {
+    CURRENT.with(|current| current.get())
+}
+
+/// Starts exporting spans of the service `service` to the OTLP/HTTP JSON
+/// endpoint `endpoint`.
+///
+/// Only `http://` endpoints are supported. Failed exports and dropped spans
+/// are logged to `log`. Fails if tracing is already on.
+pub fn install(service: &str, endpoint: &str, log: Logger) -> Result<()> {
+    let endpoint = Endpoint::parse(endpoint)?;
+    let (tx, rx) = channel::bounded(SPAN_QUEUE_SIZE);
+    TRACER
+        .set(Tracer {
+            spans: tx,
+            dropped: AtomicU64::new(0),
+        })
+        .map_err(|_| YakvError::Any(anyhow!("tracing is already installed")))?;
+    let service = service.to_owned();
+    thread::Builder::new()
+        .name("trace-exporter".to_owned())
+        .spawn(move || export_spans(rx, service, endpoint, log))?;
+    Ok(())
+}
+
+/// Exports the spans which ended so far and waits until they're sent.
+pub fn flush() {
+    if let Some(tracer) = TRACER.get() {
+        let (tx, rx) = channel::bounded(1);
+        if tracer.spans.send(Message::Flush(tx)).is_ok() {
+            let _ = rx.recv();
+        }
+    }
+}
+
+struct Tracer {
+    spans: Sender<Message>,
+    // spans dropped because the queue was full, since the last export
+    dropped: AtomicU64,
+}
+
+enum Message {
+    Span(SpanData),
+    // export now and answer when done
+    Flush(Sender<()>),
+}
+
+// Where the collector listens
+struct Endpoint {
+    // host and port
+    addr: String,
+    path: String,
+}
+
+impl Endpoint {
+    fn parse(url: &str) -> Result<Self> {
+        let rest = url.strip_prefix("http://").ok_or_else(|| {
+            YakvError::Any(anyhow!("OTLP endpoint '{}' must start with http://", url))
+        })?;
+        let (addr, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, "/v1/traces"),
+        };
+        Ok(Endpoint {
+            addr: addr.to_owned(),
+            path: path.to_owned(),
+        })
+    }
+
+    // Posts a JSON body and checks that the collector accepted it
+    fn post(&self, body: &[u8]) -> Result<()> {
+        let mut stream = self.connect()?;
+        stream.set_read_timeout(Some(EXPORT_TIMEOUT))?;
+        stream.set_write_timeout(Some(EXPORT_TIMEOUT))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.path,
+            self.addr,
+            body.len()
+        )?;
+        stream.write_all(body)?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        let status = response.split(' ').nth(1).unwrap_or("");
+        if !status.starts_with('2') {
+            return Err(YakvError::Any(anyhow!(
+                "collector answered '{}'",
+                response.lines().next().unwrap_or("")
+            )));
+        }
+        Ok(())
+    }
+
+    // Connects to the first address of the collector which answers in time
+    fn connect(&self) -> Result<TcpStream> {
+        let mut last_error = None;
+        for addr in self.addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, EXPORT_TIMEOUT) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} has no address", self.addr),
+                )
+            })
+            .into())
+    }
+}
+
+// Batches spans until the batch is full or the interval is over
+fn export_spans(rx: Receiver<Message>, service: String, endpoint: Endpoint, log: Logger) {
+    let mut batch = Vec::new();
+    loop {
+        let flushed = match rx.recv_timeout(EXPORT_INTERVAL) {
+            Ok(Message::Span(span)) => {
+                batch.push(span);
+                if batch.len() < EXPORT_BATCH_SIZE {
+                    continue;
+                }
+                None
+            }
+            Ok(Message::Flush(done)) => Some(done),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        if !batch.is_empty() {
+            let request = ExportRequest::new(&service, batch.drain(..));
+            let result = serde_json::to_vec(&request)
+                .map_err(YakvError::from)
+                .and_then(|body| endpoint.post(&body));
+            if let Err(e) = result {
+                warn!(log, "Failed to export spans"; "error" => %e);
+            }
+        }
+        let dropped = TRACER
+            .get()
+            .map_or(0, |tracer| tracer.dropped.swap(0, Ordering::Relaxed));
+        if dropped > 0 {
+            warn!(log, "Dropped spans, the export queue was full"; "spans" => dropped);
+        }
+        if let Some(done) = flushed {
+            let _ = done.send(());
+        }
+    }
+}
+
+fn new_trace_id() -> u128 {
+    (u128::from(new_span_id()) << 64) | u128::from(new_span_id())
+}
+
+fn new_span_id() -> u64 {
+    // 0 is an invalid id
+    loop {
+        let id = OsRng.next_u64();
+        if id != 0 {
+            return id;
+        }
+    }
+}
+
+// The OTLP/HTTP JSON encoding of spans
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportRequest {
+    resource_spans: Vec<ResourceSpans>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceSpans {
+    resource: Resource,
+    scope_spans: Vec<ScopeSpans>,
+}
+
+#[derive(Serialize)]
+struct Resource {
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Serialize)]
+struct ScopeSpans {
+    scope: Scope,
+    spans: Vec<OtlpSpan>,
+}
+
+#[derive(Serialize)]
+struct Scope {
+    name: &'static str,
+    version: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OtlpSpan {
+    trace_id: String,
+    span_id: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    parent_span_id: String,
+    name: &'static str,
+    kind: u8,
+    start_time_unix_nano: String,
+    end_time_unix_nano: String,
+    attributes: Vec<KeyValue>,
+    status: Status,
+}
+
+#[derive(Serialize)]
+struct Status {
+    // 0 unset, 2 error
+    code: u8,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    message: String,
+}
+
+#[derive(Serialize)]
+struct KeyValue {
+    key: &'static str,
+    value: AnyValue,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AnyValue {
+    string_value: String,
+}
+
+impl KeyValue {
+    fn new(key: &'static str, value: String) -> Self {
+        KeyValue {
+            key,
+            value: AnyValue {
+                string_value: value,
+            },
+        }
+    }
+}
+
+impl ExportRequest {
+    fn new(service: &str, spans: impl Iterator<Item = SpanData>) -> Self {
+        ExportRequest {
+            resource_spans: vec![ResourceSpans {
+                resource: Resource {
+                    attributes: vec![KeyValue::new("service.name", service.to_owned())],
+                },
+                scope_spans: vec![ScopeSpans {
+                    scope: Scope {
+                        name: "makv",
+                        version: env!("CARGO_PKG_VERSION"),
+                    },
+                    spans: spans.map(OtlpSpan::from).collect(),
+                }],
+            }],
+        }
+    }
+}
+
+impl From<SpanData> for OtlpSpan {
+    fn from(data: SpanData) -> Self {
+        let nanos = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos()
+                .to_string()
+        };
+        OtlpSpan {
+            trace_id: format!("{:032x}", data.context.trace_id),
+            span_id: format!("{:016x}", data.context.span_id),
+            parent_span_id: data
+                .parent
+                .map(|parent| format!("{:016x}", parent.span_id))
+                .unwrap_or_default(),
+            name: data.name,
+            kind: match data.kind {
+                SpanKind::Internal => 1,
+                SpanKind::Server => 2,
+                SpanKind::Client => 3,
+            },
+            start_time_unix_nano: nanos(data.start),
+            end_time_unix_nano: nanos(data.end.unwrap_or(data.start)),
+            attributes: data
+                .attributes
+                .into_iter()
+                .map(|(key, value)| KeyValue::new(key, value))
+                .collect(),
+            status: match data.error {
+                Some(message) => Status { code: 2, message },
+                None => Status {
+                    code: 0,
+                    message: String::new(),
+                },
+            },
+        }
+    }
+}
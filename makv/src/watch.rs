@@ -0,0 +1,109 @@
+use crate::{MakvEngine, Result, StoreStats};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+// Events buffered per subscriber before it is considered too slow and dropped
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+/// A change made to a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(missing_docs)]
+pub enum WatchEvent {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+impl WatchEvent {
+    /// Returns the key this event is about.
+    pub fn key(&self) -> &str {
+        match self {
+            WatchEvent::Set { key, .. } | WatchEvent::Remove { key } => key,
+        }
+    }
+}
+
+/// Wraps a `MakvEngine` and publishes every successful change to subscribers.
+///
+/// Every front-end of a server should share one `WatchedEngine` so watchers
+/// see changes no matter which protocol made them. Events are published after
+/// the write returns, so concurrent writes to the same key may be seen in a
+/// different order than they were applied.
+///
+/// A subscriber which falls more than 1024 events behind is disconnected.
+#[derive(Clone)]
+pub struct WatchedEngine<E: MakvEngine> {
+    engine: E,
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+// key prefix and channel of a subscriber
+type Subscriber = (String, Sender<WatchEvent>);
+
+impl<E: MakvEngine> WatchedEngine<E> {
+    /// Returns an engine which publishes changes made to `engine`.
+    pub fn new(engine: E) -> Self {
+        WatchedEngine {
+            engine,
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Returns a receiver for changes to keys starting with `prefix`, which
+    /// can be awaited without holding a thread.
+    ///
+    /// The subscription ends when the receiver is dropped. The receiver is
+    /// closed when the subscriber falls too far behind.
+    pub fn subscribe(&self, prefix: String) -> Receiver<WatchEvent> {
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_BUFFER);
+        self.subscribers.lock().unwrap().push((prefix, tx));
+        rx
+    }
+
+    fn publish(&self, event: WatchEvent) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|(prefix, tx)| {
+            if !event.key().starts_with(prefix.as_str()) {
+                return true;
+            }
+            match tx.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) | Err(TrySendError::Closed(_)) => false,
+            }
+        });
+    }
+}
+
+impl<E: MakvEngine> MakvEngine for WatchedEngine<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        if self.subscribers.lock().unwrap().is_empty() {
+            return self.engine.set(key, value);
+        }
+        self.engine.set(key.to_owned(), value.to_owned())?;
+        self.publish(WatchEvent::Set { key, value });
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.engine.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.engine.remove(key.to_owned())?;
+        self.publish(WatchEvent::Remove { key });
+        Ok(())
+    }
+
+    fn scan(&self, start: String, limit: usize) -> Result<Vec<String>> {
+        self.engine.scan(start, limit)
+    }
+
+    fn backup(&self, dest: &Path) -> Result<()> {
+        self.engine.backup(dest)
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        self.engine.stats()
+    }
+}
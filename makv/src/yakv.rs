@@ -1,23 +1,157 @@
 use serde::{Deserialize, Serialize};
 use serde_json::{self, Deserializer};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
+use std::mem;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crate::{MakvEngine, Result, YakvError};
+use crate::cache::{CacheStats, ValueCache};
+use crate::hint::{self, Hint, HintWriter};
+use crate::index::{CommandPos, Index, IndexMode};
+use crate::metrics::{Histogram, COMPACTION_BUCKETS};
+use crate::stats::RequestCounters;
+use crate::trace;
+use crate::{
+    Compression, EncryptionKey, Handshake, MakvEngine, Result, StoreStats, YakvError,
+    DEFAULT_COMPRESSION_THRESHOLD,
+};
+use anyhow::anyhow;
+use slog::{error, o, Logger};
 
-// This constant is used for invoking log compaction
+// Default stale bytes which trigger a log compaction
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 
+// Start of log files made of framed records. Log files without it were
+// written before records had headers and hold plain JSON commands.
+pub(crate) const LOG_MAGIC: &[u8; 4] = b"YKV\x01";
+
+// Every record is | flags: u8 | length: u32 BE | body |, where the body is
+// the JSON command, compressed and then encrypted if the flags say so. With
+// RECORD_CHECKSUM a crc32 of the flags, length and body follows the length.
+// With RECORD_BOUND an encrypted body is authenticated along with the id of
+// its log and its position, so that it can't be moved or replayed elsewhere.
+pub(crate) const RECORD_HEADER_LEN: usize = 5;
+pub(crate) const RECORD_CHECKSUM_LEN: usize = 4;
+const RECORD_LZ4: u8 = 0x01;
+pub(crate) const RECORD_ENCRYPTED: u8 = 0x02;
+pub(crate) const RECORD_CHECKSUM: u8 = 0x04;
+const RECORD_BOUND: u8 = 0x08;
+
+// Commands reach the store in frames of less than 1 GiB
+const MAX_RECORD_LEN: usize = 1 << 30;
+
+// LZ4 expands a block at most this many times
+const MAX_LZ4_RATIO: usize = 255;
+
+/// Options for `KvStore::open_with`.
+#[derive(Debug, Clone)]
+pub struct StoreOptions {
+    /// Compression for new log records
+    pub compression: Compression,
+
+    /// Records smaller than this many bytes are stored uncompressed
+    pub compression_threshold: usize,
+
+    /// Key for encrypting new records, and decrypting existing ones
+    pub encryption_key: Option<EncryptionKey>,
+
+    /// Keys only used for decrypting records, e.g. after an interrupted
+    /// `KvStore::rotate_key`
+    pub previous_keys: Vec<EncryptionKey>,
+
+    /// Where the index of keys is kept
+    pub index: IndexMode,
+
+    /// Bytes of recently read keys and values to cache, 0 disables the cache
+    pub cache_size: usize,
+
+    /// When writes are fsynced
+    pub durability: Durability,
+
+    /// Bytes of stale records in the logs which trigger a compaction
+    pub compaction_threshold: u64,
+
+    /// Where compactions triggered by writes log their errors, since the
+    /// writes have already returned
+    pub log: Logger,
+}
+
+impl Default for StoreOptions {
+    fn default() -> Self {
+        StoreOptions {
+            compression: Compression::None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            encryption_key: None,
+            previous_keys: Vec::new(),
+            index: IndexMode::Memory,
+            cache_size: 0,
+            durability: Durability::Buffered,
+            compaction_threshold: COMPACTION_THRESHOLD,
+            log: Logger::root(slog::Discard, o!()),
+        }
+    }
+}
+
+/// When writes are fsynced to disk before they are acknowledged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Writes are handed to the OS but not fsynced. They survive a crash of
+    /// the process, but not of the machine.
+    Buffered,
+
+    /// Every write is fsynced before it returns. Concurrent writes queued
+    /// while a batch is written share the fsync of the next batch.
+    Sync,
+
+    /// Like `Sync`, but the writer which starts a batch waits up to `window`,
+    /// or until `max_bytes` are queued, for other writers to join it.
+    Group {
+        /// Longest time a write waits for others to join its batch
+        window: Duration,
+
+        /// Bytes queued which end the window early
+        max_bytes: u64,
+    },
+}
+
+impl Durability {
+    /// Group commit with a window of `window` and a limit of 1 MiB.
+    pub fn group(window: Duration) -> Self {
+        Durability::Group {
+            window,
+            max_bytes: 1024 * 1024,
+        }
+    }
+}
+
+impl StoreOptions {
+    pub(crate) fn decryption_keys(&self) -> impl Iterator<Item = &EncryptionKey> {
+        self.encryption_key.iter().chain(&self.previous_keys)
+    }
+}
+
 /// The `KvStore` stores string key/value pairs.
 ///
 /// Key/value pairs are persisted to disk in log files. Log files are named after
 /// monotonically increasing generation numbers with a `log` extension name.
-/// A `BTreeMap` in memory stores the keys and the value locations for fast query.
+/// A `BTreeMap` in memory stores the keys and the value locations for fast query,
+/// unless `StoreOptions::index` asks for an index paged to disk.
+///
+/// Log files are never appended to once a newer one exists. Such sealed logs
+/// get a `hint` file with the positions of their records, so opening a store
+/// doesn't need to read every value.
+///
+/// Recently read values can be kept in an LRU cache of
+/// `StoreOptions::cache_size` bytes. Writes to a key and compactions
+/// invalidate cached values.
+///
+/// Writes are fsynced according to `StoreOptions::durability`. If a flush or
+/// fsync fails, the store fails every request until it is reopened, since its
+/// index may point to records which never reached the log.
 ///
 /// ```rust
 /// # use yakv::{KvStore, Result};
@@ -31,32 +165,194 @@ const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 /// # }
 /// ```
 #[derive(Clone)]
-pub struct KvStore(Arc<Mutex<SharedKvStore>>);
+pub struct KvStore {
+    store: Arc<Mutex<SharedKvStore>>,
+    queue: Arc<WriteQueue>,
+    durability: Durability,
+    requests: Arc<RequestCounters>,
+}
+
+// Writes waiting to be appended. The first waiting writer becomes the leader
+// and appends every queued write in one batch, with one flush and fsync.
+struct WriteQueue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct QueueState {
+    // writes and their tickets, in the order they were queued
+    pending: Vec<(u64, Command)>,
+    pending_bytes: u64,
+    next_ticket: u64,
+    // results of appended writes, by ticket
+    done: HashMap<u64, Result<()>>,
+    leader: bool,
+}
 
 impl KvStore {
     /// Opens a KvStore with the given path.
     pub fn open<T: Into<PathBuf>>(path: T) -> Result<Self> {
-        Ok(Self(Arc::new(Mutex::new(SharedKvStore::open(path)?))))
+        KvStore::open_with(path, StoreOptions::default())
+    }
+
+    /// Opens a KvStore with the given path and options.
+    ///
+    /// Options only affect new records, so logs written with other options
+    /// can still be read.
+    pub fn open_with<T: Into<PathBuf>>(path: T, options: StoreOptions) -> Result<Self> {
+        Ok(KvStore {
+            durability: options.durability,
+            store: Arc::new(Mutex::new(SharedKvStore::open(path, options)?)),
+            queue: Arc::new(WriteQueue {
+                state: Mutex::new(QueueState::default()),
+                changed: Condvar::new(),
+            }),
+            requests: Arc::new(RequestCounters::default()),
+        })
+    }
+
+    /// Rewrites every log file with records encrypted under `key`.
+    ///
+    /// The current key becomes a previous key. If the rotation is interrupted,
+    /// open the store with `key` and the old key in `previous_keys`, and
+    /// rotate again.
+    pub fn rotate_key(&self, key: EncryptionKey) -> Result<()> {
+        let mut store = self.store.lock().unwrap();
+        if let Some(old_key) = store.options.encryption_key.replace(key) {
+            store.options.previous_keys.push(old_key);
+        }
+        store.compact(true)
+    }
+
+    /// Returns the counters of the value cache.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.store.lock().unwrap().cache.stats()
+    }
+
+    // Locks the store, timing the wait in a span
+    fn lock(&self) -> MutexGuard<'_, SharedKvStore> {
+        let _span = trace::span("store.lock");
+        self.store.lock().unwrap()
+    }
+
+    // Queues a set or remove of about `len` bytes and waits until it is
+    // appended
+    fn write(&self, cmd: Command, len: u64) -> Result<()> {
+        let queue = &self.queue;
+        let mut state = queue.state.lock().unwrap();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.pending.push((ticket, cmd));
+        state.pending_bytes += len;
+        queue.changed.notify_all();
+        loop {
+            if let Some(result) = state.done.remove(&ticket) {
+                return result;
+            }
+            if !state.leader {
+                break;
+            }
+            state = queue.changed.wait(state).unwrap();
+        }
+
+        // lead the next batch, which includes this write
+        state.leader = true;
+        if let Durability::Group { window, max_bytes } = self.durability {
+            let deadline = Instant::now() + window;
+            while state.pending_bytes < max_bytes {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                state = queue.changed.wait_timeout(state, deadline - now).unwrap().0;
+            }
+        }
+        let batch = mem::take(&mut state.pending);
+        state.pending_bytes = 0;
+        drop(state);
+
+        let results = self.lock().write_batch(batch);
+        let mut state = queue.state.lock().unwrap();
+        state.leader = false;
+        state.done.extend(results);
+        queue.changed.notify_all();
+        let result = state.done.remove(&ticket).expect("write is in the batch");
+        drop(state);
+        self.compact_if_stale();
+        result
+    }
+
+    // Compacts the logs if enough of them is stale. Runs once the writes
+    // which made them stale have their results, so errors are only logged.
+    fn compact_if_stale(&self) {
+        let mut store = self.lock();
+        if store.stale_data > store.options.compaction_threshold {
+            if let Err(e) = store.compact(false) {
+                error!(store.options.log, "Compaction failed"; "error" => %e);
+            }
+        }
     }
 }
 
 impl MakvEngine for KvStore {
     /// Sets a value for a given key.
     fn set(&self, key: String, value: String) -> Result<()> {
-        let mut store = self.0.lock().unwrap();
-        store.set(key, value)
+        RequestCounters::count(&self.requests.sets, 1);
+        let len = (key.len() + value.len()) as u64;
+        self.write(Command::set(key, value), len)
     }
 
     /// Gets a value for a given key.
     fn get(&self, key: String) -> Result<Option<String>> {
-        let mut store = self.0.lock().unwrap();
+        RequestCounters::count(&self.requests.gets, 1);
+        let mut store = self.lock();
         store.get(key)
     }
 
     /// Gets a value for a given key.
     fn remove(&self, key: String) -> Result<()> {
-        let mut store = self.0.lock().unwrap();
-        store.remove(key)
+        RequestCounters::count(&self.requests.removes, 1);
+        let len = key.len() as u64;
+        self.write(Command::remove(key), len)
+    }
+
+    /// Lists keys in order, starting from a given key.
+    fn scan(&self, start: String, limit: usize) -> Result<Vec<String>> {
+        RequestCounters::count(&self.requests.scans, 1);
+        let store = self.lock();
+        store.scan(start, limit)
+    }
+
+    /// Appends the sets as one batch, with one flush and fsync.
+    fn set_batch(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        RequestCounters::count(&self.requests.sets, pairs.len() as u64);
+        let batch = pairs
+            .into_iter()
+            .enumerate()
+            .map(|(ticket, (key, value))| (ticket as u64, Command::set(key, value)))
+            .collect();
+        let results = self.lock().write_batch(batch);
+        self.compact_if_stale();
+        results.into_iter().try_for_each(|(_, result)| result)
+    }
+
+    /// Copies the live log files to `dest`. Sealed logs and their hints are
+    /// hard linked, and the current log is copied up to its last write.
+    ///
+    /// Writes wait until the copy is done. Backups of encrypted stores need
+    /// the same keys to be opened.
+    fn backup(&self, dest: &Path) -> Result<()> {
+        let mut store = self.lock();
+        store.backup(dest)
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        let stats = self.store.lock().unwrap().stats()?;
+        Ok(StoreStats {
+            requests: self.requests.stats(),
+            ..stats
+        })
     }
 }
 
@@ -65,35 +361,79 @@ pub struct SharedKvStore {
     current_id: u64,
     writer: BufWriterWithPos<File>,
     readers: HashMap<u64, BufReaderWithPos<File>>,
-    index: BTreeMap<String, CommandPos>,
+    index: Index,
     stale_data: u64,
+    options: StoreOptions,
+    // ids of log files in the JSON format without record headers
+    legacy_ids: HashSet<u64>,
+    cache: ValueCache,
+    compactions: u64,
+    last_compaction: Option<SystemTime>,
+    compaction_durations: Histogram,
+    // bytes of removed logs less the bytes of the logs which replaced them
+    reclaimed_bytes: u64,
+    syncs: u64,
+    // why the store stopped taking requests after a failed flush or fsync,
+    // which left the index ahead of the log
+    poisoned: Option<String>,
 }
 
 impl SharedKvStore {
-    fn open<T: Into<PathBuf>>(path: T) -> Result<Self> {
+    fn open<T: Into<PathBuf>>(path: T, options: StoreOptions) -> Result<Self> {
         // try to load all log files in the given path
         // if it failed then create a log file with an id suffix-ed to the file
         // e.g. key-1.log, key-2.log, key-3.log, etc
-        // after loading all the logs, build the index in-memory
+        // after loading all the logs, build the index
         let mut path = path.into();
         path.push("engine_yakv_data");
         fs::create_dir_all(&path)?;
 
         let mut readers = HashMap::new();
-        let mut index = BTreeMap::new();
+        let mut index = Index::open(options.index, &path)?;
         let mut stale_data = 0;
+        let mut legacy_ids = HashSet::new();
 
         let ids = sorted_ids(&path)?;
         // println!("IDS: {:?}", ids);
         for &id in &ids {
             let mut reader = BufReaderWithPos::new(File::open(log_path(&path, id))?)?;
-            stale_data += load_log(id, &mut reader, &mut index)?;
+            let legacy = !has_magic(&mut reader)?;
+            let log_len = reader.seek(SeekFrom::End(0))?;
+            // every existing log is sealed since a new one is created below
+            match hint::read_hints(&path, id, log_len, &options) {
+                Some(hints) => {
+                    for hint in hints {
+                        stale_data += index_hint(id, hint?, &mut index)?;
+                    }
+                }
+                None => {
+                    let mut hints = HintWriter::create(&path, id, &options)?;
+                    let mut add = |hint: Hint| -> Result<()> {
+                        hints.write(&hint)?;
+                        stale_data += index_hint(id, hint, &mut index)?;
+                        Ok(())
+                    };
+                    if legacy {
+                        load_legacy_log(&mut reader, &mut add)?;
+                    } else {
+                        load_log(&mut reader, id, &options, &mut add)?;
+                    }
+                    hints.finish(log_len)?;
+                }
+            }
+            if legacy {
+                legacy_ids.insert(id);
+            }
             readers.insert(id, reader);
         }
 
         let current_id = ids.last().unwrap_or(&0) + 1;
         let writer = create_log_file(current_id, &path, &mut readers)?;
+        if options.durability != Durability::Buffered {
+            sync_dir(&path)?;
+        }
 
+        let cache = ValueCache::new(options.cache_size);
         Ok(SharedKvStore {
             path,
             current_id,
@@ -101,30 +441,98 @@ impl SharedKvStore {
             readers,
             index,
             stale_data,
+            options,
+            legacy_ids,
+            cache,
+            compactions: 0,
+            last_compaction: None,
+            compaction_durations: Histogram::new(COMPACTION_BUCKETS),
+            reclaimed_bytes: 0,
+            syncs: 0,
+            poisoned: None,
         })
     }
 
-    fn compact(&mut self) -> Result<()> {
+    // Copies live records to a new log file and removes the old ones. With
+    // `rewrite` every record is written again with the current options;
+    // otherwise only legacy JSON commands and encrypted records are.
+    fn compact(&mut self, rewrite: bool) -> Result<()> {
+        self.check_poisoned()?;
+        let _span = trace::span("store.compaction");
+        let start = Instant::now();
         // increment id by 1
         // this will be used by compaction writer
         let compaction_id = self.current_id + 1;
-        self.current_id += 2;
-        self.writer = create_log_file(self.current_id, &self.path, &mut self.readers)?;
         let mut compaction_writer = create_log_file(compaction_id, &self.path, &mut self.readers)?;
+        // writes go on to the current log if the next one can't be created
+        self.writer = create_log_file(compaction_id + 1, &self.path, &mut self.readers)?;
+        self.current_id = compaction_id + 1;
 
-        let mut new_pos = 0;
-        for cmd_pos in &mut self.index.values_mut() {
-            let cmd_reader = self.readers.get_mut(&cmd_pos.id).expect("reader not found");
+        let mut hints = HintWriter::create(&self.path, compaction_id, &self.options)?;
+        let readers = &mut self.readers;
+        let legacy_ids = &self.legacy_ids;
+        let options = &self.options;
+        self.index.update_all(|key, cmd_pos| {
+            let cmd_reader = readers.get_mut(&cmd_pos.id).expect("reader not found");
             if cmd_reader.pos != cmd_pos.pos {
                 cmd_reader.seek(SeekFrom::Start(cmd_pos.pos))?;
             }
 
+            let new_pos = compaction_writer.pos;
             let mut cmd_reader = cmd_reader.take(cmd_pos.len);
-            let len = io::copy(&mut cmd_reader, &mut compaction_writer)?;
-            *cmd_pos = CommandPos::from((compaction_id, new_pos..new_pos + len));
-            new_pos += len;
-        }
+            if legacy_ids.contains(&cmd_pos.id) {
+                // rewrite the command as a record
+                let cmd: Command = serde_json::from_reader(cmd_reader)?;
+                write_record(
+                    &mut compaction_writer,
+                    compaction_id,
+                    new_pos,
+                    &cmd,
+                    options,
+                )?;
+            } else {
+                let mut record = Vec::with_capacity(cmd_pos.len as usize);
+                cmd_reader.read_to_end(&mut record)?;
+                // encrypted records only decrypt where they were written
+                if rewrite
+                    || record
+                        .first()
+                        .is_some_and(|flags| flags & RECORD_ENCRYPTED != 0)
+                {
+                    let (cmd, _) = read_record(
+                        &mut &record[..],
+                        cmd_pos.len,
+                        cmd_pos.id,
+                        cmd_pos.pos,
+                        options,
+                    )?;
+                    write_record(
+                        &mut compaction_writer,
+                        compaction_id,
+                        new_pos,
+                        &cmd,
+                        options,
+                    )?;
+                } else {
+                    compaction_writer.write_all(&record)?;
+                }
+            }
+            *cmd_pos = CommandPos::from((compaction_id, new_pos..compaction_writer.pos));
+            hints.write(&Hint {
+                key: key.to_owned(),
+                pos: cmd_pos.pos,
+                len: cmd_pos.len,
+                removed: false,
+            })
+        })?;
         compaction_writer.flush()?;
+        if self.options.durability != Durability::Buffered {
+            // the compacted log must be on disk before the logs it replaces
+            // are removed
+            compaction_writer.sync()?;
+            sync_dir(&self.path)?;
+        }
+        hints.finish(compaction_writer.pos)?;
 
         let stale_ids: Vec<_> = self
             .readers
@@ -133,52 +541,141 @@ impl SharedKvStore {
             .cloned()
             .collect();
 
+        let mut removed_bytes = 0;
         for stale_id in stale_ids {
             self.readers.remove(&stale_id);
-            fs::remove_file(log_path(&self.path, stale_id))?;
+            self.legacy_ids.remove(&stale_id);
+            let stale_path = log_path(&self.path, stale_id);
+            removed_bytes += fs::metadata(&stale_path)?.len();
+            fs::remove_file(stale_path)?;
+            match fs::remove_file(hint::hint_path(&self.path, stale_id)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        if self.options.durability != Durability::Buffered {
+            sync_dir(&self.path)?;
         }
         self.stale_data = 0;
+        self.cache.clear();
+        self.compactions += 1;
+        self.last_compaction = Some(SystemTime::now());
+        self.compaction_durations.observe(start.elapsed());
+        self.reclaimed_bytes += removed_bytes.saturating_sub(compaction_writer.pos);
 
         Ok(())
     }
 }
 
 impl SharedKvStore {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self.index.insert(
-                key,
-                CommandPos::from((self.current_id, pos..self.writer.pos)),
-            ) {
-                self.stale_data += old_cmd.len;
+    /// Appends a batch of sets and removes with one flush, returning the
+    /// result of every write by ticket.
+    fn write_batch(&mut self, batch: Vec<(u64, Command)>) -> Vec<(u64, Result<()>)> {
+        if let Err(e) = self.check_poisoned() {
+            let message = e.to_string();
+            return batch
+                .into_iter()
+                .map(|(ticket, _)| (ticket, Err(YakvError::Any(anyhow!("{}", message)))))
+                .collect();
+        }
+        let mut span = trace::span("store.write");
+        span.set_attribute("records", batch.len());
+        let mut results: Vec<_> = batch
+            .into_iter()
+            .map(|(ticket, cmd)| (ticket, self.append(cmd)))
+            .collect();
+        drop(span);
+        if let Err(e) = self.commit_batch() {
+            // the writes may not be on disk, but the index points to them
+            self.poisoned = Some(e.to_string());
+            for (_, result) in &mut results {
+                if result.is_ok() {
+                    *result = Err(YakvError::Any(anyhow!("{}", e)));
+                }
             }
         }
+        results
+    }
 
-        // Handle log compaction
-        if self.stale_data > COMPACTION_THRESHOLD {
-            self.compact()?;
+    // Writes a set or remove record and updates the index, without flushing
+    fn append(&mut self, cmd: Command) -> Result<()> {
+        let (key, removed) = match &cmd {
+            Command::Set { key, .. } => (key.to_owned(), false),
+            Command::Remove { key } => (key.to_owned(), true),
+            _ => return Err(YakvError::UnexpectedCommand),
+        };
+        // check if key exist in index before deleting it from the log file
+        if removed && !self.index.contains_key(&key)? {
+            return Err(YakvError::NotFoundError(key));
         }
 
+        let pos = self.writer.pos;
+        write_record(&mut self.writer, self.current_id, pos, &cmd, &self.options)?;
+        self.cache.remove(&key);
+        let old_cmd = if removed {
+            self.index.remove(&key)?
+        } else {
+            let cmd_pos = CommandPos::from((self.current_id, pos..self.writer.pos));
+            self.index.insert(key, cmd_pos)?
+        };
+        self.stale_data += old_cmd.map_or(0, |old_cmd| old_cmd.len);
+        if removed {
+            // like on open, the remove itself is stale too
+            self.stale_data += self.writer.pos - pos;
+        }
+        Ok(())
+    }
+
+    // Hands a batch to the OS and fsyncs it unless writes are buffered
+    fn commit_batch(&mut self) -> Result<()> {
+        let span = trace::span("store.flush");
+        self.writer.flush()?;
+        if self.options.durability != Durability::Buffered {
+            self.writer.sync()?;
+            self.syncs += 1;
+        }
+        drop(span);
         Ok(())
     }
 
+    fn check_poisoned(&self) -> Result<()> {
+        match &self.poisoned {
+            Some(e) => Err(YakvError::Any(anyhow!(
+                "a write to the log failed ({}), reopen the store",
+                e
+            ))),
+            None => Ok(()),
+        }
+    }
+
     /// Gets the string value for a given key.
     fn get(&mut self, key: String) -> Result<Option<String>> {
-        // println!("{:?}", self.index);
-        if let Some(cmd_pos) = self.index.get(&key) {
+        self.check_poisoned()?;
+        if let Some(value) = self.cache.get(&key) {
+            return Ok(Some(value));
+        }
+        if let Some(cmd_pos) = self.index.get(&key)? {
             let reader = self
                 .readers
                 .get_mut(&cmd_pos.id)
                 .expect("Cannot find reader");
 
             reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-            let cmd_reader = reader.take(cmd_pos.len);
-            if let Command::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
+            let mut cmd_reader = reader.take(cmd_pos.len);
+            let cmd = if self.legacy_ids.contains(&cmd_pos.id) {
+                serde_json::from_reader(cmd_reader)?
+            } else {
+                read_record(
+                    &mut cmd_reader,
+                    cmd_pos.len,
+                    cmd_pos.id,
+                    cmd_pos.pos,
+                    &self.options,
+                )?
+                .0
+            };
+            if let Command::Set { value, .. } = cmd {
+                self.cache.insert(key, value.clone());
                 Ok(Some(value))
             } else {
                 Err(YakvError::UnexpectedCommand)
@@ -188,23 +685,82 @@ impl SharedKvStore {
         }
     }
 
-    /// Removes the given key.
-    fn remove(&mut self, key: String) -> Result<()> {
-        // check if key exist in index and delete if from the log file
-        if self.index.contains_key(&key) {
-            let cmd = Command::remove(key.to_owned());
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
-            let old_cmd = self.index.remove(&key).expect("Key not found");
-            self.stale_data += old_cmd.len;
-            Ok(())
-        } else {
-            Err(YakvError::NotFoundError(key))
+    /// Lists keys in order, starting from a given key.
+    fn scan(&self, start: String, limit: usize) -> Result<Vec<String>> {
+        self.check_poisoned()?;
+        self.index.scan(start, limit)
+    }
+
+    // Counts live bytes as the records of every log less the stale ones
+    fn stats(&self) -> Result<StoreStats> {
+        let mut record_bytes = 0;
+        for &id in self.readers.keys() {
+            let len = if id == self.current_id {
+                self.writer.pos
+            } else {
+                fs::metadata(log_path(&self.path, id))?.len()
+            };
+            record_bytes += if self.legacy_ids.contains(&id) {
+                len
+            } else {
+                len.saturating_sub(LOG_MAGIC.len() as u64)
+            };
+        }
+        Ok(StoreStats {
+            keys: self.index.len() as u64,
+            live_bytes: record_bytes.saturating_sub(self.stale_data),
+            stale_bytes: self.stale_data,
+            generations: self.readers.len() as u64,
+            last_compaction: self.last_compaction.map(|time| {
+                time.duration_since(UNIX_EPOCH)
+                    .map_or(0, |since_epoch| since_epoch.as_secs())
+            }),
+            compactions: self.compactions,
+            compaction_durations: self.compaction_durations.clone(),
+            reclaimed_bytes: self.reclaimed_bytes,
+            syncs: self.syncs,
+            cache: self.cache.stats(),
+            requests: Default::default(),
+        })
+    }
+
+    fn backup(&mut self, dest: &Path) -> Result<()> {
+        self.check_poisoned()?;
+        let dest = dest.join("engine_yakv_data");
+        if dest.exists() {
+            return Err(YakvError::Any(anyhow!(
+                "{} already holds a store",
+                dest.display()
+            )));
+        }
+        fs::create_dir_all(&dest)?;
+        self.writer.flush()?;
+
+        for &id in self.readers.keys() {
+            if id == self.current_id {
+                // the current log is still appended to, so copy what's written
+                let mut log = File::open(log_path(&self.path, id))?.take(self.writer.pos);
+                let mut copy = File::create(log_path(&dest, id))?;
+                io::copy(&mut log, &mut copy)?;
+                if self.options.durability != Durability::Buffered {
+                    copy.sync_data()?;
+                }
+            } else {
+                link_or_copy(&log_path(&self.path, id), &log_path(&dest, id))?;
+                let hint_path = hint::hint_path(&self.path, id);
+                if hint_path.exists() {
+                    link_or_copy(&hint_path, &hint::hint_path(&dest, id))?;
+                }
+            }
+        }
+        if self.options.durability != Durability::Buffered {
+            sync_dir(&dest)?;
         }
+        Ok(())
     }
 }
 
-fn log_path<T: AsRef<Path>>(path: T, id: u64) -> PathBuf {
+pub(crate) fn log_path<T: AsRef<Path>>(path: T, id: u64) -> PathBuf {
     path.as_ref().join(format!("{}.log", id))
 }
 
@@ -214,47 +770,218 @@ fn create_log_file(
     readers: &mut HashMap<u64, BufReaderWithPos<File>>,
 ) -> Result<BufWriterWithPos<File>> {
     let path = log_path(&path, id);
-    let writer = BufWriterWithPos::new(OpenOptions::new().create(true).append(true).open(&path)?)?;
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    // the log may be left over from a compaction which failed to start
+    file.seek(SeekFrom::End(0))?;
+    let mut writer = BufWriterWithPos::new(file)?;
+    if writer.pos == 0 {
+        writer.write_all(LOG_MAGIC)?;
+        writer.flush()?;
+    }
     readers.insert(id, BufReaderWithPos::new(File::open(&path)?)?);
     Ok(writer)
 }
 
-// load a log and build index
-fn load_log(
+// Hard links `src` to `dest`, or copies it if they are on different file
+// systems
+fn link_or_copy(src: &Path, dest: &Path) -> Result<()> {
+    if fs::hard_link(src, dest).is_err() {
+        fs::copy(src, dest)?;
+    }
+    Ok(())
+}
+
+// Makes created and removed files of a directory durable
+pub(crate) fn sync_dir(path: &Path) -> Result<()> {
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+fn has_magic(reader: &mut BufReaderWithPos<File>) -> Result<bool> {
+    reader.seek(SeekFrom::Start(0))?;
+    let mut magic = Vec::with_capacity(LOG_MAGIC.len());
+    reader
+        .take(LOG_MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+    Ok(magic == LOG_MAGIC)
+}
+
+// Appends a command as a record at `pos` of log `id`, compressing and
+// encrypting it if options say so
+pub(crate) fn write_record<W: Write>(
+    writer: &mut W,
     id: u64,
+    pos: u64,
+    cmd: &Command,
+    options: &StoreOptions,
+) -> Result<()> {
+    let json = serde_json::to_vec(cmd)?;
+    let (mut flags, mut body) = match options
+        .compression
+        .compress(&json, options.compression_threshold)
+    {
+        Some(compressed) => (RECORD_LZ4 | RECORD_CHECKSUM, compressed),
+        None => (RECORD_CHECKSUM, json),
+    };
+    if let Some(key) = &options.encryption_key {
+        flags |= RECORD_ENCRYPTED | RECORD_BOUND;
+        body = key.encrypt(&body, &record_aad(flags, id, pos));
+    }
+    let mut header = [0; RECORD_HEADER_LEN];
+    header[0] = flags;
+    header[1..].copy_from_slice(&(body.len() as u32).to_be_bytes());
+    writer.write_all(&header)?;
+    writer.write_all(&record_checksum(&header, &body).to_be_bytes())?;
+    writer.write_all(&body)?;
+    Ok(())
+}
+
+// Reads the record at `pos` of log `id` from the reader. The record may be at
+// most `max_len` bytes long, e.g. the rest of the log. Returns the command and
+// the length of the record.
+pub(crate) fn read_record<R: Read>(
+    reader: &mut R,
+    max_len: u64,
+    id: u64,
+    pos: u64,
+    options: &StoreOptions,
+) -> Result<(Command, u64)> {
+    let mut header = [0; RECORD_HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    let flags = header[0];
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as u64;
+    let mut checksum = None;
+    let mut record_len = RECORD_HEADER_LEN as u64 + len;
+    if flags & RECORD_CHECKSUM != 0 {
+        let mut crc = [0; RECORD_CHECKSUM_LEN];
+        reader.read_exact(&mut crc)?;
+        checksum = Some(u32::from_be_bytes(crc));
+        record_len += RECORD_CHECKSUM_LEN as u64;
+    }
+    // the length isn't trusted before the checksum is checked
+    if record_len > max_len {
+        return Err(YakvError::Any(anyhow!(
+            "record of {} bytes runs past the end of the log",
+            record_len
+        )));
+    }
+    let mut body = vec![0; len as usize];
+    reader.read_exact(&mut body)?;
+    if checksum.is_some_and(|checksum| checksum != record_checksum(&header, &body)) {
+        return Err(YakvError::Any(anyhow!("record checksum mismatch")));
+    }
+
+    if flags & RECORD_ENCRYPTED != 0 {
+        if options.encryption_key.is_none() && options.previous_keys.is_empty() {
+            return Err(YakvError::Any(anyhow!(
+                "log is encrypted but no encryption key was given"
+            )));
+        }
+        body = EncryptionKey::decrypt(
+            options.decryption_keys(),
+            &body,
+            &record_aad(flags, id, pos),
+        )?;
+    }
+    if flags & RECORD_LZ4 != 0 {
+        let max_size = body.len().saturating_mul(MAX_LZ4_RATIO).min(MAX_RECORD_LEN);
+        body = Compression::Lz4.decompress(&body, max_size)?;
+    }
+    Ok((serde_json::from_slice(&body)?, record_len))
+}
+
+// Data authenticated along with an encrypted body. Records written before
+// RECORD_BOUND only authenticate their flags.
+fn record_aad(flags: u8, id: u64, pos: u64) -> Vec<u8> {
+    let mut aad = vec![flags];
+    if flags & RECORD_BOUND != 0 {
+        aad.extend_from_slice(&id.to_be_bytes());
+        aad.extend_from_slice(&pos.to_be_bytes());
+    }
+    aad
+}
+
+fn record_checksum(header: &[u8; RECORD_HEADER_LEN], body: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(header);
+    hasher.update(body);
+    hasher.finalize()
+}
+
+// read a log of records and pass the position of each record to `f`
+fn load_log<F>(
     reader: &mut BufReaderWithPos<File>,
-    index: &mut BTreeMap<String, CommandPos>,
-) -> Result<u64> {
+    id: u64,
+    options: &StoreOptions,
+    mut f: F,
+) -> Result<()>
+where
+    F: FnMut(Hint) -> Result<()>,
+{
+    let end = reader.seek(SeekFrom::End(0))?;
+    let mut pos = reader.seek(SeekFrom::Start(LOG_MAGIC.len() as u64))?;
+    while pos < end {
+        let (cmd, len) = read_record(reader, end - pos, id, pos, options)?;
+        if let Some(hint) = command_hint(cmd, pos, len) {
+            f(hint)?;
+        }
+        pos += len;
+    }
+    Ok(())
+}
+
+// read a log of JSON commands and pass the position of each command to `f`
+fn load_legacy_log<F>(reader: &mut BufReaderWithPos<File>, mut f: F) -> Result<()>
+where
+    F: FnMut(Hint) -> Result<()>,
+{
     let mut pos = reader.seek(SeekFrom::Start(0))?;
     let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-    let mut stale_data = 0;
-    // println!("ID: {}", id);
     while let Some(cmd) = stream.next() {
         let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.insert(key, CommandPos::from((id, pos..new_pos))) {
-                    stale_data += old_cmd.len;
-                }
-            }
-            Command::Remove { key } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    stale_data += old_cmd.len;
-                }
-
-                stale_data += new_pos - pos;
-            }
-            Command::Get { key: _ } => {}
+        if let Some(hint) = command_hint(cmd?, pos, new_pos - pos) {
+            f(hint)?;
         }
         pos = new_pos;
     }
-    Ok(stale_data)
+    Ok(())
+}
+
+fn command_hint(cmd: Command, pos: u64, len: u64) -> Option<Hint> {
+    let (key, removed) = match cmd {
+        Command::Set { key, .. } => (key, false),
+        Command::Remove { key } => (key, true),
+        // only sets and removes are written to the log
+        Command::Get { .. }
+        | Command::Auth { .. }
+        | Command::Handshake(_)
+        | Command::Backup { .. }
+        | Command::Stats => return None,
+    };
+    Some(Hint {
+        key,
+        pos,
+        len,
+        removed,
+    })
+}
+
+// Updates the index with a record of log `id`. Returns the stale bytes.
+fn index_hint(id: u64, hint: Hint, index: &mut Index) -> Result<u64> {
+    let range = hint.pos..hint.pos + hint.len;
+    if hint.removed {
+        Ok(index.remove(&hint.key)?.map_or(0, |old_cmd| old_cmd.len) + hint.len)
+    } else {
+        Ok(index
+            .insert(hint.key, CommandPos::from((id, range)))?
+            .map_or(0, |old_cmd| old_cmd.len))
+    }
 }
 
 // get all ids from the log files in a given path
 //
 // Returns sorted id numbers
-fn sorted_ids(path: &Path) -> Result<Vec<u64>> {
+pub(crate) fn sorted_ids(path: &Path) -> Result<Vec<u64>> {
     let mut ids: Vec<u64> = fs::read_dir(&path)?
         .flat_map(|dir_entry| -> Result<_> { Ok(dir_entry?.path()) })
         .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
@@ -300,13 +1027,13 @@ impl<T: Read + Seek> Seek for BufReaderWithPos<T> {
     }
 }
 
-struct BufWriterWithPos<T: Write + Seek> {
+pub(crate) struct BufWriterWithPos<T: Write + Seek> {
     writer: BufWriter<T>,
-    pos: u64,
+    pub(crate) pos: u64,
 }
 
 impl<T: Write + Seek> BufWriterWithPos<T> {
-    fn new(mut file: T) -> Result<Self> {
+    pub(crate) fn new(mut file: T) -> Result<Self> {
         let pos = file.seek(SeekFrom::Current(0))?;
         Ok(BufWriterWithPos {
             writer: BufWriter::new(file),
@@ -315,6 +1042,14 @@ impl<T: Write + Seek> BufWriterWithPos<T> {
     }
 }
 
+impl BufWriterWithPos<File> {
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+}
+
 impl<T: Write + Seek> Write for BufWriterWithPos<T> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let len = self.writer.write(buf)?;
@@ -336,11 +1071,15 @@ impl<T: Write + Seek> Seek for BufWriterWithPos<T> {
 
 /// Represent KV store commands
 #[allow(missing_docs)]
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Command {
     Set { key: String, value: String },
     Remove { key: String },
     Get { key: String },
+    Auth { user: String, token: String },
+    Handshake(Handshake),
+    Backup { dest: String },
+    Stats,
 }
 
 impl Command {
@@ -358,24 +1097,35 @@ impl Command {
     pub fn get(key: String) -> Self {
         Command::Get { key }
     }
-}
 
-/// Position for Command in log file
-///
-/// Stores log file id, offset, and length
-#[derive(Debug)]
-struct CommandPos {
-    id: u64,
-    pos: u64,
-    len: u64,
-}
+    /// Return Command::Auth variant
+    pub fn auth(user: String, token: String) -> Self {
+        Command::Auth { user, token }
+    }
+
+    /// Return Command::Backup variant
+    pub fn backup(dest: String) -> Self {
+        Command::Backup { dest }
+    }
+
+    /// Returns the name of the command, e.g. for metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Set { .. } => "set",
+            Command::Remove { .. } => "remove",
+            Command::Get { .. } => "get",
+            Command::Auth { .. } => "auth",
+            Command::Handshake(_) => "handshake",
+            Command::Backup { .. } => "backup",
+            Command::Stats => "stats",
+        }
+    }
 
-impl From<(u64, Range<u64>)> for CommandPos {
-    fn from((id, range): (u64, Range<u64>)) -> Self {
-        CommandPos {
-            id,
-            pos: range.start,
-            len: range.end - range.start,
+    /// Returns the key the command reads or writes, if it has one.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Command::Set { key, .. } | Command::Remove { key } | Command::Get { key } => Some(key),
+            _ => None,
         }
     }
 }
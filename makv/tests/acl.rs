@@ -0,0 +1,134 @@
+use assert_cmd::prelude::*;
+use makv::{ClientOptions, MakvClient, Result, YakvError};
+use predicates::str::contains;
+use std::fs;
+use std::process::Command;
+use tempfile::TempDir;
+
+mod common;
+use common::{server_command, start_server, Server};
+
+const ACL: &str = r#"
+[[users]]
+name = "alice"
+token = "alice-token"
+read = [""]
+write = ["alice/"]
+
+[[users]]
+name = "bob"
+token = "bob-token"
+read = ["bob/"]
+"#;
+
+fn start_acl_server(temp_dir: &TempDir, addr: &str) -> Server {
+    fs::write(temp_dir.path().join("acl.toml"), ACL).unwrap();
+    start_server(temp_dir.path(), addr, &["--acl", "acl.toml"])
+}
+
+fn client(temp_dir: &TempDir, args: &[&str]) -> assert_cmd::assert::Assert {
+    Command::cargo_bin("makv-client")
+        .unwrap()
+        .args(args)
+        .env_remove("MAKV_TOKEN")
+        .current_dir(temp_dir)
+        .assert()
+}
+
+// makv-client should need valid credentials and only reach permitted prefixes
+#[test]
+fn cli_acl() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4151";
+    let server = start_acl_server(&temp_dir, addr);
+
+    client(&temp_dir, &["set", "alice/a", "1", "--addr", addr])
+        .failure()
+        .stderr(contains("first command must be auth"));
+    client(
+        &temp_dir,
+        &[
+            "set", "alice/a", "1", "--addr", addr, "--user", "alice", "--token", "wrong",
+        ],
+    )
+    .failure()
+    .stderr(contains("invalid user or token"));
+
+    let alice = ["--addr", addr, "--user", "alice", "--token", "alice-token"];
+    client(&temp_dir, &[&["set", "alice/a", "1"], &alice[..]].concat()).success();
+    client(&temp_dir, &[&["set", "bob/a", "1"], &alice[..]].concat())
+        .failure()
+        .stderr(contains("Permission denied"));
+    client(&temp_dir, &[&["get", "alice/a"], &alice[..]].concat())
+        .success()
+        .stdout("1\n");
+
+    // the token may also come from the environment
+    Command::cargo_bin("makv-client")
+        .unwrap()
+        .args(&["get", "alice/a", "--addr", addr, "--user", "bob"])
+        .env("MAKV_TOKEN", "bob-token")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Permission denied"));
+
+    drop(server);
+}
+
+// MakvClient should authenticate with its credentials and surface ACL errors
+#[tokio::test]
+async fn client_acl() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4152";
+    let server = start_acl_server(&temp_dir, addr);
+
+    let options = ClientOptions {
+        credentials: Some(("alice".to_owned(), "alice-token".to_owned())),
+        ..ClientOptions::default()
+    };
+    let mut alice = MakvClient::connect_with(addr, options).await?;
+    alice.set("alice/a".to_owned(), "1".to_owned()).await?;
+    assert_eq!(alice.get("alice/a".to_owned()).await?, Some("1".to_owned()));
+    match alice.set("bob/a".to_owned(), "1".to_owned()).await {
+        Err(YakvError::PermissionDenied(_)) => {}
+        res => panic!("expected permission denied, got {:?}", res),
+    }
+    // a denied request keeps the connection usable
+    assert_eq!(alice.get("alice/a".to_owned()).await?, Some("1".to_owned()));
+    // backups write files on the server, which takes writing every key
+    match alice.backup("backup".to_owned()).await {
+        Err(YakvError::PermissionDenied(_)) => {}
+        res => panic!("expected permission denied, got {:?}", res),
+    }
+
+    let options = ClientOptions {
+        credentials: Some(("alice".to_owned(), "wrong".to_owned())),
+        ..ClientOptions::default()
+    };
+    match MakvClient::connect_with(addr, options).await {
+        Err(YakvError::Unauthenticated(_)) => {}
+        res => panic!("expected unauthenticated, got {:?}", res.err()),
+    }
+
+    drop(server);
+    Ok(())
+}
+
+// Front-ends which don't check the ACL can't be served along with it
+#[test]
+fn acl_with_other_front_ends() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("acl.toml"), ACL).unwrap();
+    for front_end in &["--resp-addr", "--http-addr", "--grpc-addr"] {
+        server_command(temp_dir.path())
+            .args(&["--addr", "127.0.0.1:4202", "--acl", "acl.toml"])
+            .args(&[front_end, "127.0.0.1:4203"])
+            .assert()
+            .failure()
+            .stderr(contains(format!(
+                "--acl only applies to --addr and can't be used with {}",
+                front_end
+            )));
+    }
+}
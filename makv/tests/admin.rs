@@ -0,0 +1,281 @@
+use assert_cmd::prelude::*;
+use makv::inspect;
+use makv::{EncryptionKey, KvStore, MakvEngine, Result, SledStore, StoreOptions};
+use predicates::prelude::*;
+use std::fs;
+use std::process::Command;
+use tempfile::TempDir;
+
+mod common;
+use common::start_server;
+
+fn admin(temp_dir: &TempDir, args: &[&str]) -> assert_cmd::assert::Assert {
+    Command::cargo_bin("makv-admin")
+        .unwrap()
+        .args(args)
+        .env_remove("MAKV_ENCRYPTION_KEY")
+        .current_dir(temp_dir)
+        .assert()
+}
+
+fn open_with_key(temp_dir: &TempDir, key: Option<EncryptionKey>) -> Result<KvStore> {
+    KvStore::open_with(
+        temp_dir.path(),
+        StoreOptions {
+            encryption_key: key,
+            ..StoreOptions::default()
+        },
+    )
+}
+
+// `makv-admin rotate-key` should encrypt a store and then move it to a new key
+#[test]
+fn cli_rotate_key() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let gen_key = |name: &str| {
+        let output = admin(&temp_dir, &["gen-key"])
+            .success()
+            .get_output()
+            .clone();
+        let path = temp_dir.path().join(name);
+        fs::write(&path, &output.stdout).unwrap();
+        (
+            path,
+            EncryptionKey::from_hex(&String::from_utf8_lossy(&output.stdout)).unwrap(),
+        )
+    };
+    let (path1, key1) = gen_key("key1.hex");
+    let (path2, key2) = gen_key("key2.hex");
+
+    admin(
+        &temp_dir,
+        &["rotate-key", "--new-key-file", path1.to_str().unwrap()],
+    )
+    .success();
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(open_with_key(&temp_dir, Some(key1.clone()))?);
+
+    Command::cargo_bin("makv-admin")
+        .unwrap()
+        .args(&["rotate-key", "--new-key-file", path2.to_str().unwrap()])
+        .env("MAKV_ENCRYPTION_KEY", key1.to_hex())
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert!(open_with_key(&temp_dir, Some(key1)).is_err());
+    let store = open_with_key(&temp_dir, Some(key2))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// `makv-admin export` and `import` should move every pair between engines,
+// in both formats
+#[test]
+fn cli_export_import() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let yakv_dir = temp_dir.path().join("yakv");
+    let sled_dir = temp_dir.path().join("sled");
+    let pairs = [
+        ("plain", "value"),
+        ("comma,key", "quoted \"value\""),
+        ("multi\nline", "\r\nä,\"\""),
+        ("empty", ""),
+    ];
+    let store = KvStore::open(&yakv_dir)?;
+    for (key, value) in &pairs {
+        store.set(key.to_string(), value.to_string())?;
+    }
+    for key_id in 0..2500 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key7".to_owned())?;
+    drop(store);
+
+    for format in &["jsonl", "csv"] {
+        let export = temp_dir.path().join(format!("export.{}", format));
+        admin(
+            &temp_dir,
+            &[
+                "export",
+                "--dir",
+                yakv_dir.to_str().unwrap(),
+                "--format",
+                format,
+                "--output",
+                export.to_str().unwrap(),
+            ],
+        )
+        .success();
+        admin(
+            &temp_dir,
+            &[
+                "import",
+                "--dir",
+                sled_dir.to_str().unwrap(),
+                "--engine",
+                "sled",
+                "--format",
+                format,
+                "--input",
+                export.to_str().unwrap(),
+                "--batch-size",
+                "100",
+            ],
+        )
+        .success();
+
+        let sled = SledStore::open(&sled_dir)?;
+        for (key, value) in &pairs {
+            assert_eq!(sled.get(key.to_string())?.as_deref(), Some(*value));
+        }
+        assert_eq!(sled.get("key7".to_owned())?, None);
+        assert_eq!(
+            sled.get("key2499".to_owned())?,
+            Some("value2499".to_owned())
+        );
+        assert_eq!(sled.scan("".to_owned(), usize::MAX)?.len(), 2503);
+        drop(sled);
+        fs::remove_dir_all(&sled_dir)?;
+    }
+
+    // a CSV without the header is rejected
+    let bad = temp_dir.path().join("bad.csv");
+    fs::write(&bad, "key1,value1\n")?;
+    admin(
+        &temp_dir,
+        &[
+            "import",
+            "--dir",
+            sled_dir.to_str().unwrap(),
+            "--engine",
+            "sled",
+            "--format",
+            "csv",
+            "--input",
+            bad.to_str().unwrap(),
+        ],
+    )
+    .failure();
+    Ok(())
+}
+
+// `makv-admin migrate` should copy a store to another engine and make the
+// server use that engine from then on
+#[test]
+fn cli_migrate() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1500 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key7".to_owned())?;
+    drop(store);
+
+    admin(&temp_dir, &["migrate", "--from", "yakv", "--to", "yakv"]).failure();
+    admin(&temp_dir, &["migrate", "--from", "sled", "--to", "yakv"]).failure();
+    admin(&temp_dir, &["migrate", "--from", "yakv", "--to", "sled"])
+        .success()
+        .stdout("Migrated 1499 keys from yakv to sled\n");
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("ENGINE"))?,
+        "sled\n"
+    );
+
+    let sled = SledStore::open(temp_dir.path())?;
+    assert_eq!(sled.get("key7".to_owned())?, None);
+    assert_eq!(
+        sled.get("key1499".to_owned())?,
+        Some("value1499".to_owned())
+    );
+    assert_eq!(sled.scan("".to_owned(), usize::MAX)?.len(), 1499);
+    drop(sled);
+
+    // yakv is no longer active, and sled already holds the keys
+    admin(&temp_dir, &["migrate", "--from", "yakv", "--to", "sled"]).failure();
+    Command::cargo_bin("makv-server")
+        .unwrap()
+        .args(&["--engine", "yakv", "--addr", "127.0.0.1:4191"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    // the server serves the migrated keys
+    let addr = "127.0.0.1:4205";
+    let server = start_server(temp_dir.path(), addr, &["--engine", "sled"]);
+    Command::cargo_bin("makv-client")
+        .unwrap()
+        .args(&["get", "key1499", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1499\n");
+    drop(server);
+    Ok(())
+}
+
+// `makv-admin inspect` should find a cut log, and `repair` should salvage the
+// records before the cut
+#[test]
+fn cli_inspect_repair() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    let generations = inspect::inspect(temp_dir.path(), &StoreOptions::default())?;
+    assert_eq!(generations.len(), 2);
+    assert_eq!((generations[0].sets, generations[0].removes), (3, 1));
+    assert_eq!((generations[1].sets, generations[1].removes), (1, 0));
+    assert!(generations[0].hint && !generations[1].hint);
+    for generation in &generations {
+        assert!(generation.corruption.is_none());
+        assert_eq!(
+            generation.live_bytes + generation.stale_bytes + 4,
+            generation.size
+        );
+    }
+    assert!(generations[0].stale_bytes > generations[0].live_bytes);
+    assert_eq!(generations[1].stale_bytes, 0);
+
+    admin(&temp_dir, &["inspect"])
+        .success()
+        .stdout(predicate::str::contains("2 generations"));
+    admin(&temp_dir, &["inspect", "--dump", "--generation", "2"])
+        .success()
+        .stdout(predicate::str::contains(
+            r#""Set":{"key":"key3","value":"value4"}"#,
+        ));
+
+    // cut the remove of key2 in half
+    let log = temp_dir.path().join("engine_yakv_data").join("1.log");
+    let len = fs::metadata(&log)?.len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 5)?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    admin(&temp_dir, &["inspect"]).failure().stdout(
+        predicate::str::contains("1.log: 1").and(predicate::str::contains("damaged at byte")),
+    );
+
+    admin(&temp_dir, &["repair"])
+        .success()
+        .stdout(predicate::str::contains("Wrote 3 keys to 3.log"));
+    assert!(log.with_extension("log.damaged").exists());
+    admin(&temp_dir, &["inspect"]).success();
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
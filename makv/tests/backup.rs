@@ -0,0 +1,96 @@
+use assert_cmd::prelude::*;
+use makv::{KvStore, MakvEngine, Result};
+use predicates::str::contains;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::process::Command;
+use tempfile::TempDir;
+
+mod common;
+use common::start_server;
+
+// A backup should hold the store as it was when taken, and open as a store
+#[test]
+fn backup_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // reopening seals the first log
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.backup(backup_dir.path())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    // a second backup can't overwrite the first one
+    assert!(store.backup(backup_dir.path()).is_err());
+
+    // sealed logs are hard links to the live ones
+    let sealed_log = backup_dir.path().join("engine_yakv_data").join("1.log");
+    assert_eq!(fs::metadata(&sealed_log)?.nlink(), 2);
+
+    let backup = KvStore::open(backup_dir.path())?;
+    assert_eq!(backup.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(backup.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // the backup and the store change independently
+    backup.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// `makv-client backup` should back up a running server into --backup-dir
+#[test]
+fn cli_backup() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4181";
+    let server = start_server(
+        temp_dir.path(),
+        addr,
+        &["--backup-dir", backup_dir.path().to_str().unwrap()],
+    );
+
+    let client = |addr: &str, args: &[&str]| {
+        Command::cargo_bin("makv-client")
+            .unwrap()
+            .args(args)
+            .args(&["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+    };
+    client(addr, &["set", "key1", "value1"]).success();
+    client(addr, &["backup", "backup"]).success();
+    client(addr, &["backup", "backup"]).failure();
+    client(addr, &["set", "key1", "value2"]).success();
+
+    // clients can't write outside of --backup-dir
+    let outside = temp_dir.path().join("outside");
+    for dest in &[
+        outside.to_str().unwrap(),
+        "../outside",
+        "backups/../../outside",
+    ] {
+        client(addr, &["backup", dest])
+            .failure()
+            .stderr(contains("must be a relative path without '..'"));
+    }
+    assert!(!outside.exists());
+    drop(server);
+
+    // without --backup-dir there are no backups
+    let addr = "127.0.0.1:4204";
+    let server = start_server(temp_dir.path(), addr, &[]);
+    client(addr, &["backup", "backup"])
+        .failure()
+        .stderr(contains("backups are disabled"));
+    drop(server);
+
+    let backup = KvStore::open(backup_dir.path().join("backup"))?;
+    assert_eq!(backup.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,214 @@
+use assert_cmd::prelude::*;
+use makv::{ClientOptions, MakvClient, Result};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::Command;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+use common::{server_command, spawn_server, start_server};
+
+// Should get, set and remove keys over a single reused connection
+#[tokio::test]
+async fn client_get_set_remove() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4101";
+    let server = start_server(temp_dir.path(), addr, &[]);
+
+    let mut client = MakvClient::connect(addr).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    client.set("key1".to_owned(), "value2".to_owned()).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(client.get("key2".to_owned()).await?, None);
+
+    client.remove("key1".to_owned()).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+    assert!(client.remove("key1".to_owned()).await.is_err());
+
+    drop(server);
+    Ok(())
+}
+
+// Should reconnect when the server restarts between two requests
+#[tokio::test]
+async fn client_retries_after_server_restart() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4102";
+    let server = start_server(temp_dir.path(), addr, &[]);
+
+    let options = ClientOptions {
+        retries: 5,
+        retry_backoff: Duration::from_millis(500),
+        ..ClientOptions::default()
+    };
+    let mut client = MakvClient::connect_with(addr, options).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+
+    drop(server);
+    let server = start_server(temp_dir.path(), addr, &[]);
+
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+
+    drop(server);
+    Ok(())
+}
+
+// Shouldn't repeat a write which may have been applied, but reconnect for the
+// next request
+#[tokio::test]
+async fn client_does_not_retry_sent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4211";
+    let server = start_server(temp_dir.path(), addr, &[]);
+
+    let options = ClientOptions {
+        retries: 5,
+        retry_backoff: Duration::from_millis(500),
+        ..ClientOptions::default()
+    };
+    let mut client = MakvClient::connect_with(addr, options).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+
+    drop(server);
+    let server = start_server(temp_dir.path(), addr, &[]);
+
+    // the old connection was closed with the server
+    assert!(client.remove("key1".to_owned()).await.is_err());
+    client.remove("key1".to_owned()).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+
+    drop(server);
+    Ok(())
+}
+
+// Should fail fast when nothing is listening
+#[tokio::test]
+async fn client_connect_error() {
+    let options = ClientOptions {
+        connect_timeout: Duration::from_millis(200),
+        ..ClientOptions::default()
+    };
+    assert!(MakvClient::connect_with("127.0.0.1:4103", options)
+        .await
+        .is_err());
+}
+
+// Should report the store's statistics, also through `makv-client stats`
+#[tokio::test]
+async fn client_stats() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4104";
+    let server = start_server(temp_dir.path(), addr, &[]);
+
+    let mut client = MakvClient::connect(addr).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    client.set("key2".to_owned(), "value2".to_owned()).await?;
+    client.remove("key2".to_owned()).await?;
+    client.get("key1".to_owned()).await?;
+    let stats = client.stats().await?;
+    assert_eq!(stats.keys, 1);
+    assert_eq!(stats.requests.sets, 2);
+    assert_eq!(stats.requests.removes, 1);
+    assert_eq!(stats.requests.gets, 1);
+    assert!(stats.live_bytes > 0 && stats.stale_bytes > 0);
+    assert_eq!(stats.compactions, 0);
+
+    Command::cargo_bin("makv-client")
+        .unwrap()
+        .args(&["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("keys: 1\n"))
+        .stdout(predicates::str::contains("last_compaction: never\n"));
+
+    drop(server);
+    Ok(())
+}
+
+// Should serve request latencies, errors and store metrics at /metrics
+#[tokio::test]
+async fn client_metrics() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4105";
+    let metrics_addr = "127.0.0.1:4106";
+    let server = spawn_server(
+        server_command(temp_dir.path()).args(&["--addr", addr, "--metrics-addr", metrics_addr]),
+        &[addr, metrics_addr],
+    );
+
+    let mut client = MakvClient::connect(addr).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    client.get("key1".to_owned()).await?;
+    assert!(client.remove("key2".to_owned()).await.is_err());
+
+    let mut stream = TcpStream::connect(metrics_addr)?;
+    stream.write_all(b"GET /metrics HTTP/1.0\r\nHost: localhost\r\n\r\n")?;
+    let mut metrics = String::new();
+    stream.read_to_string(&mut metrics)?;
+    assert!(metrics.starts_with("HTTP/1.0 200"));
+    assert!(metrics.contains("makv_request_duration_seconds_count{command=\"set\"} 1\n"));
+    assert!(metrics.contains("makv_request_duration_seconds_count{command=\"get\"} 1\n"));
+    assert!(metrics.contains("makv_errors_total{kind=\"not_found\"} 1\n"));
+    assert!(metrics.contains("makv_active_connections 1\n"));
+    assert!(metrics.contains("makv_keys 1\n"));
+    assert!(metrics.contains("makv_compaction_duration_seconds_count 0\n"));
+
+    drop(server);
+    Ok(())
+}
+
+// Idle connections shouldn't hold a thread of the pool, so that more clients
+// than threads can stay connected
+#[tokio::test]
+async fn more_idle_clients_than_threads() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4200";
+    let server = start_server(temp_dir.path(), addr, &["--pool-size", "2"]);
+
+    let mut idle = Vec::new();
+    for i in 0..4 {
+        let mut client = MakvClient::connect(addr).await?;
+        client
+            .set(format!("key{}", i), format!("value{}", i))
+            .await?;
+        idle.push(client);
+    }
+    // connected without ever sending a request
+    let silent = (0..4)
+        .map(|_| TcpStream::connect(addr))
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    let options = ClientOptions {
+        request_timeout: Duration::from_secs(2),
+        retries: 0,
+        ..ClientOptions::default()
+    };
+    let mut client = MakvClient::connect_with(addr, options).await?;
+    client.set("key".to_owned(), "value".to_owned()).await?;
+    assert_eq!(
+        client.get("key".to_owned()).await?,
+        Some("value".to_owned())
+    );
+    for (i, client) in idle.iter_mut().enumerate() {
+        assert_eq!(
+            client.get(format!("key{}", i)).await?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    drop(silent);
+    drop(server);
+    Ok(())
+}
@@ -0,0 +1,58 @@
+// Helpers for the tests which run makv-server. Not every test uses all of them.
+#![allow(dead_code)]
+
+use assert_cmd::prelude::*;
+use std::net::TcpStream;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How long a server may take to start listening
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Kills the server when dropped
+pub struct Server(pub Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.0.kill().expect("server exited before killed");
+        self.0.wait().unwrap();
+    }
+}
+
+// makv-server running in `dir`
+pub fn server_command(dir: &Path) -> Command {
+    let mut cmd = Command::cargo_bin("makv-server").unwrap();
+    cmd.current_dir(dir);
+    cmd
+}
+
+// Starts makv-server on `addr` with more `args`, once it accepts connections
+pub fn start_server(dir: &Path, addr: &str, args: &[&str]) -> Server {
+    spawn_server(
+        server_command(dir).args(&["--addr", addr]).args(args),
+        &[addr],
+    )
+}
+
+// Starts the server of `cmd` and waits until every address in `addrs`
+// accepts connections
+pub fn spawn_server(cmd: &mut Command, addrs: &[&str]) -> Server {
+    let mut child = cmd.spawn().unwrap();
+    let deadline = Instant::now() + START_TIMEOUT;
+    for addr in addrs {
+        while TcpStream::connect(addr).is_err() {
+            if let Some(status) = child.try_wait().unwrap() {
+                panic!("server exited with {} before listening", status);
+            }
+            if Instant::now() > deadline {
+                child.kill().unwrap();
+                child.wait().unwrap();
+                panic!("server did not listen on {}", addr);
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+    Server(child)
+}
@@ -0,0 +1,130 @@
+use assert_cmd::prelude::*;
+use makv::{MakvClient, Result};
+use predicates::str::contains;
+use serde_json::Value;
+use std::fs;
+use tempfile::TempDir;
+
+mod common;
+use common::{server_command, spawn_server, start_server};
+
+// Settings should come from the config file, with environment variables
+// taking precedence
+#[tokio::test]
+async fn server_config_file() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    fs::create_dir(temp_dir.path().join("data"))?;
+    fs::write(
+        temp_dir.path().join("makv.toml"),
+        r#"
+[server]
+addr = "127.0.0.1:4195"
+data-dir = "data"
+engine = "yakv"
+
+[pool]
+type = "shared-queue"
+size = 2
+
+[store]
+compaction-threshold = 1024
+durability = "sync"
+
+[log]
+format = "json"
+file = "server.log"
+"#,
+    )?;
+    let addr = "127.0.0.1:4196";
+    let server = spawn_server(
+        server_command(temp_dir.path())
+            .args(&["--config", "makv.toml"])
+            .env("MAKV_ADDR", addr),
+        &[addr],
+    );
+
+    let mut client = MakvClient::connect(addr).await?;
+    for i in 0..100 {
+        client.set("key".to_owned(), format!("value{}", i)).await?;
+    }
+    // far less than the default threshold of 1 MiB is stale
+    assert!(client.stats().await?.compactions > 0);
+    drop(server);
+
+    assert!(temp_dir.path().join("data/engine_yakv_data").is_dir());
+    let log = fs::read_to_string(temp_dir.path().join("server.log"))?;
+    let first: Value = serde_json::from_str(log.lines().next().unwrap())?;
+    assert_eq!(first["msg"], "version: 0.4.0");
+    Ok(())
+}
+
+// Invalid settings should stop the server, naming where they came from
+#[test]
+fn server_config_errors() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4197";
+    let config = |content: &str| {
+        let path = temp_dir.path().join("makv.toml");
+        fs::write(&path, content).unwrap();
+        path
+    };
+
+    server_command(temp_dir.path())
+        .assert()
+        .failure()
+        .stderr(contains("--addr is required, also as MAKV_ADDR"));
+    server_command(temp_dir.path())
+        .args(&["--addr", addr])
+        .env("MAKV_POOL_SIZE", "0")
+        .assert()
+        .failure()
+        .stderr(contains("0 from MAKV_POOL_SIZE must be at least 1"));
+    server_command(temp_dir.path())
+        .args(&["--addr", addr, "--data-dir", "missing"])
+        .assert()
+        .failure()
+        .stderr(contains(
+            "data directory missing from --data-dir does not exist",
+        ));
+    server_command(temp_dir.path())
+        .args(&["--addr", addr])
+        .env("MAKV_BACKUP_DIR", "missing")
+        .assert()
+        .failure()
+        .stderr(contains(
+            "backup directory missing from MAKV_BACKUP_DIR does not exist",
+        ));
+
+    let path = config("[store]\ncompaction = 1024\n");
+    server_command(temp_dir.path())
+        .args(&["--addr", addr, "--config", path.to_str().unwrap()])
+        .assert()
+        .failure()
+        .stderr(contains("unknown setting `store.compaction`"));
+    let path = config("[store]\ndurability = \"always\"\n");
+    server_command(temp_dir.path())
+        .args(&["--addr", addr, "--config", path.to_str().unwrap()])
+        .assert()
+        .failure()
+        .stderr(contains("'always' from `store.durability` in"))
+        .stderr(contains("expected one of buffered, sync, group"));
+    let path = config("[limits]\nmax-frame-size = \"big\"\n");
+    server_command(temp_dir.path())
+        .args(&["--addr", addr, "--config", path.to_str().unwrap()])
+        .assert()
+        .failure()
+        .stderr(contains("Invalid value 'big' from `limits.max-frame-size`"));
+
+    // arguments take precedence over the file
+    let path = config("[server]\nengine = \"bogus\"\n");
+    server_command(temp_dir.path())
+        .args(&["--addr", addr, "--config", path.to_str().unwrap()])
+        .assert()
+        .failure()
+        .stderr(contains("expected one of yakv, sled, lsm"));
+    start_server(
+        temp_dir.path(),
+        addr,
+        &["--config", path.to_str().unwrap(), "--engine", "yakv"],
+    );
+}
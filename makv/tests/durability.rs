@@ -0,0 +1,80 @@
+use makv::{KvStore, MakvClient, MakvEngine, Result};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+use common::start_server;
+
+// Kills the server while clients are writing, and checks every write it
+// acknowledged is in the store. Returns the fsyncs of the server and the
+// number of acknowledged writes.
+//
+// Killing the process leaves its writes in the OS page cache, so this doesn't
+// simulate a power loss: even buffered writes survive. Whether writes are
+// actually fsynced is checked by counting the fsyncs instead.
+async fn crash_while_writing(addr: &str, durability: &str) -> Result<(u64, usize)> {
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(temp_dir.path(), addr, &["--durability", durability]);
+
+    let acked = Arc::new(Mutex::new(Vec::new()));
+    let mut writers = Vec::new();
+    for writer_id in 0..4 {
+        let acked = acked.clone();
+        let addr = addr.to_owned();
+        writers.push(tokio::spawn(async move {
+            let mut client = MakvClient::connect(&addr).await?;
+            for i in 0.. {
+                let key = format!("writer{}-key{}", writer_id, i);
+                if client
+                    .set(key.clone(), format!("value{}", i))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+                acked.lock().unwrap().push((key, format!("value{}", i)));
+            }
+            Ok::<_, makv::YakvError>(())
+        }));
+    }
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let syncs = MakvClient::connect(addr).await?.stats().await?.syncs;
+    drop(server);
+    for writer in writers {
+        writer.await.unwrap()?;
+    }
+
+    let acked = acked.lock().unwrap();
+    assert!(!acked.is_empty(), "no write was acknowledged");
+    let store = KvStore::open(temp_dir.path())?;
+    for (key, value) in acked.iter() {
+        assert_eq!(store.get(key.to_owned())?.as_ref(), Some(value));
+    }
+    Ok((syncs, acked.len()))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn crash_buffered() -> Result<()> {
+    let (syncs, _) = crash_while_writing("127.0.0.1:4171", "buffered").await?;
+    assert_eq!(syncs, 0);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn crash_sync() -> Result<()> {
+    let (syncs, acked) = crash_while_writing("127.0.0.1:4172", "sync").await?;
+    // concurrent writes may share an fsync, but every acknowledged write was
+    // fsynced before its reply
+    assert!(syncs > 0 && syncs <= acked as u64, "{} fsyncs", syncs);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn crash_group() -> Result<()> {
+    let (syncs, acked) = crash_while_writing("127.0.0.1:4173", "group").await?;
+    // the writers wait for each other, so each fsync covers several writes
+    assert!(syncs > 0 && syncs * 2 < acked as u64, "{} fsyncs", syncs);
+    Ok(())
+}
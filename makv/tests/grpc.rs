@@ -0,0 +1,116 @@
+use makv::proto::key_value_client::KeyValueClient;
+use makv::proto::{watch_event, GetRequest, RemoveRequest, ScanRequest, SetRequest, WatchRequest};
+use makv::MakvClient;
+use tempfile::TempDir;
+
+mod common;
+use common::{server_command, spawn_server, Server};
+use tonic::Code;
+
+fn start_grpc_server(temp_dir: &TempDir, addr: &str, grpc_addr: &str) -> Server {
+    spawn_server(
+        server_command(temp_dir.path()).args(&["--addr", addr, "--grpc-addr", grpc_addr]),
+        &[addr, grpc_addr],
+    )
+}
+
+#[tokio::test]
+async fn grpc_get_set_remove_scan() {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_grpc_server(&temp_dir, "127.0.0.1:4131", "127.0.0.1:4132");
+    let mut client = KeyValueClient::connect("http://127.0.0.1:4132")
+        .await
+        .unwrap();
+
+    for key in &["a", "b", "c"] {
+        client
+            .set(SetRequest {
+                key: key.to_string(),
+                value: format!("value-{}", key),
+            })
+            .await
+            .unwrap();
+    }
+    let res = client
+        .get(GetRequest {
+            key: "a".to_owned(),
+        })
+        .await
+        .unwrap();
+    assert_eq!(res.into_inner().value, Some("value-a".to_owned()));
+
+    client
+        .remove(RemoveRequest {
+            key: "a".to_owned(),
+        })
+        .await
+        .unwrap();
+    let res = client
+        .get(GetRequest {
+            key: "a".to_owned(),
+        })
+        .await
+        .unwrap();
+    assert_eq!(res.into_inner().value, None);
+    let err = client
+        .remove(RemoveRequest {
+            key: "a".to_owned(),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Code::NotFound);
+
+    let res = client
+        .scan(ScanRequest {
+            start: String::new(),
+            end: "c".to_owned(),
+            limit: 0,
+        })
+        .await
+        .unwrap();
+    let pairs: Vec<_> = res
+        .into_inner()
+        .pairs
+        .into_iter()
+        .map(|p| (p.key, p.value))
+        .collect();
+    assert_eq!(pairs, vec![("b".to_owned(), "value-b".to_owned())]);
+}
+
+// Should stream changes made through any front-end
+#[tokio::test]
+async fn grpc_watch() {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_grpc_server(&temp_dir, "127.0.0.1:4133", "127.0.0.1:4134");
+    let mut client = KeyValueClient::connect("http://127.0.0.1:4134")
+        .await
+        .unwrap();
+    let mut events = client
+        .watch(WatchRequest {
+            prefix: "user/".to_owned(),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+
+    let mut native = MakvClient::connect("127.0.0.1:4133").await.unwrap();
+    native
+        .set("other".to_owned(), "ignored".to_owned())
+        .await
+        .unwrap();
+    native
+        .set("user/1".to_owned(), "alice".to_owned())
+        .await
+        .unwrap();
+    native.remove("user/1".to_owned()).await.unwrap();
+
+    let event = events.message().await.unwrap().unwrap();
+    assert_eq!(event.kind(), watch_event::Kind::Set);
+    assert_eq!(
+        (event.key.as_str(), event.value.as_str()),
+        ("user/1", "alice")
+    );
+    let event = events.message().await.unwrap().unwrap();
+    assert_eq!(event.kind(), watch_event::Kind::Remove);
+    assert_eq!(event.key, "user/1");
+}
@@ -0,0 +1,83 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use tempfile::TempDir;
+
+mod common;
+use common::{server_command, spawn_server, Server};
+
+fn start_http_server(temp_dir: &TempDir, addr: &str, http_addr: &str) -> Server {
+    spawn_server(
+        server_command(temp_dir.path()).args(&["--addr", addr, "--http-addr", http_addr]),
+        &[addr, http_addr],
+    )
+}
+
+// Sends a request and returns the status code and body
+fn request(addr: &str, method: &str, path: &str, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        addr,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response[9..12].parse().unwrap();
+    let body = response.split_once("\r\n\r\n").unwrap().1.to_owned();
+    (status, body)
+}
+
+#[test]
+fn http_get_put_delete() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4122";
+    let _server = start_http_server(&temp_dir, "127.0.0.1:4121", addr);
+
+    assert_eq!(request(addr, "GET", "/health", ""), (200, "OK".to_owned()));
+    assert_eq!(request(addr, "PUT", "/keys/key1", "value1").0, 204);
+    assert_eq!(
+        request(addr, "GET", "/keys/key1", ""),
+        (200, "value1".to_owned())
+    );
+    assert_eq!(request(addr, "PUT", "/keys/a%20b", "value2").0, 204);
+    assert_eq!(
+        request(addr, "GET", "/keys/a%20b", ""),
+        (200, "value2".to_owned())
+    );
+    assert_eq!(request(addr, "DELETE", "/keys/key1", "").0, 204);
+    assert_eq!(request(addr, "GET", "/keys/key1", "").0, 404);
+    assert_eq!(request(addr, "DELETE", "/keys/key1", "").0, 404);
+    assert_eq!(request(addr, "POST", "/keys/key1", "").0, 405);
+    assert_eq!(request(addr, "GET", "/nope", "").0, 404);
+
+    let (status, metrics) = request(addr, "GET", "/metrics", "");
+    assert_eq!(status, 200);
+    assert!(metrics.contains("makv_request_duration_seconds_count{command=\"set\"} 2\n"));
+    assert!(metrics.contains("makv_request_duration_seconds_count{command=\"get\"} 3\n"));
+    assert!(metrics.contains("makv_request_duration_seconds_count{command=\"remove\"} 2\n"));
+    // a 404 is an answer, not an error
+    assert!(!metrics.contains("makv_errors_total{"));
+    assert!(metrics.contains("makv_keys 1\n"));
+}
+
+#[test]
+fn http_list_range() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4124";
+    let _server = start_http_server(&temp_dir, "127.0.0.1:4123", addr);
+
+    for key in &["a", "b", "c", "d"] {
+        assert_eq!(request(addr, "PUT", &format!("/keys/{}", key), key).0, 204);
+    }
+    let (status, body) = request(addr, "GET", "/keys?start=b&end=d", "");
+    assert_eq!(status, 200);
+    assert_eq!(body, r#"[{"key":"b","value":"b"},{"key":"c","value":"c"}]"#);
+    let (_, body) = request(addr, "GET", "/keys?limit=1", "");
+    assert_eq!(body, r#"[{"key":"a","value":"a"}]"#);
+    assert_eq!(request(addr, "GET", "/keys?limit=zero", "").0, 400);
+}
@@ -1,6 +1,11 @@
-use makv::{KvStore, MakvEngine, Result};
+use makv::{
+    CacheStats, Compression, Durability, EncryptionKey, IndexMode, KvStore, MakvEngine,
+    RequestStats, Result, StoreOptions,
+};
+use std::fs;
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -209,3 +214,558 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Should compress large values and read them back with any options
+#[test]
+fn compressed_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = StoreOptions {
+        compression: Compression::Lz4,
+        ..StoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+
+    let large = "{\"field\": \"value\"}, ".repeat(10_000);
+    store.set("large".to_owned(), large.to_owned())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large.to_owned()));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+
+    let log_size: u64 = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum();
+    assert!(log_size < large.len() as u64 / 10);
+
+    // Open from disk again without compression and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Should read logs written before records had headers, also after compaction
+#[test]
+fn legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("engine_yakv_data");
+    fs::create_dir_all(&data_dir)?;
+    fs::write(
+        data_dir.join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Remove":{"key":"key2"}}"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // overwrite another key until the legacy log is compacted away
+    let value = "x".repeat(1024);
+    while data_dir.join("1.log").exists() {
+        store.set("key3".to_owned(), value.to_owned())?;
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some(value));
+
+    Ok(())
+}
+
+// Should encrypt records and only read them back with the key
+#[test]
+fn encrypted_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = EncryptionKey::generate();
+    let options = |key: Option<&EncryptionKey>| StoreOptions {
+        compression: Compression::Lz4,
+        encryption_key: key.cloned(),
+        ..StoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options(Some(&key)))?;
+    let large = "secret value ".repeat(1000);
+    store.set("key1".to_owned(), "secret1".to_owned())?;
+    store.set("key2".to_owned(), large.to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("secret1".to_owned()));
+    drop(store);
+
+    for entry in WalkDir::new(temp_dir.path()) {
+        let entry = entry.unwrap();
+        if entry.file_type().is_file() {
+            let content = fs::read(entry.path())?;
+            assert!(!content
+                .windows(6)
+                .any(|w| w == b"secret" || w == b"key1\"}"));
+        }
+    }
+
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    assert!(
+        KvStore::open_with(temp_dir.path(), options(Some(&EncryptionKey::generate()))).is_err()
+    );
+    let store = KvStore::open_with(temp_dir.path(), options(Some(&key)))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("secret1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some(large));
+
+    Ok(())
+}
+
+// Encrypted records shouldn't read back from another position, but should
+// survive being moved by compaction
+#[test]
+fn moved_encrypted_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("engine_yakv_data").join("1.log");
+    let options = StoreOptions {
+        encryption_key: Some(EncryptionKey::generate()),
+        compaction_threshold: 1024,
+        ..StoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // swap the two records, which have the same length, after the magic
+    let log = fs::read(&log_path)?;
+    let len = (log.len() - 4) / 2;
+    let mut swapped = log[..4].to_vec();
+    swapped.extend_from_slice(&log[4 + len..]);
+    swapped.extend_from_slice(&log[4..4 + len]);
+    fs::write(&log_path, &swapped)?;
+    assert!(KvStore::open_with(temp_dir.path(), options.clone()).is_err());
+    fs::write(&log_path, &log)?;
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..100 {
+        store.set("key2".to_owned(), format!("value{}", i))?;
+    }
+    assert!(store.stats()?.compactions > 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value99".to_owned()));
+
+    Ok(())
+}
+
+// Should rewrite every record under the new key, including unencrypted ones
+#[test]
+fn rotate_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let key1 = EncryptionKey::generate();
+    store.rotate_key(key1.clone())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let key2 = EncryptionKey::generate();
+    store.rotate_key(key2.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    let with_key = |key: &EncryptionKey| {
+        KvStore::open_with(
+            temp_dir.path(),
+            StoreOptions {
+                encryption_key: Some(key.clone()),
+                ..StoreOptions::default()
+            },
+        )
+    };
+    assert!(with_key(&key1).is_err());
+    let store = with_key(&key2)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should build the index from hint files without reading values
+#[test]
+fn hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("engine_yakv_data");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    // reopening seals 1.log and writes its hints
+    drop(KvStore::open(temp_dir.path())?);
+    assert!(data_dir.join("1.hint").exists());
+
+    // break every value without changing the length of the log
+    let mut log = fs::read(data_dir.join("1.log"))?;
+    for i in 0..log.len() - 7 {
+        if &log[i..i + 7] == b":\"value" {
+            log[i + 1] = b'[';
+        }
+    }
+    fs::write(data_dir.join("1.log"), log)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.scan(String::new(), 10)?,
+        vec!["key1".to_owned(), "key3".to_owned()]
+    );
+    assert!(store.get("key1".to_owned()).is_err());
+    drop(store);
+
+    // without hints the log is read in full
+    fs::remove_file(data_dir.join("1.hint"))?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    Ok(())
+}
+
+// Should ignore and rewrite hint files which fail their checksum
+#[test]
+fn corrupted_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let hint_path = temp_dir.path().join("engine_yakv_data").join("1.hint");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    drop(KvStore::open(temp_dir.path())?);
+
+    let hint = fs::read(&hint_path)?;
+    let mut corrupted = hint.clone();
+    let last = corrupted.len() - 3;
+    corrupted[last] ^= 0xff;
+    fs::write(&hint_path, &corrupted)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(fs::read(&hint_path)?, hint);
+
+    Ok(())
+}
+
+// A damaged record should fail its checksum, and a damaged length shouldn't
+// be read past the end of the log
+#[test]
+fn corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("engine_yakv_data").join("1.log");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let log = fs::read(&log_path)?;
+
+    let mut corrupted = log.clone();
+    let last = corrupted.len() - 3;
+    corrupted[last] ^= 0x01;
+    fs::write(&log_path, &corrupted)?;
+    let err = KvStore::open(temp_dir.path()).err().unwrap();
+    assert!(err.to_string().contains("checksum mismatch"), "{}", err);
+
+    // the length follows the magic and the flags of the first record
+    let mut corrupted = log;
+    corrupted[5..9].copy_from_slice(&u32::MAX.to_be_bytes());
+    fs::write(&log_path, &corrupted)?;
+    let err = KvStore::open(temp_dir.path()).err().unwrap();
+    assert!(err.to_string().contains("runs past the end"), "{}", err);
+
+    Ok(())
+}
+
+// The disk index behaves like the memory index, across compactions and reopens
+#[test]
+fn disk_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = StoreOptions {
+        index: IndexMode::Disk,
+        ..StoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    // overwrite enough records to trigger compactions
+    for iter in 0..40 {
+        for key_id in 0..1000 {
+            store.set(format!("key{:03}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove("key001".to_owned())?;
+    assert_eq!(store.get("key000".to_owned())?, Some("39".to_owned()));
+    assert_eq!(store.get("key001".to_owned())?, None);
+    assert_eq!(
+        store.scan("key000".to_owned(), 3)?,
+        vec!["key000", "key002", "key003"]
+    );
+    drop(store);
+
+    let log_count = fs::read_dir(temp_dir.path().join("engine_yakv_data"))?
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+        .count();
+    assert!(log_count < 40, "no compaction happened");
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key999".to_owned())?, Some("39".to_owned()));
+    assert_eq!(store.get("key001".to_owned())?, None);
+    assert_eq!(
+        store.scan("key998".to_owned(), 10)?,
+        vec!["key998", "key999"]
+    );
+
+    // a store can switch between index modes
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key500".to_owned())?, Some("39".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = StoreOptions {
+        cache_size: 100,
+        ..StoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        store.cache_stats(),
+        CacheStats {
+            hits: 1,
+            misses: 1,
+            entries: 1,
+            size: 10,
+        }
+    );
+
+    // writes invalidate the cached value
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.cache_stats().hits, 1);
+
+    // the least recently used values are evicted to stay within 100 bytes
+    for key_id in 0..20 {
+        let key = format!("key{}", key_id);
+        store.set(key.clone(), "value".to_owned())?;
+        store.get(key)?;
+    }
+    let stats = store.cache_stats();
+    assert!(stats.size <= 100);
+    assert_eq!(stats.entries, 10);
+    store.get("key19".to_owned())?;
+    store.get("key0".to_owned())?;
+    assert_eq!(store.cache_stats().hits, stats.hits + 1);
+    Ok(())
+}
+
+// Concurrent writers share fsyncs and all return once their write is synced
+#[test]
+fn group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = StoreOptions {
+        durability: Durability::group(Duration::from_millis(5)),
+        ..StoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let writers: Vec<_> = (0..8)
+        .map(|writer_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..50 {
+                    store
+                        .set(format!("key{}-{}", writer_id, i), format!("value{}", i))
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    let syncs = store.stats()?.syncs;
+    assert!(syncs > 0 && syncs < 8 * 50 / 2, "{} fsyncs", syncs);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for writer_id in 0..8 {
+        for i in 0..50 {
+            assert_eq!(
+                store.get(format!("key{}-{}", writer_id, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+    Ok(())
+}
+
+// Sync durability should fsync every write or batch once, and buffered
+// durability never
+#[test]
+fn sync_durability() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = |durability| StoreOptions {
+        durability,
+        ..StoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options(Durability::Sync))?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    assert_eq!(store.stats()?.syncs, 10);
+    let pairs = (0..100)
+        .map(|i| (format!("key{}", i), "value".to_owned()))
+        .collect();
+    store.set_batch(pairs)?;
+    assert_eq!(store.stats()?.syncs, 11);
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options(Durability::Buffered))?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    assert_eq!(store.stats()?.syncs, 0);
+    Ok(())
+}
+
+// Writes batched together get their own results, in the order they were queued
+#[test]
+fn concurrent_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = StoreOptions {
+        durability: Durability::Sync,
+        ..StoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key".to_owned(), "value".to_owned())?;
+
+    let barrier = Arc::new(Barrier::new(16));
+    let removers: Vec<_> = (0..16)
+        .map(|_| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                store.remove("key".to_owned()).is_ok()
+            })
+        })
+        .collect();
+    let removed = removers
+        .into_iter()
+        .map(|remover| remover.join().unwrap())
+        .filter(|&ok| ok)
+        .count();
+    assert_eq!(removed, 1);
+    assert_eq!(store.get("key".to_owned())?, None);
+    Ok(())
+}
+
+// Remove records are stale as soon as they are written, and count towards
+// the compaction threshold
+#[test]
+fn removes_trigger_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().join("measure"))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let set_len = store.stats()?.live_bytes;
+    drop(store);
+
+    // the set alone doesn't exceed the threshold
+    let options = StoreOptions {
+        compaction_threshold: set_len,
+        ..StoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, 1);
+    assert_eq!(stats.stale_bytes, 0);
+    Ok(())
+}
+
+// Writes which trigger a failing compaction should still succeed, and the
+// store should compact once it can
+#[test]
+fn failed_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("engine_yakv_data");
+    let options = StoreOptions {
+        compaction_threshold: 1024,
+        ..StoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    // compaction moves writes on to 3.log, which can't be created
+    fs::create_dir(data_dir.join("3.log"))?;
+    for i in 0..100 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    assert_eq!(store.stats()?.compactions, 0);
+    assert_eq!(store.get("key".to_owned())?, Some("value99".to_owned()));
+
+    fs::remove_dir(data_dir.join("3.log"))?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(store.stats()?.compactions, 1);
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Stats should count keys, bytes and requests the same way before and
+// after a reopen, and count compactions
+#[test]
+fn store_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.scan("".to_owned(), 10)?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 1);
+    assert_eq!(stats.generations, 1);
+    assert_eq!(stats.compactions, 0);
+    assert_eq!(stats.last_compaction, None);
+    assert_eq!(
+        stats.requests,
+        RequestStats {
+            gets: 1,
+            sets: 3,
+            removes: 1,
+            scans: 1,
+        }
+    );
+    assert!(stats.stale_bytes > stats.live_bytes);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let reopened = store.stats()?;
+    assert_eq!(reopened.keys, stats.keys);
+    assert_eq!(reopened.live_bytes, stats.live_bytes);
+    assert_eq!(reopened.stale_bytes, stats.stale_bytes);
+    assert_eq!(reopened.generations, 2);
+    assert_eq!(reopened.requests, RequestStats::default());
+
+    // overwriting one key until a compaction
+    let value = "x".repeat(1024);
+    for _ in 0..2000 {
+        store.set("key3".to_owned(), value.clone())?;
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 2);
+    assert!(stats.compactions >= 1);
+    assert!(stats.last_compaction.is_some());
+    assert!(stats.stale_bytes < 1024 * 1024);
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use makv::logging::RotatingFile;
+use makv::{MakvClient, Result};
+use serde_json::Value;
+use std::fs;
+use std::io::Write;
+use tempfile::TempDir;
+
+mod common;
+use common::start_server;
+
+// Should rotate between lines once a file is full, keeping only `keep` files
+#[test]
+fn rotating_file() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("server.log");
+    let mut file = RotatingFile::open(&path, 10, 2)?;
+    for line in 0..10 {
+        // a line written in pieces stays in one file
+        write!(file, "line {} ", line)?;
+        writeln!(file, "of ten")?;
+    }
+    file.flush()?;
+
+    assert_eq!(file.rotated_path(0), path);
+    assert!(!file.rotated_path(3).exists());
+    for n in 0..3 {
+        let content = fs::read_to_string(file.rotated_path(n))?;
+        assert!(content.lines().all(|line| line.ends_with(" of ten")));
+    }
+    assert_eq!(fs::read_to_string(&path)?, "line 9 of ten\n");
+    assert_eq!(fs::read_to_string(file.rotated_path(2))?, "line 7 of ten\n");
+    Ok(())
+}
+
+// Should log every request as a JSON object with its peer, command, key,
+// latency and outcome
+#[tokio::test]
+async fn server_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4192";
+    let server = start_server(
+        temp_dir.path(),
+        addr,
+        &["--log-format", "json", "--log-file", "server.log"],
+    );
+
+    let mut client = MakvClient::connect(addr).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert!(client.remove("key2".to_owned()).await.is_err());
+    drop(server);
+
+    let log = fs::read_to_string(temp_dir.path().join("server.log"))?;
+    let lines = log
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<serde_json::Result<Vec<Value>>>()?;
+    let request = |command: &str| {
+        lines
+            .iter()
+            .find(|line| line["msg"] == "request" && line["command"] == command)
+            .unwrap_or_else(|| panic!("no {} request in {}", command, log))
+    };
+    let set = request("set");
+    assert_eq!(set["key"], "key1");
+    assert_eq!(set["outcome"], "ok");
+    assert_eq!(set["level"], "INFO");
+    assert!(set["peer"].as_str().unwrap().starts_with("127.0.0.1:"));
+    assert!(set["latency_us"].is_u64());
+    assert_eq!(request("remove")["outcome"], "not_found");
+    assert_eq!(request("handshake")["key"], Value::Null);
+    Ok(())
+}
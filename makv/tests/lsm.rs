@@ -0,0 +1,195 @@
+use makv::{Durability, LsmOptions, LsmStore, MakvEngine, RequestStats, Result};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+// Options small enough for a few thousand writes to reach level 2
+fn small_options() -> LsmOptions {
+    LsmOptions {
+        memtable_size: 4 * 1024,
+        block_size: 512,
+        table_size: 8 * 1024,
+        level0_tables: 2,
+        level1_size: 32 * 1024,
+        bloom_bits_per_key: 10,
+        ..LsmOptions::default()
+    }
+}
+
+fn table_count(path: &Path) -> usize {
+    fs::read_dir(path.join("engine_lsm_data"))
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("sst".as_ref()))
+        .count()
+}
+
+#[test]
+fn get_set_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(store.remove("key2".to_owned()).is_err());
+    assert_eq!(store.scan("".to_owned(), 10)?, vec!["key1"]);
+    Ok(())
+}
+
+// Writes which are only in the write-ahead log survive a reopen
+#[test]
+fn wal_replay() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    assert_eq!(table_count(temp_dir.path()), 0);
+    let store = LsmStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // a torn record at the end of the log is ignored
+    let data_dir = temp_dir.path().join("engine_lsm_data");
+    let wal = fs::read_dir(&data_dir)?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension() == Some("wal".as_ref()))
+        .expect("log exists");
+    let mut bytes = fs::read(&wal)?;
+    bytes.extend_from_slice(&[0, 0, 0, 100, 1, 2]);
+    fs::write(&wal, bytes)?;
+    let store = LsmStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn flush_and_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmStore::open_with(temp_dir.path(), small_options())?;
+
+    for iter in 0..5 {
+        for key_id in 0..2000 {
+            store.set(format!("key{:05}", key_id), format!("value{}", iter))?;
+        }
+    }
+    for key_id in (0..2000).step_by(3) {
+        store.remove(format!("key{:05}", key_id))?;
+    }
+
+    let check = |store: &LsmStore| -> Result<()> {
+        assert_eq!(store.get("key00000".to_owned())?, None);
+        assert_eq!(store.get("key00001".to_owned())?, Some("value4".to_owned()));
+        assert_eq!(store.get("key01998".to_owned())?, None);
+        assert_eq!(store.get("key01999".to_owned())?, Some("value4".to_owned()));
+        assert_eq!(store.get("key02000".to_owned())?, None);
+        assert_eq!(
+            store.scan("key00000".to_owned(), 4)?,
+            vec!["key00001", "key00002", "key00004", "key00005"]
+        );
+        assert_eq!(store.scan("".to_owned(), usize::MAX)?.len(), 1333);
+        Ok(())
+    };
+    check(&store)?;
+
+    // 10k writes of 4 KiB memtables were merged into few tables
+    let tables = table_count(temp_dir.path());
+    assert!(tables > 1 && tables < 40, "{} tables", tables);
+
+    drop(store);
+    let store = LsmStore::open_with(temp_dir.path(), small_options())?;
+    check(&store)
+}
+
+// Tables not in the manifest are left over from interrupted compactions
+// Synced writes, flushes and compactions should leave a store which opens
+// with every write and no temporary manifest
+#[test]
+fn sync_durability() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = LsmOptions {
+        durability: Durability::Sync,
+        ..small_options()
+    };
+    let store = LsmStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..500 {
+        store.set(format!("key{:05}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    let data_dir = temp_dir.path().join("engine_lsm_data");
+    assert!(table_count(temp_dir.path()) > 0);
+    assert!(!data_dir.join("MANIFEST.tmp").exists());
+    let store = LsmStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key00000".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(
+        store.get("key00499".to_owned())?,
+        Some("value499".to_owned())
+    );
+    assert_eq!(store.scan("".to_owned(), usize::MAX)?.len(), 500);
+    Ok(())
+}
+
+#[test]
+fn remove_unlisted_tables() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmStore::open_with(temp_dir.path(), small_options())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    drop(store);
+
+    let tables = table_count(temp_dir.path());
+    let leftover = temp_dir.path().join("engine_lsm_data").join("99999.sst");
+    fs::write(&leftover, b"partial table")?;
+    let store = LsmStore::open_with(temp_dir.path(), small_options())?;
+    assert!(!leftover.exists());
+    assert_eq!(table_count(temp_dir.path()), tables);
+    assert_eq!(store.get("key999".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Stats should estimate the keys from the memtable and tables without reading
+// them, and count tables, compactions and requests
+#[test]
+fn store_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmStore::open_with(temp_dir.path(), small_options())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key7".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.scan("".to_owned(), 10)?;
+    assert!(table_count(temp_dir.path()) > 0);
+
+    let stats = store.stats()?;
+    // the tombstone of key7 may be counted until compaction drops it
+    assert!(
+        stats.keys == 999 || stats.keys == 1000,
+        "{} keys",
+        stats.keys
+    );
+    assert_eq!(stats.generations, table_count(temp_dir.path()) as u64);
+    assert!(stats.live_bytes > 0);
+    assert!(stats.compactions > 0);
+    assert_eq!(stats.compaction_durations.count, stats.compactions);
+    assert_eq!(
+        stats.requests,
+        RequestStats {
+            gets: 1,
+            sets: 1000,
+            removes: 1,
+            scans: 1,
+        }
+    );
+    Ok(())
+}
@@ -0,0 +1,239 @@
+use makv::trace::SpanContext;
+use makv::{MakvClient, Payload, PayloadType, YakvError, YakvMessage};
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+mod common;
+use common::start_server;
+
+// Frames are written by hand so that the tests pin the wire format instead of
+// whatever the current `Command` and `Response` types serialize to.
+fn send(stream: &mut TcpStream, frame: Value) -> Value {
+    let bytes = serde_json::to_vec(&frame).unwrap();
+    stream
+        .write_all(&(bytes.len() as u32).to_be_bytes())
+        .unwrap();
+    stream.write_all(&bytes).unwrap();
+    read_frame(stream).expect("server closed the connection")
+}
+
+fn read_frame(stream: &mut TcpStream) -> Option<Value> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len).ok()?;
+    let mut buf = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut buf).unwrap();
+    Some(serde_json::from_slice(&buf).unwrap())
+}
+
+// Clients which predate the handshake should be served exactly as before
+#[test]
+fn legacy_frames_without_handshake() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4161";
+    let server = start_server(temp_dir.path(), addr, &[]);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let ok = json!({ "is_error": false, "error_msg": null, "result": null });
+    assert_eq!(
+        send(&mut stream, json!({ "Set": { "key": "k", "value": "v" } })),
+        ok
+    );
+    assert_eq!(
+        send(&mut stream, json!({ "Get": { "key": "k" } })),
+        json!({ "is_error": false, "error_msg": null, "result": "v" })
+    );
+    assert_eq!(send(&mut stream, json!({ "Remove": { "key": "k" } })), ok);
+    assert_eq!(
+        send(&mut stream, json!({ "Get": { "key": "k" } })),
+        json!({ "is_error": false, "error_msg": null, "result": "Key not found" })
+    );
+    assert_eq!(
+        send(&mut stream, json!({ "Remove": { "key": "k" } }))["is_error"],
+        true
+    );
+
+    drop(server);
+}
+
+// The server should answer a handshake with the agreed version and features,
+// and reject versions it doesn't speak
+#[test]
+fn handshake() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4162";
+    let server = start_server(temp_dir.path(), addr, &[]);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let res = send(
+        &mut stream,
+        json!({ "Handshake": { "version": 1, "features": ["auth", "teleport"] } }),
+    );
+    assert_eq!(res["is_error"], false);
+    assert_eq!(
+        res["handshake"],
+        json!({ "version": 1, "features": ["auth"] })
+    );
+    assert_eq!(
+        send(&mut stream, json!({ "Set": { "key": "k", "value": "v" } }))["is_error"],
+        false
+    );
+    let res = send(
+        &mut stream,
+        json!({ "Handshake": { "version": 1, "features": [] } }),
+    );
+    assert_eq!(res["error_msg"], "handshake must be the first command");
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let res = send(
+        &mut stream,
+        json!({ "Handshake": { "version": 99, "features": [] } }),
+    );
+    assert_eq!(res["is_error"], true);
+    assert_eq!(res["error_kind"], "UnsupportedVersion");
+    assert!(res["error_msg"]
+        .as_str()
+        .unwrap()
+        .contains("client speaks version 99"));
+    assert!(read_frame(&mut stream).is_none());
+
+    drop(server);
+}
+
+// Frames larger than the maximum should be rejected before their payload is read
+#[test]
+fn frame_size_limit() {
+    let frame = [&1000u32.to_be_bytes()[..], &[b'{'; 10][..]].concat();
+    match YakvMessage::with_max_frame_size(&frame[..], PayloadType::Command, 100) {
+        Err(YakvError::FrameTooLarge { length, max }) => assert_eq!((length, max), (1000, 100)),
+        res => panic!("expected frame too large, got {:?}", res),
+    }
+    // a length prefix larger than the data must not read past the frame
+    match YakvMessage::with_max_frame_size(&frame[..], PayloadType::Command, 1000) {
+        Err(YakvError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof),
+        res => panic!("expected unexpected eof, got {:?}", res),
+    }
+
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4163";
+    let server = start_server(temp_dir.path(), addr, &["--max-frame-size", "1024"]);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(&u32::MAX.to_be_bytes()).unwrap();
+    let res = read_frame(&mut stream).expect("server closed the connection");
+    assert_eq!(res["error_kind"], "FrameTooLarge");
+    assert!(read_frame(&mut stream).is_none());
+
+    // the server should keep serving other connections
+    let mut stream = TcpStream::connect(addr).unwrap();
+    assert_eq!(
+        send(&mut stream, json!({ "Set": { "key": "k", "value": "v" } }))["is_error"],
+        false
+    );
+
+    drop(server);
+}
+
+// Connections which stop mid-frame or go idle should be closed by the server
+#[test]
+fn half_open_and_idle_connections() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4164";
+    let server = start_server(temp_dir.path(), addr, &["--read-timeout", "1"]);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(&100u32.to_be_bytes()).unwrap();
+    stream.write_all(b"{\"Get\"").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    assert!(read_frame(&mut stream).is_none());
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let start = Instant::now();
+    assert!(read_frame(&mut stream).is_none());
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    drop(server);
+}
+
+// Responses should be compressed only for clients which asked for it
+#[test]
+fn compressed_frames() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4165";
+    let server = start_server(temp_dir.path(), addr, &[]);
+
+    let value = "abc".repeat(10_000);
+    let get = json!({ "Get": { "key": "k" } });
+
+    // legacy clients get plain frames
+    let mut stream = TcpStream::connect(addr).unwrap();
+    send(
+        &mut stream,
+        json!({ "Set": { "key": "k", "value": value } }),
+    );
+    assert_eq!(send(&mut stream, get.clone())["result"], value.as_str());
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let res = send(
+        &mut stream,
+        json!({ "Handshake": { "version": 1, "features": ["lz4"] } }),
+    );
+    assert_eq!(res["handshake"]["features"], json!(["lz4"]));
+    let bytes = serde_json::to_vec(&get).unwrap();
+    stream
+        .write_all(&(bytes.len() as u32).to_be_bytes())
+        .unwrap();
+    stream.write_all(&bytes).unwrap();
+    let mut len = [0; 4];
+    stream.read_exact(&mut len).unwrap();
+    let len = u32::from_be_bytes(len);
+    assert_ne!(len & (1 << 31), 0);
+    assert!(((len & !(1 << 31)) as usize) < value.len() / 10);
+
+    // MakvClient should send and receive compressed frames transparently
+    let read = tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(async {
+            let mut client = MakvClient::connect(addr).await?;
+            client.set("k2".to_owned(), value.to_owned()).await?;
+            client.get("k2".to_owned()).await
+        })
+        .unwrap();
+    assert_eq!(read, Some(value));
+
+    drop(server);
+}
+
+// A span context should survive a frame, compressed or not, and frames
+// without one should have none
+#[test]
+fn traced_frames() {
+    let context = SpanContext {
+        trace_id: 0x0123_4567_89ab_cdef_0123_4567_89ab_cdef,
+        span_id: 42,
+    };
+    let set = || Payload::Command(makv::Command::set("k".to_owned(), "v".repeat(10_000)));
+    let frames = vec![
+        YakvMessage::get_len_payload_bytes(set()).unwrap().1,
+        YakvMessage::get_compressed_len_payload_bytes(set(), 1024)
+            .unwrap()
+            .1,
+    ];
+    for frame in frames {
+        let plain = YakvMessage::new(&frame[..], PayloadType::Command).unwrap();
+        assert_eq!(plain.trace, None);
+
+        let traced = YakvMessage::traced(frame, context);
+        let message = YakvMessage::new(&traced[..], PayloadType::Command).unwrap();
+        assert_eq!(message.trace, Some(context));
+        match message.payload {
+            Payload::Command(makv::Command::Set { key, value }) => {
+                assert_eq!(key, "k");
+                assert_eq!(value.len(), 10_000);
+            }
+            payload => panic!("unexpected payload {:?}", payload),
+        }
+    }
+}
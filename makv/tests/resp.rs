@@ -0,0 +1,188 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+use common::{server_command, spawn_server, Server};
+
+fn start_resp_server(temp_dir: &TempDir, addr: &str, resp_addr: &str) -> Server {
+    spawn_server(
+        server_command(temp_dir.path()).args(&["--addr", addr, "--resp-addr", resp_addr]),
+        &[addr, resp_addr],
+    )
+}
+
+// Minimal RESP client which returns raw replies
+struct RespClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl RespClient {
+    fn connect(addr: &str) -> Self {
+        let stream = TcpStream::connect(addr).unwrap();
+        RespClient {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        }
+    }
+
+    fn call(&mut self, args: &[&str]) -> String {
+        let mut req = format!("*{}\r\n", args.len());
+        for arg in args {
+            req.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.writer.write_all(req.as_bytes()).unwrap();
+        self.read_reply()
+    }
+
+    fn read_reply(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        match line.as_bytes()[0] {
+            b'$' if !line.starts_with("$-1") => {
+                let len: usize = line[1..].trim_end().parse().unwrap();
+                let mut buf = vec![0; len + 2];
+                self.reader.read_exact(&mut buf).unwrap();
+                line.push_str(&String::from_utf8(buf).unwrap());
+            }
+            b'*' => {
+                let len: usize = line[1..].trim_end().parse().unwrap();
+                for _ in 0..len {
+                    let item = self.read_reply();
+                    line.push_str(&item);
+                }
+            }
+            _ => {}
+        }
+        line
+    }
+}
+
+#[test]
+fn resp_get_set_del_exists() {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_resp_server(&temp_dir, "127.0.0.1:4111", "127.0.0.1:4112");
+    let mut client = RespClient::connect("127.0.0.1:4112");
+
+    assert_eq!(client.call(&["PING"]), "+PONG\r\n");
+    assert_eq!(client.call(&["ping", "hi"]), "$2\r\nhi\r\n");
+    assert_eq!(client.call(&["SET", "key1", "value1"]), "+OK\r\n");
+    assert_eq!(client.call(&["GET", "key1"]), "$6\r\nvalue1\r\n");
+    assert_eq!(client.call(&["GET", "key2"]), "$-1\r\n");
+    assert_eq!(client.call(&["EXISTS", "key1", "key2"]), ":1\r\n");
+    assert_eq!(client.call(&["DEL", "key1", "key2"]), ":1\r\n");
+    assert_eq!(client.call(&["GET", "key1"]), "$-1\r\n");
+    assert!(client
+        .call(&["GET"])
+        .starts_with("-ERR wrong number of arguments"));
+    assert!(client
+        .call(&["FLUSHALL"])
+        .starts_with("-ERR unknown command"));
+}
+
+#[test]
+fn resp_keys_and_scan() {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_resp_server(&temp_dir, "127.0.0.1:4113", "127.0.0.1:4114");
+    let mut client = RespClient::connect("127.0.0.1:4114");
+
+    for key in &["a1", "a2", "b1", "b2", "c1"] {
+        assert_eq!(client.call(&["SET", key, "v"]), "+OK\r\n");
+    }
+    assert_eq!(
+        client.call(&["KEYS", "a*"]),
+        "*2\r\n$2\r\na1\r\n$2\r\na2\r\n"
+    );
+    assert_eq!(
+        client.call(&["KEYS", "[bc]1"]),
+        "*2\r\n$2\r\nb1\r\n$2\r\nc1\r\n"
+    );
+    assert_eq!(
+        client.call(&["SCAN", "0", "COUNT", "3"]),
+        "*2\r\n$1\r\n3\r\n*3\r\n$2\r\na1\r\n$2\r\na2\r\n$2\r\nb1\r\n"
+    );
+    assert_eq!(
+        client.call(&["SCAN", "3", "COUNT", "3", "MATCH", "*2"]),
+        "*2\r\n$1\r\n0\r\n*1\r\n$2\r\nb2\r\n"
+    );
+}
+
+#[test]
+fn resp_expire() {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_resp_server(&temp_dir, "127.0.0.1:4115", "127.0.0.1:4116");
+    let mut client = RespClient::connect("127.0.0.1:4116");
+
+    assert_eq!(client.call(&["SET", "key1", "value1"]), "+OK\r\n");
+    assert_eq!(client.call(&["EXPIRE", "key1", "1"]), ":1\r\n");
+    assert_eq!(client.call(&["EXPIRE", "key2", "1"]), ":0\r\n");
+    assert_eq!(
+        client.call(&["SET", "key2", "value2", "PX", "100"]),
+        "+OK\r\n"
+    );
+    assert_eq!(client.call(&["EXISTS", "key1", "key2"]), ":2\r\n");
+
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(client.call(&["GET", "key1"]), "$-1\r\n");
+    assert_eq!(client.call(&["EXISTS", "key1", "key2"]), ":0\r\n");
+    assert_eq!(client.call(&["KEYS", "*"]), "*0\r\n");
+}
+
+// Connections should only hold a pool thread while they have a command, and
+// lines, strings and glob patterns should have bounded costs
+#[test]
+fn resp_limits() {
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, resp_addr) = ("127.0.0.1:4209", "127.0.0.1:4210");
+    let _server = spawn_server(
+        server_command(temp_dir.path()).args(&[
+            "--addr",
+            addr,
+            "--resp-addr",
+            resp_addr,
+            "--pool-size",
+            "2",
+            "--max-frame-size",
+            "1024",
+        ]),
+        &[addr, resp_addr],
+    );
+
+    let mut clients: Vec<_> = (0..10).map(|_| RespClient::connect(resp_addr)).collect();
+    for client in &mut clients {
+        client
+            .writer
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(client.call(&["PING"]), "+PONG\r\n");
+    }
+
+    let key = "a".repeat(40);
+    assert_eq!(clients[0].call(&["SET", &key, "value"]), "+OK\r\n");
+    assert_eq!(
+        clients[0].call(&["KEYS", "*a*a*a*a*a*a*a*a*a*a*a*b"]),
+        "*0\r\n"
+    );
+    assert_eq!(
+        clients[0].call(&["KEYS", "*a*a*a*a*a*a*a*a*a*a*a"]),
+        format!("*1\r\n$40\r\n{}\r\n", key)
+    );
+
+    // a line without an end and a string over the limit close the connection
+    let mut stream = TcpStream::connect(resp_addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream.write_all("x".repeat(2000).as_bytes()).unwrap();
+    assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+    let mut stream = TcpStream::connect(resp_addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream.write_all(b"*2\r\n$3\r\nGET\r\n$2000\r\n").unwrap();
+    assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+    assert_eq!(clients[1].call(&["PING"]), "+PONG\r\n");
+}
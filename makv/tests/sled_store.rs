@@ -0,0 +1,30 @@
+use makv::{MakvEngine, RequestStats, Result, SledStore};
+use tempfile::TempDir;
+
+// Stats should count keys and requests
+#[test]
+fn store_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_batch(vec![
+        ("key2".to_owned(), "value2".to_owned()),
+        ("key3".to_owned(), "value3".to_owned()),
+    ])?;
+    store.remove("key2".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.scan("".to_owned(), 10)?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 2);
+    assert_eq!(
+        stats.requests,
+        RequestStats {
+            gets: 1,
+            sets: 3,
+            removes: 1,
+            scans: 1,
+        }
+    );
+    Ok(())
+}
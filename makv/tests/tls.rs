@@ -0,0 +1,193 @@
+use assert_cmd::prelude::*;
+use makv::{tls, ClientOptions, MakvClient, Result};
+use predicates::str::contains;
+use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::TempDir;
+
+mod common;
+use common::start_server;
+
+// Paths of a self-signed CA, a server certificate for 127.0.0.1 and a client
+// certificate, all written as PEM files.
+struct Certs {
+    ca: PathBuf,
+    server_cert: PathBuf,
+    server_key: PathBuf,
+    client_cert: PathBuf,
+    client_key: PathBuf,
+}
+
+fn generate_certs(dir: &Path) -> Certs {
+    let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+
+    let write_signed = |name: &str, sans: Vec<String>| {
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(sans)
+            .unwrap()
+            .signed_by(&key, &ca)
+            .unwrap();
+        let cert_path = dir.join(format!("{}.pem", name));
+        let key_path = dir.join(format!("{}-key.pem", name));
+        fs::write(&cert_path, cert.pem()).unwrap();
+        fs::write(&key_path, key.serialize_pem()).unwrap();
+        (cert_path, key_path)
+    };
+    let (server_cert, server_key) = write_signed("server", vec!["127.0.0.1".to_owned()]);
+    let (client_cert, client_key) = write_signed("client", vec![]);
+
+    let ca_path = dir.join("ca.pem");
+    fs::write(&ca_path, ca.pem()).unwrap();
+    Certs {
+        ca: ca_path,
+        server_cert,
+        server_key,
+        client_cert,
+        client_key,
+    }
+}
+
+fn client(temp_dir: &TempDir, args: &[&str]) -> assert_cmd::assert::Assert {
+    Command::cargo_bin("makv-client")
+        .unwrap()
+        .args(args)
+        .current_dir(temp_dir)
+        .assert()
+}
+
+fn path(p: &Path) -> &str {
+    p.to_str().unwrap()
+}
+
+#[test]
+fn tls_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let certs = generate_certs(temp_dir.path());
+    let addr = "127.0.0.1:4141";
+    let _server = start_server(
+        temp_dir.path(),
+        addr,
+        &[
+            "--tls-cert",
+            path(&certs.server_cert),
+            "--tls-key",
+            path(&certs.server_key),
+        ],
+    );
+
+    let ca = path(&certs.ca);
+    client(
+        &temp_dir,
+        &["set", "key1", "value1", "--addr", addr, "--ca", ca],
+    )
+    .success();
+    client(&temp_dir, &["get", "key1", "--addr", addr, "--ca", ca])
+        .success()
+        .stdout("value1\n");
+
+    // plaintext clients can't talk to a TLS listener
+    client(&temp_dir, &["get", "key1", "--addr", addr]).failure();
+
+    // the server certificate must be signed by the trusted CA
+    let other = TempDir::new().unwrap();
+    let other_certs = generate_certs(other.path());
+    client(
+        &temp_dir,
+        &["get", "key1", "--addr", addr, "--ca", path(&other_certs.ca)],
+    )
+    .failure();
+}
+
+#[test]
+fn mutual_tls_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let certs = generate_certs(temp_dir.path());
+    let addr = "127.0.0.1:4142";
+    let _server = start_server(
+        temp_dir.path(),
+        addr,
+        &[
+            "--tls-cert",
+            path(&certs.server_cert),
+            "--tls-key",
+            path(&certs.server_key),
+            "--tls-client-ca",
+            path(&certs.ca),
+        ],
+    );
+
+    let ca = path(&certs.ca);
+    let cert = path(&certs.client_cert);
+    let key = path(&certs.client_key);
+    client(
+        &temp_dir,
+        &[
+            "set", "key1", "value1", "--addr", addr, "--ca", ca, "--cert", cert, "--key", key,
+        ],
+    )
+    .success();
+    client(
+        &temp_dir,
+        &[
+            "get", "key1", "--addr", addr, "--ca", ca, "--cert", cert, "--key", key,
+        ],
+    )
+    .success()
+    .stdout("value1\n");
+
+    // clients without a certificate are rejected
+    client(&temp_dir, &["get", "key1", "--addr", addr, "--ca", ca]).failure();
+
+    // --cert needs --key
+    client(
+        &temp_dir,
+        &["get", "key1", "--addr", addr, "--ca", ca, "--cert", cert],
+    )
+    .failure()
+    .stderr(contains("--key"));
+}
+
+// The async client should talk to a TLS server with a client certificate
+#[tokio::test]
+async fn client_mutual_tls() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let certs = generate_certs(temp_dir.path());
+    let addr = "127.0.0.1:4201";
+    let _server = start_server(
+        temp_dir.path(),
+        addr,
+        &[
+            "--tls-cert",
+            path(&certs.server_cert),
+            "--tls-key",
+            path(&certs.server_key),
+            "--tls-client-ca",
+            path(&certs.ca),
+        ],
+    );
+
+    let identity = Some((certs.client_cert.as_path(), certs.client_key.as_path()));
+    let options = ClientOptions {
+        tls: Some(tls::client_config(&certs.ca, identity)?),
+        ..ClientOptions::default()
+    };
+    let mut client = MakvClient::connect_with(addr, options).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+
+    // without a certificate the handshake fails
+    let options = ClientOptions {
+        tls: Some(tls::client_config(&certs.ca, None)?),
+        retries: 0,
+        ..ClientOptions::default()
+    };
+    assert!(MakvClient::connect_with(addr, options).await.is_err());
+    Ok(())
+}
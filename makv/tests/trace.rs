@@ -0,0 +1,110 @@
+use makv::{trace, MakvClient, Result};
+use serde_json::Value;
+use slog::{o, Logger};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+use common::start_server;
+
+// A stand-in for an OpenTelemetry collector, which keeps the spans of every
+// export with the name of their service
+fn start_collector(addr: &str) -> Arc<Mutex<Vec<(String, Value)>>> {
+    let spans = Arc::new(Mutex::new(Vec::new()));
+    let server = tiny_http::Server::http(addr).unwrap();
+    let collected = spans.clone();
+    thread::spawn(move || {
+        for mut request in server.incoming_requests() {
+            assert_eq!(request.url(), "/v1/traces");
+            let export: Value = serde_json::from_reader(request.as_reader()).unwrap();
+            for resource in export["resourceSpans"].as_array().unwrap() {
+                let service = resource["resource"]["attributes"][0]["value"]["stringValue"]
+                    .as_str()
+                    .unwrap();
+                for scope in resource["scopeSpans"].as_array().unwrap() {
+                    for span in scope["spans"].as_array().unwrap() {
+                        collected
+                            .lock()
+                            .unwrap()
+                            .push((service.to_owned(), span.clone()));
+                    }
+                }
+            }
+            request
+                .respond(tiny_http::Response::from_string("{}"))
+                .unwrap();
+        }
+    });
+    spans
+}
+
+fn attribute<'a>(span: &'a Value, key: &str) -> Option<&'a str> {
+    span["attributes"]
+        .as_array()?
+        .iter()
+        .find(|attribute| attribute["key"] == key)?["value"]["stringValue"]
+        .as_str()
+}
+
+// The server's spans should join the trace of the client's request, with
+// spans for the queue, the store lock, the log write and the flush
+#[tokio::test]
+async fn trace_across_client_and_server() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4193";
+    let endpoint = "http://127.0.0.1:4194/v1/traces";
+    let spans = start_collector("127.0.0.1:4194");
+    trace::install("makv-test", endpoint, Logger::root(slog::Discard, o!()))?;
+    let server = start_server(temp_dir.path(), addr, &["--otlp-endpoint", endpoint]);
+
+    let mut client = MakvClient::connect(addr).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert!(client.remove("key2".to_owned()).await.is_err());
+    trace::flush();
+    // the server exports every half second
+    thread::sleep(Duration::from_millis(1500));
+    drop(server);
+
+    let spans = spans.lock().unwrap();
+    let client_span = |command: &str| {
+        spans
+            .iter()
+            .map(|(_, span)| span)
+            .find(|span| {
+                span["name"] == "client.request" && attribute(span, "command") == Some(command)
+            })
+            .unwrap_or_else(|| panic!("no client span for {}", command))
+    };
+    let in_trace = |client: &Value, name: &str| {
+        spans
+            .iter()
+            .find(|(_, span)| span["traceId"] == client["traceId"] && span["name"] == name)
+            .unwrap_or_else(|| panic!("no {} span in the trace", name))
+    };
+
+    let set = client_span("set");
+    assert_eq!(set["kind"], 3);
+    let (service, request) = in_trace(set, "server.request");
+    assert_eq!(service, "makv-server");
+    assert_eq!(request["parentSpanId"], set["spanId"]);
+    assert_eq!(request["kind"], 2);
+    let (_, queue) = in_trace(set, "server.queue");
+    assert_eq!(queue["parentSpanId"], set["spanId"]);
+    for name in &["store.lock", "store.write", "store.flush"] {
+        let (_, span) = in_trace(set, name);
+        assert_eq!(span["parentSpanId"], request["spanId"]);
+        assert!(span["startTimeUnixNano"].as_str() <= span["endTimeUnixNano"].as_str());
+    }
+
+    let remove = client_span("remove");
+    assert_eq!(remove["status"]["code"], 2);
+    let (_, request) = in_trace(remove, "server.request");
+    assert_eq!(request["status"]["code"], 2);
+    assert!(request["status"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("key2"));
+    Ok(())
+}